@@ -7,8 +7,16 @@
 )]
 #![cfg_attr(docsrs, feature(doc_cfg, doc_auto_cfg))]
 
+pub mod reconnect;
+
 use {
-    jetstream_rpc::{Frame, Protocol},
+    jetstream_rpc::{
+        metrics::{self, Side},
+        Frame,
+        Framer,
+        Header,
+        Protocol,
+    },
     jetstream_wireformat::WireFormat,
     tokio_util::{
         bytes::{self, Buf, BufMut},
@@ -20,17 +28,42 @@ pub struct ClientCodec<P>
 where
     P: Protocol,
 {
-    _p: std::marker::PhantomData<P>,
+    headers: bool,
+    // The codec holds no `P`, so it is `Unpin` whatever `P` is.
+    _p: std::marker::PhantomData<fn() -> P>,
+}
+
+impl<P: Protocol> ClientCodec<P> {
+    /// Sends frame headers, and with them the deadline, metadata and trace
+    /// context of requests. Servers that predate frame headers fail to decode
+    /// requests carrying one, so they are left off unless the server is known
+    /// to understand them.
+    pub fn with_headers(mut self) -> Self {
+        self.headers = true;
+        self
+    }
 }
+
 impl<P: jetstream_rpc::Protocol> Encoder<Frame<P::Request>> for ClientCodec<P> {
     type Error = std::io::Error;
 
     fn encode(
         &mut self,
-        item: Frame<P::Request>,
+        mut item: Frame<P::Request>,
         dst: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
-        WireFormat::encode(&item, &mut dst.writer())
+        if !self.headers {
+            item.header = Header::default();
+        }
+        let start = dst.len();
+        WireFormat::encode(&item, &mut dst.writer())?;
+        metrics::record_sent(
+            Side::Client,
+            P::NAME,
+            item.msg.message_name(),
+            dst.len() - start,
+        );
+        Ok(())
     }
 }
 
@@ -38,7 +71,19 @@ impl<P: jetstream_rpc::Protocol> Decoder for ClientCodec<P> {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Ok(Some(Frame::<P::Response>::decode(&mut src.reader())?))
+        // Wait until the whole frame, including its size prefix, has been buffered.
+        let Some(size) = src.get(..4) else {
+            return Ok(None);
+        };
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        if src.len() < size {
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(size.max(4));
+        let frame = Frame::<P::Response>::decode(&mut frame.reader())?;
+        metrics::record_received(Side::Client, P::NAME, frame.msg.message_name(), size);
+        Ok(Some(frame))
     }
 
     type Item = Frame<P::Response>;
@@ -50,6 +95,7 @@ where
 {
     fn default() -> Self {
         Self {
+            headers: false,
             _p: std::marker::PhantomData,
         }
     }
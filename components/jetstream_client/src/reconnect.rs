@@ -0,0 +1,384 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+//! Reconnecting client transports.
+//!
+//! [`Reconnect`] owns a transport created by a [`Connect`] factory. When that transport fails it
+//! is dropped and a new one is dialed, backing off exponentially between failed dials, so a broken
+//! connection doesn't fail every subsequent call. Requests for methods marked `#[idempotent]` are
+//! replayed on the new transport as allowed by the [`RetryPolicy`], whether one or several are
+//! outstanding. Servers that are shutting down
+//! send a goaway frame, which is treated the same as the transport failing.
+
+use {
+    futures::{ready, Sink, Stream},
+    jetstream_rpc::{ClientTransport, Frame, Framer, Protocol},
+    jetstream_wireformat::WireFormat,
+    rand::Rng,
+    std::{
+        future::Future,
+        collections::BTreeMap,
+        io::{self, ErrorKind},
+        marker::PhantomData,
+        pin::Pin,
+        sync::{Mutex, PoisonError},
+        task::{Context, Poll},
+        time::Duration,
+    },
+    tokio::time::Sleep,
+};
+
+/// A factory for the transports used by [`Reconnect`].
+///
+/// This is implemented for closures returning a future that resolves to a transport, for example
+/// `|| async { Ok(Framed::new(TcpStream::connect(addr).await?, ClientCodec::default())) }`.
+pub trait Connect<P: Protocol>: Send + Sync {
+    /// The transport created by this factory.
+    type Transport: ClientTransport<P>;
+    /// The future returned by [`Connect::connect`].
+    type Future: Future<Output = io::Result<Self::Transport>> + Send;
+    /// Dials a new transport.
+    fn connect(&self) -> Self::Future;
+}
+
+impl<P, F, Fut, T> Connect<P> for F
+where
+    P: Protocol,
+    F: Fn() -> Fut + Send + Sync,
+    Fut: Future<Output = io::Result<T>> + Send,
+    T: ClientTransport<P>,
+{
+    type Future = Fut;
+    type Transport = T;
+
+    fn connect(&self) -> Self::Future {
+        self()
+    }
+}
+
+/// Exponential backoff with jitter, applied between failed attempts to dial a transport.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Backoff {
+    /// Delay after the first failed attempt.
+    pub initial: Duration,
+    /// Upper bound for the delay.
+    pub max: Duration,
+    /// Factor by which the delay grows after every failed attempt.
+    pub multiplier: f64,
+    /// Fraction of the delay that is randomized, from `0.0` (none) to `1.0` (full jitter).
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay after `failures` consecutive failed attempts.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let exponent = failures.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = (self.initial.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max.as_secs_f64())
+            .max(0.0);
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+}
+
+/// Decides when [`Reconnect`] gives up.
+///
+/// Only requests whose message reports [`Framer::is_idempotent`], i.e. methods marked
+/// `#[idempotent]` in the `service` definition, are ever sent more than once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Maximum number of times an idempotent request is sent, including the first attempt.
+    pub max_attempts: u32,
+    /// Maximum number of consecutive failed dials before the error is returned to the caller.
+    pub max_connect_attempts: u32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            max_connect_attempts: 5,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that never replays requests, but still redials for subsequent calls.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+}
+
+/// A request that is waiting for its response.
+struct Pending {
+    /// The encoded request, kept only if it may be replayed.
+    frame: Option<Vec<u8>>,
+    /// Number of times the request has been sent.
+    attempts: u32,
+    /// Set when the request has to be sent on a new transport before a response can arrive.
+    resend: bool,
+}
+
+enum State<T, F> {
+    Disconnected,
+    Waiting(Pin<Box<Sleep>>),
+    // The dialing future is only ever polled through `&mut self`, the mutex makes it `Sync`.
+    Connecting(Mutex<Pin<Box<F>>>),
+    Connected(T),
+}
+
+/// A [`ClientTransport`] that redials when its underlying transport fails.
+///
+/// ```ignore
+/// let mut transport = Reconnect::new(|| async {
+///     let stream = TcpStream::connect(("server", PORT)).await?;
+///     Ok(Framed::new(stream, ClientCodec::<EchoChannel>::default()))
+/// });
+/// let mut chan = EchoChannel {
+///     inner: Box::new(&mut transport),
+/// };
+/// ```
+pub struct Reconnect<P: Protocol, C: Connect<P>> {
+    connector: C,
+    backoff: Backoff,
+    policy: RetryPolicy,
+    state: State<C::Transport, C::Future>,
+    failed_dials: u32,
+    /// The requests waiting for a response, by tag.
+    pending: BTreeMap<u16, Pending>,
+    _protocol: PhantomData<fn() -> P>,
+}
+
+// The connector is never pinned, dials in flight are boxed and transports are `Unpin`.
+impl<P: Protocol, C: Connect<P>> Unpin for Reconnect<P, C> {}
+
+impl<P: Protocol, C: Connect<P>> Reconnect<P, C> {
+    /// Creates a client that dials transports with `connector`, lazily on first use.
+    pub fn new(connector: C) -> Self {
+        Self {
+            connector,
+            backoff: Backoff::default(),
+            policy: RetryPolicy::default(),
+            state: State::Disconnected,
+            failed_dials: 0,
+            pending: BTreeMap::new(),
+            _protocol: PhantomData,
+        }
+    }
+
+    /// Sets the backoff applied between failed dials.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sets the retry policy.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Returns true if a transport is currently established.
+    pub fn is_connected(&self) -> bool {
+        matches!(self.state, State::Connected(_))
+    }
+
+    fn poll_connected(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                State::Connected(_) => return Poll::Ready(Ok(())),
+                State::Disconnected => {
+                    self.state = State::Connecting(Mutex::new(Box::pin(self.connector.connect())));
+                }
+                State::Waiting(sleep) => {
+                    ready!(sleep.as_mut().poll(cx));
+                    self.state = State::Disconnected;
+                }
+                State::Connecting(connecting) => {
+                    let connecting = connecting.get_mut().unwrap_or_else(PoisonError::into_inner);
+                    match ready!(connecting.as_mut().poll(cx)) {
+                        Ok(transport) => {
+                            self.failed_dials = 0;
+                            self.state = State::Connected(transport);
+                        }
+                        Err(err) => {
+                            self.failed_dials += 1;
+                            if self.failed_dials >= self.policy.max_connect_attempts {
+                                self.failed_dials = 0;
+                                self.state = State::Disconnected;
+                                return Poll::Ready(Err(err));
+                            }
+                            let delay = self.backoff.delay(self.failed_dials);
+                            self.state = State::Waiting(Box::pin(tokio::time::sleep(delay)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Drops the current transport after it failed with `err`.
+    ///
+    /// Returns `Ok` if every pending request is going to be replayed on a new transport. Those
+    /// that can't be are forgotten, and their callers get `err`.
+    fn fail(&mut self, err: io::Error) -> io::Result<()> {
+        self.state = State::Disconnected;
+        let max_attempts = self.policy.max_attempts;
+        let outstanding = self.pending.len();
+        self.pending
+            .retain(|_, pending| pending.frame.is_some() && pending.attempts < max_attempts);
+        for pending in self.pending.values_mut() {
+            pending.resend = true;
+        }
+        if outstanding > 0 && self.pending.len() == outstanding {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    /// Sends the pending requests on a new transport, if the previous one failed.
+    fn poll_resend(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while let Some(tag) = self.pending.iter().find_map(|(&tag, p)| p.resend.then_some(tag)) {
+            if let Err(err) = ready!(self.poll_connected(cx)) {
+                self.pending.clear();
+                return Poll::Ready(Err(err));
+            }
+            let (State::Connected(transport), Some(pending)) =
+                (&mut self.state, self.pending.get_mut(&tag))
+            else {
+                continue;
+            };
+            let sent = ready!(Pin::new(&mut *transport).poll_ready(cx)).and_then(|()| {
+                let mut frame = pending.frame.as_deref().unwrap_or_default();
+                let frame = Frame::<P::Request>::decode(&mut frame)?;
+                Pin::new(transport).start_send(frame)
+            });
+            match sent {
+                Ok(()) => {
+                    pending.attempts += 1;
+                    pending.resend = false;
+                }
+                Err(err) => {
+                    if let Err(err) = self.fail(err) {
+                        return Poll::Ready(Err(err));
+                    }
+                }
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<P: Protocol, C: Connect<P>> Sink<Frame<P::Request>> for Reconnect<P, C> {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        ready!(this.poll_connected(cx))?;
+        let State::Connected(transport) = &mut this.state else {
+            unreachable!("poll_connected returned without a transport");
+        };
+        let ready = ready!(Pin::new(transport).poll_ready(cx));
+        if ready.is_err() {
+            this.state = State::Disconnected;
+        }
+        Poll::Ready(ready)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Frame<P::Request>) -> Result<(), Self::Error> {
+        let this = self.get_mut();
+        let frame = if item.msg.is_idempotent() && this.policy.max_attempts > 1 {
+            let mut frame = Vec::with_capacity(item.byte_size() as usize);
+            item.encode(&mut frame)?;
+            Some(frame)
+        } else {
+            None
+        };
+        let pending = Pending {
+            frame,
+            attempts: 1,
+            resend: false,
+        };
+        this.pending.insert(item.tag, pending);
+        let State::Connected(transport) = &mut this.state else {
+            return this.fail(io::Error::from(ErrorKind::NotConnected));
+        };
+        match Pin::new(transport).start_send(item) {
+            Ok(()) => Ok(()),
+            Err(err) => this.fail(err),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_resend(cx))?;
+            let State::Connected(transport) = &mut this.state else {
+                return Poll::Ready(Ok(()));
+            };
+            match ready!(Pin::new(transport).poll_flush(cx)) {
+                Ok(()) => return Poll::Ready(Ok(())),
+                Err(err) => this.fail(err)?,
+            }
+        }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        this.pending.clear();
+        match &mut this.state {
+            State::Connected(transport) => Pin::new(transport).poll_close(cx),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl<P: Protocol, C: Connect<P>> Stream for Reconnect<P, C> {
+    type Item = io::Result<Frame<P::Response>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Err(err) = ready!(this.poll_resend(cx)) {
+                return Poll::Ready(Some(Err(err)));
+            }
+            let State::Connected(transport) = &mut this.state else {
+                return Poll::Ready(Some(Err(io::Error::from(ErrorKind::NotConnected))));
+            };
+            let next = match ready!(Pin::new(&mut *transport).poll_flush(cx)) {
+                Ok(()) => ready!(Pin::new(transport).poll_next(cx)),
+                Err(err) => Some(Err(err)),
+            };
+            let err = match next {
+                // The server is shutting down, replay the request elsewhere.
+                Some(Ok(frame)) if frame.is_go_away() => {
+                    io::Error::new(ErrorKind::ConnectionAborted, "server is going away")
+                }
+                Some(Ok(frame)) => {
+                    this.pending.remove(&frame.tag);
+                    return Poll::Ready(Some(Ok(frame)));
+                }
+                Some(Err(err)) => err,
+                None => io::Error::new(ErrorKind::ConnectionReset, "connection closed"),
+            };
+            if let Err(err) = this.fail(err) {
+                return Poll::Ready(Some(Err(err)));
+            }
+        }
+    }
+}
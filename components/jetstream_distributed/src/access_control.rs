@@ -71,6 +71,15 @@
 
 use cel_interpreter::{Context, Program};
 
+pub mod authorize;
+pub mod consistency;
+pub mod namespace;
+pub mod policy;
+pub mod service;
+pub mod store;
+pub mod tuple;
+pub mod zanzibar;
+
 /// Access Control trait. This follows the zanzibar model, of subject, verb, resource.
 #[trait_variant::make(Send+Sync)]
 pub trait AccessControl {
@@ -82,6 +91,17 @@ pub trait AccessControl {
         resource: impl Resource,
     ) -> bool;
 }
+impl<A: AccessControl> AccessControl for std::sync::Arc<A> {
+    async fn check(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> bool {
+        (**self).check(subject, access, resource).await
+    }
+}
+
 /// Subject, an entity that can perform actions, could be a person, a service, a bot or an organization with members.
 #[trait_variant::make(Send+Sync)]
 pub trait Subject {
@@ -107,14 +127,92 @@ pub trait Verb {
     fn into_value(self) -> cel_interpreter::Value;
 }
 
+/// Why a [`Script`] couldn't be compiled or evaluated.
+#[derive(thiserror::Error, Debug)]
+pub enum ScriptError {
+    /// The expression isn't valid CEL.
+    #[error("invalid policy: {0}")]
+    Compile(String),
+    /// Evaluating the expression failed, for example because it refers to a
+    /// variable or function that doesn't exist.
+    #[error("policy failed: {0}")]
+    Execution(#[from] cel_interpreter::ExecutionError),
+    /// The expression evaluated to something other than a bool.
+    #[error("policy evaluated to {0:?} rather than a bool")]
+    NotBool(cel_interpreter::Value),
+}
+
+/// The variables a [`Script`] is evaluated with.
+struct Variables {
+    subject: cel_interpreter::Value,
+    action: cel_interpreter::Value,
+    resource: cel_interpreter::Value,
+}
+
+impl Variables {
+    fn new(subject: impl Subject, access: impl Verb, resource: impl Resource) -> Self {
+        Self {
+            subject: subject.into_value(),
+            action: access.into_value(),
+            resource: resource.into_value(),
+        }
+    }
+}
+
 /// A simple ACL script that uses CEL to evaluate access control rules.
 /// ```js
 /// subject.id == "alice" && action == "write" && resource == "file1"
 /// ```
+/// The expression is compiled once, by [`Script::new`].
 pub struct Script {
-    program: String,
+    source: String,
+    program: Program,
+}
+
+impl Script {
+    /// Compiles `source`.
+    pub fn new(source: impl Into<String>) -> Result<Self, ScriptError> {
+        let source = source.into();
+        let program =
+            Program::compile(&source).map_err(|err| ScriptError::Compile(err.to_string()))?;
+        Ok(Self { source, program })
+    }
+
+    /// Returns the expression the script was compiled from.
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluates the script, unlike [`AccessControl::check`] telling why it
+    /// failed.
+    pub fn evaluate(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> Result<bool, ScriptError> {
+        self.evaluate_with(&Variables::new(subject, access, resource))
+    }
+
+    fn evaluate_with(&self, variables: &Variables) -> Result<bool, ScriptError> {
+        let mut context = Context::default();
+        context.add_variable_from_value("subject", variables.subject.clone());
+        context.add_variable_from_value("action", variables.action.clone());
+        context.add_variable_from_value("resource", variables.resource.clone());
+        match self.program.execute(&context)? {
+            cel_interpreter::Value::Bool(allowed) => Ok(allowed),
+            value => Err(ScriptError::NotBool(value)),
+        }
+    }
+}
+
+impl std::fmt::Debug for Script {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("Script").field(&self.source).finish()
+    }
 }
 
+/// Scripts that fail to evaluate deny access.
 impl AccessControl for Script {
     async fn check(
         &self,
@@ -122,21 +220,7 @@ impl AccessControl for Script {
         access: impl Verb,
         resource: impl Resource,
     ) -> bool {
-        let mut context = Context::default();
-        context
-            .add_variable("subject", subject.into_value())
-            .unwrap();
-        context.add_variable("action", access.into_value()).unwrap();
-        context
-            .add_variable("resource", resource.into_value())
-            .unwrap();
-        let program = Program::compile(&self.program).unwrap();
-
-        let value = program.execute(&context);
-        match value {
-            Ok(cel_interpreter::Value::Bool(b)) => b,
-            _ => false,
-        }
+        self.evaluate(subject, access, resource).unwrap_or(false)
     }
 }
 
@@ -190,10 +274,9 @@ mod test {
 
     #[okstd::test]
     async fn test_simple_acl() {
-        let script = Script {
-            program: r#"subject.id == "alice" && action == "write" && resource == "file1""#
-                .to_string(),
-        };
+        let script =
+            Script::new(r#"subject.id == "alice" && action == "write" && resource == "file1""#)
+                .unwrap();
         let subject = SimpleSubject {
             id: "alice".to_string(),
         };
@@ -203,4 +286,23 @@ mod test {
         let verb = SimpleVerb::Write;
         assert!(script.check(subject, verb, resource).await);
     }
+
+    #[okstd::test]
+    fn test_broken_scripts_are_errors() {
+        assert!(matches!(
+            Script::new(r#"subject.id == ("alice""#),
+            Err(ScriptError::Compile(_))
+        ));
+        let script = Script::new(r#"subjct.id == "alice""#).unwrap();
+        let subject = SimpleSubject {
+            id: "alice".to_string(),
+        };
+        let resource = SimpleResource {
+            id: "file1".to_string(),
+        };
+        assert!(matches!(
+            script.evaluate(subject, SimpleVerb::Write, resource),
+            Err(ScriptError::Execution(_))
+        ));
+    }
 }
@@ -0,0 +1,188 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Authorizing requests against an [`AccessControl`] policy.
+//!
+//! [`AuthorizeLayer`] checks every request before it reaches the service it
+//! wraps. The subject is the [`Peer`] the transport attached to the request,
+//! the verb is the method's name, and the resource is the method's argument
+//! marked `#[resource]`, if it has one. Requests the policy refuses, and
+//! requests without a peer, are answered with a
+//! [`Code::PermissionDenied`](jetstream_rpc::Code::PermissionDenied) error
+//! frame.
+//!
+//! ```ignore
+//! #[service]
+//! pub trait Files {
+//!     async fn read(&mut self, #[resource] path: String) -> Result<Vec<u8>, Error>;
+//! }
+//!
+//! let authorize = AuthorizeLayer::new(policy);
+//! let mut service = FilesService { inner: FilesImpl {} }.layer(&authorize);
+//! ```
+
+use {
+    super::{AccessControl, Resource, Subject, Verb},
+    cel_interpreter::{
+        objects::{Key, Map},
+        Value,
+    },
+    jetstream_rpc::{Error, Frame, Framer, Layer, Peer, Protocol, Status},
+    std::{collections::HashMap, sync::Arc},
+};
+
+/// Peers are identified by the first subject alternative name of their
+/// certificate, or its subject if it has none, `uid:<uid>` for local users,
+/// `cid:<cid>` for virtual machines, and otherwise their IP address.
+///
+/// As a CEL value, a peer is a map with its `id`, its `kind` (`tls`, `unix`,
+/// `vsock` or `socket`), and what else the transport knows about it, like
+/// the `sans` of its certificate or the `gid` of a local user.
+impl Subject for Peer {
+    fn id(&self) -> String {
+        match self {
+            Peer::Tls { subject, sans, .. } => sans.first().unwrap_or(subject).clone(),
+            Peer::Unix { uid, .. } => format!("uid:{}", uid),
+            Peer::Vsock { cid } => format!("cid:{}", cid),
+            Peer::Socket(addr) => addr.ip().to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn into_value(self) -> Value {
+        let mut map: HashMap<Key, Value> = HashMap::new();
+        map.insert("id".into(), Value::String(self.id().into()));
+        let kind = match self {
+            Peer::Tls {
+                addr,
+                subject,
+                sans,
+                ..
+            } => {
+                map.insert("addr".into(), Value::String(addr.to_string().into()));
+                map.insert("subject".into(), Value::String(subject.into()));
+                let sans = sans
+                    .into_iter()
+                    .map(|san| Value::String(san.into()))
+                    .collect::<Vec<_>>();
+                map.insert("sans".into(), Value::List(sans.into()));
+                "tls"
+            }
+            Peer::Unix { uid, gid, pid } => {
+                map.insert("uid".into(), Value::UInt(uid.into()));
+                map.insert("gid".into(), Value::UInt(gid.into()));
+                if let Some(pid) = pid {
+                    map.insert("pid".into(), Value::Int(pid.into()));
+                }
+                "unix"
+            }
+            Peer::Vsock { cid } => {
+                map.insert("cid".into(), Value::UInt(cid.into()));
+                "vsock"
+            }
+            Peer::Socket(addr) => {
+                map.insert("addr".into(), Value::String(addr.to_string().into()));
+                "socket"
+            }
+            _ => "unknown",
+        };
+        map.insert("kind".into(), Value::String(kind.to_string().into()));
+        Value::Map(Map { map: map.into() })
+    }
+}
+
+/// A method name, as returned by [`Framer::message_name`].
+impl Verb for &str {
+    fn into_value(self) -> Value {
+        Value::String(self.to_string().into())
+    }
+}
+
+/// The `#[resource]` argument of a request, null in CEL if it has none.
+impl Resource for Option<String> {
+    fn id(&self) -> String {
+        self.clone().unwrap_or_default()
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Some(resource) => Value::String(resource.into()),
+            None => Value::Null,
+        }
+    }
+}
+
+/// Authorizes requests with a policy, see the [module](self) documentation.
+#[derive(Debug)]
+pub struct AuthorizeLayer<A> {
+    policy: Arc<A>,
+}
+
+impl<A> AuthorizeLayer<A> {
+    /// Checks requests against `policy`.
+    pub fn new(policy: A) -> Self {
+        Self {
+            policy: Arc::new(policy),
+        }
+    }
+}
+
+impl<A> Clone for AuthorizeLayer<A> {
+    fn clone(&self) -> Self {
+        Self {
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+impl<P, A> Layer<P> for AuthorizeLayer<A> {
+    type Protocol = Authorize<P, A>;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        Authorize {
+            inner,
+            policy: self.policy.clone(),
+        }
+    }
+}
+
+/// The protocol [`AuthorizeLayer`] wraps protocols in.
+#[derive(Debug)]
+pub struct Authorize<P, A> {
+    inner: P,
+    policy: Arc<A>,
+}
+
+impl<P, A> Protocol for Authorize<P, A>
+where
+    P: Protocol,
+    P::Error: From<Error>,
+    A: AccessControl + Send + Sync,
+{
+    type Request = P::Request;
+    type Response = P::Response;
+    type Error = P::Error;
+    const VERSION: &'static str = P::VERSION;
+    const NAME: &'static str = P::NAME;
+
+    async fn rpc(
+        &mut self,
+        frame: Frame<Self::Request>,
+    ) -> Result<Frame<Self::Response>, Self::Error> {
+        let method = frame.msg.message_name();
+        let allowed = match frame.extensions.get::<Peer>() {
+            Some(peer) => {
+                self.policy
+                    .check(peer.clone(), method, frame.msg.resource())
+                    .await
+            }
+            None => false,
+        };
+        if !allowed {
+            let status = Status::permission_denied(format!("{} is not allowed", method));
+            return Ok(Frame::error(frame.tag, status).map_err(Error::from)?);
+        }
+        self.inner.rpc(frame).await
+    }
+}
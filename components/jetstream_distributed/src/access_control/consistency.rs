@@ -0,0 +1,128 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Consistency tokens, Zanzibar's zookies.
+//!
+//! Every write to a [`TupleStore`](super::store::TupleStore) makes a new
+//! revision of the tuples, and returns a [`Zookie`] for it. Checks and
+//! lookups read a single revision, a snapshot, chosen by their
+//! [`Consistency`].
+//!
+//! Zookies avoid the "new enemy" problem: after removing Bob from a document
+//! and then adding secrets to it, the application stores the zookie of the
+//! removal with the new content, and checks access to the content
+//! [`Consistency::AtLeastAsFresh`] that zookie. The check can't see tuples
+//! from before the removal, even if the store it reads from is lagging.
+
+use {
+    super::tuple::ParseError,
+    jetstream_wireformat::JetStreamWireFormat,
+    hmac::{Hmac, Mac},
+    rand::RngCore,
+    sha2::Sha256,
+    std::{fmt, str::FromStr},
+};
+
+/// A token for a revision of the stored tuples.
+///
+/// Applications keep zookies as their text form, but can't make them: a
+/// zookie carries a tag only the [`ZookieKey`] of the store that issued it
+/// makes, so one edited to pick another snapshot is refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, JetStreamWireFormat)]
+pub struct Zookie {
+    revision: u64,
+    tag: u128,
+}
+
+impl Zookie {
+    /// Returns the revision the token is for. Later revisions are larger.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+}
+
+impl fmt::Display for Zookie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "zk{:x}.{:032x}", self.revision, self.tag)
+    }
+}
+
+impl FromStr for Zookie {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.strip_prefix("zk")
+            .and_then(|zookie| zookie.split_once('.'))
+            .and_then(|(revision, tag)| {
+                Some(Zookie {
+                    revision: u64::from_str_radix(revision, 16).ok()?,
+                    tag: u128::from_str_radix(tag, 16).ok()?,
+                })
+            })
+            .ok_or_else(|| ParseError::Zookie(s.to_string()))
+    }
+}
+
+/// The secret a store makes its zookies with. Stores that serve the same
+/// tuples, like replicas of a database, share it.
+#[derive(Clone)]
+pub struct ZookieKey([u8; 32]);
+
+impl ZookieKey {
+    /// Returns the key made of `secret`.
+    pub fn new(secret: [u8; 32]) -> Self {
+        Self(secret)
+    }
+
+    /// Returns a new random key.
+    pub fn random() -> Self {
+        let mut secret = [0; 32];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self(secret)
+    }
+
+    /// Returns the zookie of `revision`.
+    pub fn zookie(&self, revision: u64) -> Zookie {
+        let tag = self.mac(revision).finalize().into_bytes();
+        Zookie {
+            revision,
+            tag: u128::from_le_bytes(tag[..16].try_into().unwrap()),
+        }
+    }
+
+    /// Returns true if the key made `zookie`.
+    pub fn verify(&self, zookie: &Zookie) -> bool {
+        // Compared in constant time, so tags can't be guessed a byte at a time.
+        self.mac(zookie.revision)
+            .verify_truncated_left(&zookie.tag.to_le_bytes())
+            .is_ok()
+    }
+
+    /// Returns the HMAC-SHA256 of `revision`, whose first half tags its
+    /// zookies.
+    fn mac(&self, revision: u64) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC takes keys of any size");
+        mac.update(&revision.to_le_bytes());
+        mac
+    }
+}
+
+impl fmt::Debug for ZookieKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ZookieKey(..)")
+    }
+}
+
+/// Which snapshot of the tuples a check or lookup reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, JetStreamWireFormat)]
+pub enum Consistency {
+    /// Whichever snapshot the store can read soonest, which may be stale.
+    #[default]
+    MinimizeLatency,
+    /// A snapshot that includes the write the zookie came from, and every
+    /// write before it.
+    AtLeastAsFresh(Zookie),
+    /// The newest snapshot.
+    FullyConsistent,
+}
@@ -0,0 +1,98 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Namespace configs, which say how the relations of a namespace's objects
+//! follow from each other.
+//!
+//! ```ignore
+//! // Repository admins can write, and writers can read, as can members of
+//! // the organization that owns the repository.
+//! let repo = Namespace::new("repo")
+//!     .relation("admin", Rewrite::This)
+//!     .relation("write", Rewrite::union([Rewrite::This, Rewrite::computed("admin")]))
+//!     .relation(
+//!         "read",
+//!         Rewrite::union([
+//!             Rewrite::This,
+//!             Rewrite::computed("write"),
+//!             Rewrite::tuple_to_userset("owner", "member"),
+//!         ]),
+//!     );
+//! ```
+
+use std::collections::HashMap;
+
+/// How the users with a relation are worked out, a userset rewrite rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rewrite {
+    /// The users the relation's own tuples name.
+    This,
+    /// The users with another relation to the same object.
+    ComputedUserset(String),
+    /// For every object the `tupleset` relation points at, the users with
+    /// the `computed_userset` relation to that object. A document's viewers
+    /// could include the viewers of its `parent` folder, for example.
+    TupleToUserset {
+        /// The relation pointing at the other objects.
+        tupleset: String,
+        /// The relation to those objects that is inherited.
+        computed_userset: String,
+    },
+    /// The users of any of the rewrites.
+    Union(Vec<Rewrite>),
+}
+
+impl Rewrite {
+    /// Returns [`Rewrite::ComputedUserset`].
+    pub fn computed(relation: impl Into<String>) -> Self {
+        Rewrite::ComputedUserset(relation.into())
+    }
+
+    /// Returns [`Rewrite::TupleToUserset`].
+    pub fn tuple_to_userset(tupleset: impl Into<String>, computed_userset: impl Into<String>) -> Self {
+        Rewrite::TupleToUserset {
+            tupleset: tupleset.into(),
+            computed_userset: computed_userset.into(),
+        }
+    }
+
+    /// Returns [`Rewrite::Union`].
+    pub fn union(rewrites: impl IntoIterator<Item = Rewrite>) -> Self {
+        Rewrite::Union(rewrites.into_iter().collect())
+    }
+}
+
+/// The relations of a namespace and their rewrite rules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namespace {
+    name: String,
+    relations: HashMap<String, Rewrite>,
+}
+
+impl Namespace {
+    /// Returns a namespace without any relations.
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            relations: HashMap::new(),
+        }
+    }
+
+    /// Adds `relation`, whose users are worked out with `rewrite`.
+    pub fn relation(mut self, relation: impl Into<String>, rewrite: Rewrite) -> Self {
+        self.relations.insert(relation.into(), rewrite);
+        self
+    }
+
+    /// Returns the namespace's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the rewrite rule of `relation`. Relations that weren't
+    /// configured only have their own tuples, [`Rewrite::This`].
+    pub fn rewrite(&self, relation: &str) -> &Rewrite {
+        self.relations.get(relation).unwrap_or(&Rewrite::This)
+    }
+}
@@ -0,0 +1,433 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Policy sets: named [`Script`] rules combined with deny-overrides.
+//!
+//! A request is denied if any deny rule matches it, or fails to evaluate.
+//! Otherwise it is allowed if an allow rule matches it, and denied if none
+//! does.
+//!
+//! Policy files have a rule on each line, its effect, its name and its CEL
+//! expression. Empty lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! # Anyone can read what's public.
+//! allow public: action == "read" && resource.startsWith("/pub/")
+//! # Only root touches secrets, whatever else allows it.
+//! deny secrets: resource.startsWith("/secret/") && subject.id != "uid:0"
+//! ```
+//!
+//! [`PolicyFile`] reloads a file when it changes:
+//!
+//! ```ignore
+//! let policy = Arc::new(PolicyFile::open("/etc/jetstream/policy.cel")?);
+//! policy.watch(Duration::from_secs(5));
+//! let authorize = AuthorizeLayer::new(policy);
+//! ```
+
+use {
+    super::{AccessControl, Resource, Script, ScriptError, Subject, Variables, Verb},
+    std::{
+        collections::HashSet,
+        fmt,
+        io,
+        path::{Path, PathBuf},
+        sync::{Arc, RwLock},
+        time::Duration,
+    },
+    tokio::task::JoinHandle,
+};
+
+/// Why a policy file couldn't be loaded.
+#[derive(thiserror::Error, Debug)]
+pub enum PolicyError {
+    /// The file couldn't be read.
+    #[error("reading policy: {0}")]
+    Io(#[from] io::Error),
+    /// A line isn't a rule.
+    #[error("line {line}: {message}")]
+    Syntax {
+        /// The line, counting from 1.
+        line: usize,
+        /// What is wrong with it.
+        message: String,
+    },
+    /// A rule's expression doesn't compile.
+    #[error("rule {rule}: {source}")]
+    Rule {
+        /// The rule's name.
+        rule: String,
+        /// Why it doesn't compile.
+        source: ScriptError,
+    },
+}
+
+/// What a rule does to the requests it matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    /// Allows them, unless a deny rule matches too.
+    Allow,
+    /// Denies them.
+    Deny,
+}
+
+impl fmt::Display for Effect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Effect::Allow => "allow",
+            Effect::Deny => "deny",
+        })
+    }
+}
+
+/// A named script in a [`PolicySet`].
+#[derive(Debug)]
+pub struct Rule {
+    name: String,
+    effect: Effect,
+    script: Script,
+}
+
+impl Rule {
+    /// Returns a rule that allows the requests `script` matches.
+    pub fn allow(name: impl Into<String>, script: Script) -> Self {
+        Self::new(name, Effect::Allow, script)
+    }
+
+    /// Returns a rule that denies the requests `script` matches.
+    pub fn deny(name: impl Into<String>, script: Script) -> Self {
+        Self::new(name, Effect::Deny, script)
+    }
+
+    fn new(name: impl Into<String>, effect: Effect, script: Script) -> Self {
+        Self {
+            name: name.into(),
+            effect,
+            script,
+        }
+    }
+
+    /// Returns the rule's name.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns what the rule does to the requests it matches.
+    pub fn effect(&self) -> Effect {
+        self.effect
+    }
+}
+
+/// The outcome of a [`PolicySet`], and the rule that decided it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// An allow rule matched, and no deny rule did.
+    Allow {
+        /// The allow rule that matched.
+        rule: String,
+    },
+    /// A deny rule matched, or no allow rule did.
+    Deny {
+        /// The deny rule that matched, or failed to evaluate. None if no
+        /// allow rule matched.
+        rule: Option<String>,
+    },
+}
+
+impl Decision {
+    /// Returns true if the request is allowed.
+    pub fn is_allowed(&self) -> bool {
+        matches!(self, Decision::Allow { .. })
+    }
+}
+
+/// Rules combined with deny-overrides, see the [module](self) documentation.
+#[derive(Debug, Default)]
+pub struct PolicySet {
+    rules: Vec<Rule>,
+}
+
+impl PolicySet {
+    /// Returns a policy without rules, which denies everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `rule`.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    /// Returns the rules, in the order they were added.
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Parses and compiles the rules of a policy file.
+    pub fn parse(source: &str) -> Result<Self, PolicyError> {
+        let mut policy = Self::new();
+        let mut names = HashSet::new();
+        for (index, line) in source.lines().enumerate() {
+            let syntax = |message: &str| {
+                PolicyError::Syntax {
+                    line: index + 1,
+                    message: message.to_string(),
+                }
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (head, expression) = line
+                .split_once(':')
+                .ok_or_else(|| syntax("expected `allow|deny name: expression`"))?;
+            let (effect, name) = match head.split_whitespace().collect::<Vec<_>>()[..] {
+                ["allow", name] => (Effect::Allow, name),
+                ["deny", name] => (Effect::Deny, name),
+                _ => return Err(syntax("expected `allow|deny name: expression`")),
+            };
+            if !names.insert(name) {
+                return Err(syntax(&format!("rule {} is defined twice", name)));
+            }
+            let script = Script::new(expression.trim()).map_err(|source| {
+                PolicyError::Rule {
+                    rule: name.to_string(),
+                    source,
+                }
+            })?;
+            policy.rules.push(Rule::new(name, effect, script));
+        }
+        Ok(policy)
+    }
+
+    /// Decides whether `subject` can `access` `resource`.
+    pub fn decide(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> Decision {
+        let variables = Variables::new(subject, access, resource);
+        let rules = |effect| self.rules.iter().filter(move |rule| rule.effect == effect);
+        for rule in rules(Effect::Deny) {
+            match rule.script.evaluate_with(&variables) {
+                Ok(false) => {}
+                Ok(true) => {
+                    return Decision::Deny {
+                        rule: Some(rule.name.clone()),
+                    }
+                }
+                Err(err) => {
+                    tracing::warn!(rule = %rule.name, %err, "deny rule failed, denying");
+                    return Decision::Deny {
+                        rule: Some(rule.name.clone()),
+                    };
+                }
+            }
+        }
+        for rule in rules(Effect::Allow) {
+            match rule.script.evaluate_with(&variables) {
+                Ok(true) => {
+                    return Decision::Allow {
+                        rule: rule.name.clone(),
+                    }
+                }
+                Ok(false) => {}
+                Err(err) => tracing::warn!(rule = %rule.name, %err, "allow rule failed, skipping"),
+            }
+        }
+        Decision::Deny { rule: None }
+    }
+}
+
+impl AccessControl for PolicySet {
+    async fn check(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> bool {
+        self.decide(subject, access, resource).is_allowed()
+    }
+}
+
+#[derive(Debug)]
+struct Loaded {
+    source: String,
+    policy: Arc<PolicySet>,
+}
+
+/// A [`PolicySet`] loaded from a file, and reloaded when the file changes.
+#[derive(Debug)]
+pub struct PolicyFile {
+    path: PathBuf,
+    loaded: RwLock<Loaded>,
+}
+
+impl PolicyFile {
+    /// Loads the policy in the file at `path`.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self, PolicyError> {
+        let path = path.into();
+        let source = std::fs::read_to_string(&path)?;
+        let policy = Arc::new(PolicySet::parse(&source)?);
+        Ok(Self {
+            path,
+            loaded: RwLock::new(Loaded { source, policy }),
+        })
+    }
+
+    /// Returns the path of the file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the policy in effect.
+    pub fn policy(&self) -> Arc<PolicySet> {
+        self.loaded.read().unwrap().policy.clone()
+    }
+
+    /// Loads the file again if it changed, returning true if it did. If the
+    /// new policy can't be loaded the old one stays in effect.
+    pub fn reload(&self) -> Result<bool, PolicyError> {
+        let source = std::fs::read_to_string(&self.path)?;
+        if self.loaded.read().unwrap().source == source {
+            return Ok(false);
+        }
+        let policy = Arc::new(PolicySet::parse(&source)?);
+        *self.loaded.write().unwrap() = Loaded { source, policy };
+        Ok(true)
+    }
+
+    /// Checks the file for changes every `interval`, until the task is
+    /// aborted. Policies that can't be loaded are logged and skipped.
+    pub fn watch(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let file = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                match file.reload() {
+                    Ok(true) => tracing::info!(path = %file.path.display(), "reloaded policy"),
+                    Ok(false) => {}
+                    Err(err) => {
+                        tracing::warn!(path = %file.path.display(), %err, "keeping old policy")
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl AccessControl for PolicyFile {
+    async fn check(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> bool {
+        self.policy().decide(subject, access, resource).is_allowed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::access_control::tuple::{Object, User},
+        okstd::prelude::*,
+    };
+
+    const POLICY: &str = r#"
+        # Everyone can read the handbook, but only alice can write it.
+        allow readers: action == "read"
+        allow alice: subject == "alice"
+        deny mallory: subject == "mallory"
+    "#;
+
+    fn decide(policy: &PolicySet, subject: &str, action: &str) -> Decision {
+        policy.decide(
+            User::Id(subject.to_string()),
+            action,
+            Object::new("doc", "handbook"),
+        )
+    }
+
+    #[okstd::test]
+    fn test_deny_overrides_allow() {
+        let policy = PolicySet::parse(POLICY).unwrap();
+        assert_eq!(
+            decide(&policy, "bob", "read"),
+            Decision::Allow {
+                rule: "readers".to_string()
+            }
+        );
+        assert_eq!(
+            decide(&policy, "alice", "write"),
+            Decision::Allow {
+                rule: "alice".to_string()
+            }
+        );
+        assert_eq!(
+            decide(&policy, "mallory", "read"),
+            Decision::Deny {
+                rule: Some("mallory".to_string())
+            }
+        );
+        assert_eq!(decide(&policy, "bob", "write"), Decision::Deny { rule: None });
+    }
+
+    #[okstd::test]
+    fn test_failing_deny_rules_deny() {
+        let policy = PolicySet::new()
+            .rule(Rule::allow("all", Script::new("true").unwrap()))
+            .rule(Rule::deny("typo", Script::new(r#"subjct == "mallory""#).unwrap()));
+        assert_eq!(
+            decide(&policy, "bob", "read"),
+            Decision::Deny {
+                rule: Some("typo".to_string())
+            }
+        );
+    }
+
+    #[okstd::test]
+    fn test_invalid_policies_are_rejected() {
+        assert!(matches!(
+            PolicySet::parse("allow readers action == \"read\""),
+            Err(PolicyError::Syntax { line: 1, .. })
+        ));
+        assert!(matches!(
+            PolicySet::parse("allow a: true\npermit b: true"),
+            Err(PolicyError::Syntax { line: 2, .. })
+        ));
+        assert!(matches!(
+            PolicySet::parse("allow a: true\ndeny a: false"),
+            Err(PolicyError::Syntax { line: 2, .. })
+        ));
+        assert!(matches!(
+            PolicySet::parse("allow a: (true"),
+            Err(PolicyError::Rule { rule, .. }) if rule == "a"
+        ));
+    }
+
+    #[okstd::test]
+    fn test_policy_files_are_reloaded() {
+        let path = std::env::temp_dir().join(format!("jetstream-policy-{}.cel", std::process::id()));
+        std::fs::write(&path, "allow readers: action == \"read\"").unwrap();
+        let file = PolicyFile::open(&path).unwrap();
+        assert!(!file.reload().unwrap());
+        assert!(!decide(&file.policy(), "bob", "write").is_allowed());
+
+        std::fs::write(&path, "allow writers: action == \"write\"").unwrap();
+        assert!(file.reload().unwrap());
+        assert!(decide(&file.policy(), "bob", "write").is_allowed());
+
+        // A broken policy leaves the old one in effect.
+        std::fs::write(&path, "allow writers: (").unwrap();
+        assert!(file.reload().is_err());
+        assert!(decide(&file.policy(), "bob", "write").is_allowed());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Access control as a jetstream service.
+//!
+//! Rather than every service embedding its policy, a permission server serves
+//! a [`Zanzibar`] with [`AclServer`], and services share it through an
+//! [`AclClient`]:
+//!
+//! ```ignore
+//! // On the permission server, for every connection:
+//! let mut serv = acl_protocol::AclService { inner: AclServer::new(acl.clone()) };
+//! run(&mut serv, Framed::new(stream, ServerCodec::default())).await?;
+//!
+//! // In a service:
+//! let acl = AclClient::new(Framed::new(stream, ClientCodec::default()));
+//! let zookie = acl.write(vec!["doc:design#editor@user123".parse()?]).await?;
+//! let fresh = Consistency::AtLeastAsFresh(zookie);
+//! assert!(acl.check_user("doc:design".parse()?, "editor", "user123".parse()?, fresh).await?);
+//! ```
+//!
+//! [`AclClient`] is an [`AccessControl`], so an
+//! [`AuthorizeLayer`](super::authorize::AuthorizeLayer) can check requests
+//! against the permission server.
+
+use {
+    super::{
+        consistency::{Consistency, Zookie},
+        store::TupleStore,
+        tuple::{Object, RelationTuple, User},
+        zanzibar::{UsersetTree, Zanzibar},
+        AccessControl,
+        Resource,
+        Subject,
+        Verb,
+    },
+    acl_protocol::AclChannel,
+    cel_interpreter::Value,
+    jetstream_macros::service,
+    jetstream_rpc::{ClientTransport, Error, Status},
+    std::sync::Arc,
+    tokio::sync::Mutex,
+};
+
+/// The calls of the permission server.
+#[service(crate = jetstream_rpc)]
+pub trait Acl {
+    /// Returns true if `user` has `relation` to `object`.
+    async fn check(
+        &mut self,
+        #[resource] object: Object,
+        relation: String,
+        user: User,
+        consistency: Consistency,
+    ) -> Result<bool, Error>;
+    /// Stores `tuples`, returning the zookie of the revision that includes
+    /// them.
+    async fn write(
+        &mut self,
+        tuples: Vec<RelationTuple>,
+    ) -> Result<Zookie, Error>;
+    /// Removes `tuples`, returning the zookie of the revision that doesn't
+    /// include them.
+    async fn delete(
+        &mut self,
+        tuples: Vec<RelationTuple>,
+    ) -> Result<Zookie, Error>;
+    /// Returns the tree of usersets whose users have `relation` to `object`.
+    async fn expand(
+        &mut self,
+        #[resource] object: Object,
+        relation: String,
+        consistency: Consistency,
+    ) -> Result<UsersetTree, Error>;
+    /// Returns the users the tuples of `object#relation` name, without
+    /// following rewrites or usersets.
+    async fn read(
+        &mut self,
+        #[resource] object: Object,
+        relation: String,
+        consistency: Consistency,
+    ) -> Result<Vec<User>, Error>;
+}
+
+/// Refuses the call with a status, which is sent back in an error frame
+/// rather than ending the connection and the other calls on it.
+fn refuse(err: crate::Error) -> Error {
+    let status = match &err {
+        crate::Error::InvalidZookie(_) => Status::permission_denied(err.to_string()),
+        crate::Error::SnapshotUnavailable(_) => Status::failed_precondition(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    };
+    Error::Status(status)
+}
+
+/// Serves a [`Zanzibar`], shared by every connection.
+pub struct AclServer<S> {
+    acl: Arc<Zanzibar<S>>,
+}
+
+impl<S> AclServer<S> {
+    /// Returns a server for `acl`.
+    pub fn new(acl: Arc<Zanzibar<S>>) -> Self {
+        Self { acl }
+    }
+}
+
+impl<S> Clone for AclServer<S> {
+    fn clone(&self) -> Self {
+        Self {
+            acl: self.acl.clone(),
+        }
+    }
+}
+
+impl<S: TupleStore> Acl for AclServer<S> {
+    async fn check(
+        &mut self,
+        object: Object,
+        relation: String,
+        user: User,
+        consistency: Consistency,
+    ) -> Result<bool, Error> {
+        self.acl
+            .check_user(&object, &relation, &user, consistency)
+            .await
+            .map_err(refuse)
+    }
+
+    async fn write(&mut self, tuples: Vec<RelationTuple>) -> Result<Zookie, Error> {
+        self.acl.store().write(&tuples).await.map_err(refuse)
+    }
+
+    async fn delete(&mut self, tuples: Vec<RelationTuple>) -> Result<Zookie, Error> {
+        self.acl.store().delete(&tuples).await.map_err(refuse)
+    }
+
+    async fn expand(
+        &mut self,
+        object: Object,
+        relation: String,
+        consistency: Consistency,
+    ) -> Result<UsersetTree, Error> {
+        self.acl
+            .expand(&object, &relation, consistency)
+            .await
+            .map_err(refuse)
+    }
+
+    async fn read(
+        &mut self,
+        object: Object,
+        relation: String,
+        consistency: Consistency,
+    ) -> Result<Vec<User>, Error> {
+        let store = self.acl.store();
+        let snapshot = store.snapshot(consistency).await.map_err(refuse)?;
+        store
+            .read(&object, &relation, snapshot)
+            .await
+            .map_err(refuse)
+    }
+}
+
+/// Calls a permission server over a transport. Calls are made one at a time.
+pub struct AclClient<T> {
+    transport: Mutex<T>,
+}
+
+impl<T> AclClient<T>
+where
+    T: for<'a> ClientTransport<AclChannel<'a>>,
+{
+    /// Returns a client calling the server at the other end of `transport`.
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport: Mutex::new(transport),
+        }
+    }
+
+    /// Returns true if `user` has `relation` to `object`, in the snapshot
+    /// `consistency` picks.
+    pub async fn check_user(
+        &self,
+        object: Object,
+        relation: impl Into<String>,
+        user: User,
+        consistency: Consistency,
+    ) -> Result<bool, Error> {
+        let mut transport = self.transport.lock().await;
+        let mut chan = AclChannel {
+            inner: Box::new(&mut *transport),
+        };
+        chan.check(object, relation.into(), user, consistency).await
+    }
+
+    /// Stores `tuples`, returning the zookie of the revision that includes
+    /// them.
+    pub async fn write(&self, tuples: Vec<RelationTuple>) -> Result<Zookie, Error> {
+        let mut transport = self.transport.lock().await;
+        let mut chan = AclChannel {
+            inner: Box::new(&mut *transport),
+        };
+        chan.write(tuples).await
+    }
+
+    /// Removes `tuples`, returning the zookie of the revision that doesn't
+    /// include them.
+    pub async fn delete(&self, tuples: Vec<RelationTuple>) -> Result<Zookie, Error> {
+        let mut transport = self.transport.lock().await;
+        let mut chan = AclChannel {
+            inner: Box::new(&mut *transport),
+        };
+        chan.delete(tuples).await
+    }
+
+    /// Returns the tree of usersets whose users have `relation` to `object`,
+    /// see [`Zanzibar::expand`].
+    pub async fn expand(
+        &self,
+        object: Object,
+        relation: impl Into<String>,
+        consistency: Consistency,
+    ) -> Result<UsersetTree, Error> {
+        let mut transport = self.transport.lock().await;
+        let mut chan = AclChannel {
+            inner: Box::new(&mut *transport),
+        };
+        chan.expand(object, relation.into(), consistency).await
+    }
+
+    /// Returns the users the tuples of `object#relation` name.
+    pub async fn read(
+        &self,
+        object: Object,
+        relation: impl Into<String>,
+        consistency: Consistency,
+    ) -> Result<Vec<User>, Error> {
+        let mut transport = self.transport.lock().await;
+        let mut chan = AclChannel {
+            inner: Box::new(&mut *transport),
+        };
+        chan.read(object, relation.into(), consistency).await
+    }
+}
+
+/// Checks on the server, the way [`Zanzibar`] does. Subjects and resources
+/// whose ids aren't a user and an object, and failed calls, deny access.
+impl<T> AccessControl for AclClient<T>
+where
+    T: for<'a> ClientTransport<AclChannel<'a>>,
+{
+    async fn check(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> bool {
+        let (Ok(user), Ok(object)) = (subject.id().parse::<User>(), resource.id().parse::<Object>())
+        else {
+            return false;
+        };
+        let Value::String(relation) = access.into_value() else {
+            return false;
+        };
+        self.check_user(object, relation.to_string(), user, Consistency::default())
+            .await
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{acl_protocol::*, *},
+        crate::access_control::{consistency::ZookieKey, store::MemoryStore},
+        jetstream_rpc::{Code, Frame, Framer, Protocol},
+        jetstream_wireformat::WireFormat,
+        okstd::prelude::*,
+    };
+
+    fn tuple(tuple: &str) -> RelationTuple {
+        tuple.parse().unwrap()
+    }
+
+    async fn call(serv: &mut AclService<AclServer<MemoryStore>>, req: Tmessage) -> Rmessage {
+        serv.rpc(Frame::from((0, req))).await.unwrap().msg
+    }
+
+    #[okstd::test]
+    async fn test_service_checks_written_tuples() {
+        let acl = Arc::new(Zanzibar::new(MemoryStore::new()));
+        let mut serv = AclService {
+            inner: AclServer::new(acl),
+        };
+        let req = Tmessage::Write(Twrite {
+            tuples: vec![tuple("doc:design#editor@user123")],
+        });
+        let Rmessage::Write(Rwrite(zookie)) = call(&mut serv, req).await else {
+            panic!("expected a zookie");
+        };
+        let check = |user: &str| {
+            Tmessage::Check(Tcheck {
+                object: "doc:design".parse().unwrap(),
+                relation: "editor".to_string(),
+                user: user.parse().unwrap(),
+                consistency: Consistency::AtLeastAsFresh(zookie),
+            })
+        };
+        assert_eq!(check("user123").resource().as_deref(), Some("doc:design"));
+        assert!(matches!(call(&mut serv, check("user123")).await, Rmessage::Check(Rcheck(true))));
+        assert!(matches!(call(&mut serv, check("user456")).await, Rmessage::Check(Rcheck(false))));
+
+        // A zookie another store issued is refused with an error frame.
+        let forged = Tmessage::Check(Tcheck {
+            object: "doc:design".parse().unwrap(),
+            relation: "editor".to_string(),
+            user: "user123".parse().unwrap(),
+            consistency: Consistency::AtLeastAsFresh(ZookieKey::random().zookie(1)),
+        });
+        let Rmessage::Error(status) = call(&mut serv, forged).await else {
+            panic!("expected the zookie to be refused");
+        };
+        assert_eq!(status.code, Code::PermissionDenied);
+    }
+
+    #[okstd::test]
+    fn test_trees_round_trip() {
+        let tree = UsersetTree::Union {
+            userset: "doc:design#viewer".parse().unwrap(),
+            children: vec![UsersetTree::Leaf {
+                userset: "doc:design#editor".parse().unwrap(),
+                users: vec![
+                    "user123".parse().unwrap(),
+                    "folder:root".parse().unwrap(),
+                    "team:eng#member".parse().unwrap(),
+                ],
+            }],
+        };
+        let mut buf = Vec::new();
+        tree.encode(&mut buf).unwrap();
+        assert_eq!(buf.len() as u32, tree.byte_size());
+        assert_eq!(UsersetTree::decode(&mut buf.as_slice()).unwrap(), tree);
+    }
+}
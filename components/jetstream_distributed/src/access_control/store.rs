@@ -0,0 +1,215 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Where relation tuples are kept.
+//!
+//! [`TupleStore`] is what [`Zanzibar`](super::zanzibar::Zanzibar) needs of a
+//! store. [`MemoryStore`] keeps tuples in memory, persistent stores implement
+//! the trait over a database.
+//!
+//! Stores are versioned: every write makes a new revision, and reads are of a
+//! snapshot, see [`consistency`](super::consistency).
+
+use {
+    super::{
+        consistency::{Consistency, Zookie, ZookieKey},
+        tuple::{Object, RelationTuple, User},
+    },
+    crate::{Error, Result},
+    std::{
+        collections::BTreeMap,
+        sync::RwLock,
+    },
+};
+
+/// Versioned storage for relation tuples.
+#[trait_variant::make(Send + Sync)]
+pub trait TupleStore {
+    /// Adds `tuples`. Tuples that are already stored are left as they are.
+    /// Returns the zookie of the revision that includes them.
+    async fn write(&self, tuples: &[RelationTuple]) -> Result<Zookie>;
+    /// Removes `tuples`. Tuples that aren't stored are ignored. Returns the
+    /// zookie of the revision that doesn't include them.
+    async fn delete(&self, tuples: &[RelationTuple]) -> Result<Zookie>;
+    /// Picks the snapshot to read for `consistency`. Stores that can't
+    /// provide a fresh enough snapshot fail with
+    /// [`Error::SnapshotUnavailable`], and zookies they didn't issue with
+    /// [`Error::InvalidZookie`].
+    async fn snapshot(&self, consistency: Consistency) -> Result<Zookie>;
+    /// Returns the users the tuples of `object#relation` name in `snapshot`.
+    /// Stores that no longer have the snapshot fail with
+    /// [`Error::SnapshotUnavailable`].
+    async fn read(&self, object: &Object, relation: &str, snapshot: Zookie) -> Result<Vec<User>>;
+    /// Returns every object in `namespace` that has tuples in `snapshot`.
+    async fn objects(&self, namespace: &str, snapshot: Zookie) -> Result<Vec<Object>>;
+}
+
+/// When a tuple was stored, from its `added` revision until the revision it
+/// was `removed` in.
+#[derive(Debug, Clone, Copy)]
+struct Lifetime {
+    added: u64,
+    removed: Option<u64>,
+}
+
+impl Lifetime {
+    fn contains(&self, revision: u64) -> bool {
+        self.added <= revision && self.removed.is_none_or(|removed| revision < removed)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Tuples {
+    revision: u64,
+    /// The oldest revision that can still be read.
+    horizon: u64,
+    tuples: BTreeMap<(Object, String), BTreeMap<User, Vec<Lifetime>>>,
+}
+
+impl Tuples {
+    /// Fails if `snapshot` was compacted away.
+    fn check(&self, snapshot: Zookie) -> Result<()> {
+        if snapshot.revision() < self.horizon {
+            return Err(Error::SnapshotUnavailable(snapshot));
+        }
+        Ok(())
+    }
+}
+
+/// A [`TupleStore`] that keeps tuples in memory.
+///
+/// Removed tuples are kept so older snapshots can still be read, until they
+/// are [compacted](MemoryStore::compact).
+#[derive(Debug)]
+pub struct MemoryStore {
+    key: ZookieKey,
+    tuples: RwLock<Tuples>,
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::with_key(ZookieKey::random())
+    }
+}
+
+impl MemoryStore {
+    /// Returns an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns an empty store that makes its zookies with `key`.
+    pub fn with_key(key: ZookieKey) -> Self {
+        Self {
+            key,
+            tuples: RwLock::default(),
+        }
+    }
+
+    /// Forgets tuples removed at or before `zookie`, after which snapshots
+    /// from before it can't be read.
+    pub fn compact(&self, zookie: Zookie) {
+        let mut stored = self.tuples.write().unwrap();
+        stored.horizon = stored.horizon.max(zookie.revision());
+        stored.tuples.retain(|_, users| {
+            users.retain(|_, lifetimes| {
+                lifetimes.retain(|lifetime| {
+                    lifetime
+                        .removed
+                        .is_none_or(|removed| removed > zookie.revision())
+                });
+                !lifetimes.is_empty()
+            });
+            !users.is_empty()
+        });
+    }
+}
+
+impl TupleStore for MemoryStore {
+    async fn write(&self, tuples: &[RelationTuple]) -> Result<Zookie> {
+        let mut stored = self.tuples.write().unwrap();
+        stored.revision += 1;
+        let revision = stored.revision;
+        for tuple in tuples {
+            let lifetimes = stored
+                .tuples
+                .entry((tuple.object.clone(), tuple.relation.clone()))
+                .or_default()
+                .entry(tuple.user.clone())
+                .or_default();
+            if !lifetimes.iter().any(|lifetime| lifetime.removed.is_none()) {
+                lifetimes.push(Lifetime {
+                    added: revision,
+                    removed: None,
+                });
+            }
+        }
+        Ok(self.key.zookie(revision))
+    }
+
+    async fn delete(&self, tuples: &[RelationTuple]) -> Result<Zookie> {
+        let mut stored = self.tuples.write().unwrap();
+        stored.revision += 1;
+        let revision = stored.revision;
+        for tuple in tuples {
+            let key = (tuple.object.clone(), tuple.relation.clone());
+            let lifetimes = stored
+                .tuples
+                .get_mut(&key)
+                .and_then(|users| users.get_mut(&tuple.user));
+            for lifetime in lifetimes.into_iter().flatten() {
+                lifetime.removed.get_or_insert(revision);
+            }
+        }
+        Ok(self.key.zookie(revision))
+    }
+
+    async fn snapshot(&self, consistency: Consistency) -> Result<Zookie> {
+        let latest = self.tuples.read().unwrap().revision;
+        match consistency {
+            Consistency::AtLeastAsFresh(zookie) if !self.key.verify(&zookie) => {
+                Err(Error::InvalidZookie(zookie))
+            }
+            Consistency::AtLeastAsFresh(zookie) if zookie.revision() > latest => {
+                Err(Error::SnapshotUnavailable(zookie))
+            }
+            _ => Ok(self.key.zookie(latest)),
+        }
+    }
+
+    async fn read(&self, object: &Object, relation: &str, snapshot: Zookie) -> Result<Vec<User>> {
+        let stored = self.tuples.read().unwrap();
+        stored.check(snapshot)?;
+        Ok(stored
+            .tuples
+            .get(&(object.clone(), relation.to_string()))
+            .into_iter()
+            .flatten()
+            .filter(|(_, lifetimes)| {
+                lifetimes
+                    .iter()
+                    .any(|lifetime| lifetime.contains(snapshot.revision()))
+            })
+            .map(|(user, _)| user.clone())
+            .collect())
+    }
+
+    async fn objects(&self, namespace: &str, snapshot: Zookie) -> Result<Vec<Object>> {
+        let stored = self.tuples.read().unwrap();
+        stored.check(snapshot)?;
+        let mut objects: Vec<Object> = stored
+            .tuples
+            .iter()
+            .filter(|((object, _), users)| {
+                object.namespace == namespace
+                    && users.values().flatten().any(|lifetime| lifetime.contains(snapshot.revision()))
+            })
+            .map(|((object, _), _)| object.clone())
+            .collect();
+        // Keys are sorted, so an object's relations are next to each other.
+        objects.dedup();
+        Ok(objects)
+    }
+}
+
@@ -0,0 +1,205 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Relation tuples, and their text syntax.
+//!
+//! A relation tuple says a user has a relation to an object:
+//!
+//! ```text
+//! tuple   = object "#" relation "@" user
+//! object  = namespace ":" id
+//! user    = id | object | object "#" relation
+//! ```
+//!
+//! So `doc:design#editor@user123` makes `user123` an editor of
+//! `doc:design`, and `repo:main#write@team:eng#member` gives write access to
+//! every member of `team:eng`.
+
+use {
+    jetstream_wireformat::JetStreamWireFormat,
+    std::{fmt, str::FromStr},
+};
+
+/// Why a tuple, object or user couldn't be parsed.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// An object without the `:` between its namespace and id.
+    #[error("expected namespace:id, got {0:?}")]
+    Object(String),
+    /// A tuple without a `#relation` or an `@user`.
+    #[error("expected object#relation@user, got {0:?}")]
+    Tuple(String),
+    /// A consistency token that isn't one.
+    #[error("invalid zookie {0:?}")]
+    Zookie(String),
+    /// A namespace, id or relation that is empty.
+    #[error("empty {0} in {1:?}")]
+    Empty(&'static str, String),
+}
+
+fn non_empty<'a>(part: &'static str, value: &'a str, input: &str) -> Result<&'a str, ParseError> {
+    if value.is_empty() {
+        Err(ParseError::Empty(part, input.to_string()))
+    } else {
+        Ok(value)
+    }
+}
+
+/// An object in a namespace, like `doc:design`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, JetStreamWireFormat)]
+pub struct Object {
+    /// The namespace the object belongs to, like `doc`.
+    pub namespace: String,
+    /// The object's id within its namespace, like `design`.
+    pub id: String,
+}
+
+impl Object {
+    /// Returns the object `id` in `namespace`.
+    pub fn new(namespace: impl Into<String>, id: impl Into<String>) -> Self {
+        Self {
+            namespace: namespace.into(),
+            id: id.into(),
+        }
+    }
+}
+
+impl fmt::Display for Object {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.id)
+    }
+}
+
+impl FromStr for Object {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (namespace, id) = s
+            .split_once(':')
+            .ok_or_else(|| ParseError::Object(s.to_string()))?;
+        Ok(Self::new(
+            non_empty("namespace", namespace, s)?,
+            non_empty("id", id, s)?,
+        ))
+    }
+}
+
+/// The set of users with a relation to an object, like `team:eng#member`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, JetStreamWireFormat)]
+pub struct Userset {
+    /// The object the users are related to.
+    pub object: Object,
+    /// The relation they have to it.
+    pub relation: String,
+}
+
+impl Userset {
+    /// Returns the users with `relation` to `object`.
+    pub fn new(object: Object, relation: impl Into<String>) -> Self {
+        Self {
+            object,
+            relation: relation.into(),
+        }
+    }
+}
+
+impl fmt::Display for Userset {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}#{}", self.object, self.relation)
+    }
+}
+
+impl FromStr for Userset {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (object, relation) = s
+            .split_once('#')
+            .ok_or_else(|| ParseError::Tuple(s.to_string()))?;
+        Ok(Self::new(object.parse()?, non_empty("relation", relation, s)?))
+    }
+}
+
+/// Who a tuple relates to its object.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, JetStreamWireFormat)]
+pub enum User {
+    /// A single user, like `user123`.
+    Id(String),
+    /// An object, like `folder:root`. Objects are users too: relations such
+    /// as a document's `parent` point at them, and a subject can be
+    /// identified as one, like `uid:1000`.
+    Object(Object),
+    /// Every user in a userset, like `team:eng#member`.
+    Set(Userset),
+}
+
+impl fmt::Display for User {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            User::Id(id) => f.write_str(id),
+            User::Object(object) => object.fmt(f),
+            User::Set(userset) => userset.fmt(f),
+        }
+    }
+}
+
+impl FromStr for User {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains('#') {
+            s.parse().map(User::Set)
+        } else if s.contains(':') {
+            s.parse().map(User::Object)
+        } else {
+            non_empty("id", s, s).map(|id| User::Id(id.to_string()))
+        }
+    }
+}
+
+/// A user's relation to an object, like `doc:design#editor@user123`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, JetStreamWireFormat)]
+pub struct RelationTuple {
+    /// The object the user is related to.
+    pub object: Object,
+    /// How the user is related to it.
+    pub relation: String,
+    /// Who is related to it.
+    pub user: User,
+}
+
+impl RelationTuple {
+    /// Returns a tuple giving `user` `relation` to `object`.
+    pub fn new(object: Object, relation: impl Into<String>, user: User) -> Self {
+        Self {
+            object,
+            relation: relation.into(),
+            user,
+        }
+    }
+}
+
+impl fmt::Display for RelationTuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}#{}@{}", self.object, self.relation, self.user)
+    }
+}
+
+impl FromStr for RelationTuple {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (object, rest) = s
+            .split_once('#')
+            .ok_or_else(|| ParseError::Tuple(s.to_string()))?;
+        let (relation, user) = rest
+            .split_once('@')
+            .ok_or_else(|| ParseError::Tuple(s.to_string()))?;
+        Ok(Self::new(
+            object.parse()?,
+            non_empty("relation", relation, s)?,
+            user.parse()?,
+        ))
+    }
+}
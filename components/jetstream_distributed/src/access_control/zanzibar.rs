@@ -0,0 +1,825 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Checking relations over stored tuples, the way Zanzibar does.
+//!
+//! ```ignore
+//! let acl = Zanzibar::new(MemoryStore::new()).with_namespace(repo);
+//! let zookie = acl
+//!     .store()
+//!     .write(&["repo:main#write@team:eng#member".parse()?, "team:eng#member@user456".parse()?])
+//!     .await?;
+//! let fresh = Consistency::AtLeastAsFresh(zookie);
+//! assert!(acl.check_user(&"repo:main".parse()?, "read", &"user456".parse()?, fresh).await?);
+//! ```
+//!
+//! Besides checks, [`Zanzibar::expand`] answers how users come to have a
+//! relation, [`Zanzibar::lookup_subjects`] who has it, and
+//! [`Zanzibar::lookup_resources`] what a user has it to:
+//!
+//! ```ignore
+//! let page = Page::new(100);
+//! let readers = acl.lookup_subjects(&"repo:main".parse()?, "read", &page, fresh).await?;
+//! let writable = acl.lookup_resources("repo", "write", &"user123".parse()?, &page, fresh).await?;
+//! ```
+
+use {
+    super::{
+        namespace::{Namespace, Rewrite},
+        store::TupleStore,
+        consistency::{Consistency, Zookie},
+        tuple::{Object, User, Userset},
+        AccessControl,
+        Resource,
+        Subject,
+        Verb,
+    },
+    crate::{Error, Result},
+    cel_interpreter::Value,
+    jetstream_wireformat::JetStreamWireFormat,
+    std::{
+        collections::{HashMap, HashSet},
+        future::Future,
+        pin::Pin,
+    },
+};
+
+// Sync as well as Send, so expansions can be served by `#[service]`s.
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + Sync + 'a>>;
+
+/// An [`AccessControl`] that checks relations between users and objects,
+/// following the namespaces' rewrite rules through the tuples in a store.
+#[derive(Debug)]
+pub struct Zanzibar<S> {
+    store: S,
+    namespaces: HashMap<String, Namespace>,
+}
+
+impl<S: TupleStore> Zanzibar<S> {
+    /// Returns a checker over the tuples in `store`. Namespaces without a
+    /// config only have the relations their tuples name directly.
+    pub fn new(store: S) -> Self {
+        Self {
+            store,
+            namespaces: HashMap::new(),
+        }
+    }
+
+    /// Adds the config of a namespace.
+    pub fn with_namespace(mut self, namespace: Namespace) -> Self {
+        self.namespaces
+            .insert(namespace.name().to_string(), namespace);
+        self
+    }
+
+    /// Returns the store the tuples are read from, and written to.
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    fn rewrite(&self, object: &Object, relation: &str) -> &Rewrite {
+        self.namespaces
+            .get(&object.namespace)
+            .map_or(&Rewrite::This, |namespace| namespace.rewrite(relation))
+    }
+
+    /// Returns true if `user` has `relation` to `object`, in the snapshot
+    /// `consistency` picks.
+    pub async fn check_user(
+        &self,
+        object: &Object,
+        relation: &str,
+        user: &User,
+        consistency: Consistency,
+    ) -> Result<bool> {
+        let snapshot = self.store.snapshot(consistency).await?;
+        self.walk(object, relation, snapshot, |related| related == user)
+            .await
+    }
+
+    async fn check_subject(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+        consistency: Consistency,
+    ) -> bool {
+        let (Ok(user), Ok(object)) = (subject.id().parse::<User>(), resource.id().parse::<Object>())
+        else {
+            return false;
+        };
+        let Value::String(relation) = access.into_value() else {
+            return false;
+        };
+        self.check_user(&object, &relation, &user, consistency)
+            .await
+            .unwrap_or(false)
+    }
+
+    /// Returns an [`AccessControl`] that checks snapshots that include the
+    /// write `zookie` came from.
+    pub fn at_least_as_fresh(&self, zookie: Zookie) -> AtLeastAsFresh<'_, S> {
+        AtLeastAsFresh { acl: self, zookie }
+    }
+
+    /// Calls `found` with every user, object or userset that has `relation`
+    /// to `object` in `snapshot`, until it returns true. Usersets are
+    /// passed to `found` before their users are.
+    ///
+    /// Every `object#relation` the walk through usersets reaches is only
+    /// visited once, so tuples that form a cycle, like two groups that are
+    /// members of each other, end the walk rather than loop.
+    async fn walk(
+        &self,
+        object: &Object,
+        relation: &str,
+        snapshot: Zookie,
+        mut found: impl FnMut(&User) -> bool + Send,
+    ) -> Result<bool> {
+        let mut visited = HashSet::new();
+        let mut pending = vec![(object.clone(), relation.to_string())];
+        while let Some((object, relation)) = pending.pop() {
+            if !visited.insert((object.clone(), relation.clone())) {
+                continue;
+            }
+            let mut rewrites = vec![self.rewrite(&object, &relation)];
+            while let Some(rewrite) = rewrites.pop() {
+                match rewrite {
+                    Rewrite::This => {
+                        for related in self.store.read(&object, &relation, snapshot).await? {
+                            if found(&related) {
+                                return Ok(true);
+                            }
+                            if let User::Set(userset) = related {
+                                pending.push((userset.object, userset.relation))
+                            }
+                        }
+                    }
+                    Rewrite::ComputedUserset(computed) => {
+                        pending.push((object.clone(), computed.clone()))
+                    }
+                    Rewrite::TupleToUserset {
+                        tupleset,
+                        computed_userset,
+                    } => {
+                        for related in self.store.read(&object, tupleset, snapshot).await? {
+                            match related {
+                                User::Object(related) => {
+                                    pending.push((related, computed_userset.clone()))
+                                }
+                                User::Set(userset) => {
+                                    pending.push((userset.object, computed_userset.clone()))
+                                }
+                                User::Id(_) => {}
+                            }
+                        }
+                    }
+                    Rewrite::Union(union) => rewrites.extend(union),
+                }
+            }
+        }
+        Ok(false)
+    }
+
+    /// Returns the tree of usersets whose users have `relation` to `object`,
+    /// following the rewrite rules of its namespace. Usersets named by
+    /// tuples are left for the caller to expand, and rewrites that lead back
+    /// to a userset being expanded end in a [`UsersetTree::Cycle`].
+    pub async fn expand(
+        &self,
+        object: &Object,
+        relation: &str,
+        consistency: Consistency,
+    ) -> Result<UsersetTree> {
+        let snapshot = self.store.snapshot(consistency).await?;
+        let userset = Userset::new(object.clone(), relation);
+        let mut path = HashSet::new();
+        self.expand_userset(userset, snapshot, &mut path).await
+    }
+
+    /// Expands `userset`, unless it is on the `path` of usersets from the
+    /// root being expanded. Usersets reached along other paths are expanded
+    /// again, each subtree stands on its own.
+    fn expand_userset<'a>(
+        &'a self,
+        userset: Userset,
+        snapshot: Zookie,
+        path: &'a mut HashSet<Userset>,
+    ) -> BoxFuture<'a, Result<UsersetTree>> {
+        Box::pin(async move {
+            if path.contains(&userset) {
+                return Ok(UsersetTree::Cycle { userset });
+            }
+            path.insert(userset.clone());
+            let rewrite = self.rewrite(&userset.object, &userset.relation);
+            let tree = self
+                .expand_rewrite(userset.clone(), rewrite, snapshot, path)
+                .await;
+            path.remove(&userset);
+            tree
+        })
+    }
+
+    fn expand_rewrite<'a>(
+        &'a self,
+        userset: Userset,
+        rewrite: &'a Rewrite,
+        snapshot: Zookie,
+        path: &'a mut HashSet<Userset>,
+    ) -> BoxFuture<'a, Result<UsersetTree>> {
+        Box::pin(async move {
+            match rewrite {
+                Rewrite::This => {
+                    let users = self.store.read(&userset.object, &userset.relation, snapshot).await?;
+                    Ok(UsersetTree::Leaf { userset, users })
+                }
+                Rewrite::ComputedUserset(computed) => {
+                    let computed = Userset::new(userset.object.clone(), computed.clone());
+                    self.expand_userset(computed, snapshot, path).await
+                }
+                Rewrite::TupleToUserset {
+                    tupleset,
+                    computed_userset,
+                } => {
+                    let mut children = vec![];
+                    for related in self.store.read(&userset.object, tupleset, snapshot).await? {
+                        let related = match related {
+                            User::Object(related) => related,
+                            User::Set(related) => related.object,
+                            User::Id(_) => continue,
+                        };
+                        let computed = Userset::new(related, computed_userset.clone());
+                        children.push(self.expand_userset(computed, snapshot, path).await?);
+                    }
+                    Ok(UsersetTree::Union { userset, children })
+                }
+                Rewrite::Union(union) => {
+                    let mut children = vec![];
+                    for rewrite in union {
+                        children.push(
+                            self.expand_rewrite(userset.clone(), rewrite, snapshot, path)
+                                .await?,
+                        );
+                    }
+                    Ok(UsersetTree::Union { userset, children })
+                }
+            }
+        })
+    }
+
+    /// Returns the users, objects and usersets that have `relation` to
+    /// `object`, with usersets expanded, in the order they are found. The
+    /// walk through the usersets stops once the page is full, so a page
+    /// only costs as much as the results up to its end.
+    pub async fn lookup_subjects(
+        &self,
+        object: &Object,
+        relation: &str,
+        page: &Page,
+        consistency: Consistency,
+    ) -> Result<Paginated<User>> {
+        // The token counts the results on the pages before.
+        let skip = match page.token.as_deref() {
+            Some(token) => token
+                .parse::<usize>()
+                .map_err(|_| Error::InvalidPageToken(token.to_string()))?,
+            None => 0,
+        };
+        let snapshot = self.store.snapshot(consistency).await?;
+        let mut seen = HashSet::new();
+        let mut items = vec![];
+        self.walk(object, relation, snapshot, |related| {
+            if seen.insert(related.clone()) && seen.len() > skip {
+                items.push(related.clone());
+            }
+            // One more than fits shows there is another page.
+            items.len() > page.size
+        })
+        .await?;
+        let next_page_token = (items.len() > page.size).then(|| (skip + page.size).to_string());
+        items.truncate(page.size);
+        Ok(Paginated {
+            items,
+            next_page_token,
+        })
+    }
+
+    /// Returns the objects in `namespace` that `user` has `relation` to, in
+    /// order. Objects are checked one by one, only as many as the page
+    /// needs.
+    pub async fn lookup_resources(
+        &self,
+        namespace: &str,
+        relation: &str,
+        user: &User,
+        page: &Page,
+        consistency: Consistency,
+    ) -> Result<Paginated<Object>> {
+        // The token is the last object on the page before.
+        if let Some(token) = page.token.as_deref() {
+            match token.parse::<Object>() {
+                Ok(last) if last.namespace == namespace => {}
+                _ => return Err(Error::InvalidPageToken(token.to_string())),
+            }
+        }
+        let snapshot = self.store.snapshot(consistency).await?;
+        let mut objects = self.store.objects(namespace, snapshot).await?;
+        objects.retain(|object| page.includes(&object.to_string()));
+        objects.sort_by_cached_key(|object| object.to_string());
+        let mut resources = vec![];
+        for object in objects {
+            if resources.len() > page.size {
+                break;
+            }
+            if self
+                .walk(&object, relation, snapshot, |related| related == user)
+                .await?
+            {
+                resources.push(object);
+            }
+        }
+        Ok(page.paginate(resources))
+    }
+}
+
+/// The users with a relation to an object, as returned by
+/// [`Zanzibar::expand`].
+#[derive(Debug, Clone, PartialEq, Eq, JetStreamWireFormat)]
+pub enum UsersetTree {
+    /// The users the tuples of `userset` name. Usersets among them aren't
+    /// expanded.
+    Leaf {
+        /// The userset whose tuples were read.
+        userset: Userset,
+        /// The users, objects and usersets they name.
+        users: Vec<User>,
+    },
+    /// The users of any of the children.
+    Union {
+        /// The userset the children make up.
+        userset: Userset,
+        /// The trees of the rewrites, or of the related objects.
+        children: Vec<UsersetTree>,
+    },
+    /// A userset whose rewrites lead back to itself. It adds no users
+    /// beyond those of the tree it is expanded in further up.
+    Cycle {
+        /// The userset being expanded further up.
+        userset: Userset,
+    },
+}
+
+impl UsersetTree {
+    /// Returns the userset the tree is of.
+    pub fn userset(&self) -> &Userset {
+        match self {
+            UsersetTree::Leaf { userset, .. }
+            | UsersetTree::Union { userset, .. }
+            | UsersetTree::Cycle { userset } => userset,
+        }
+    }
+}
+
+/// Which part of a lookup to return. A page starts where the one its token
+/// came with ended.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Page {
+    /// The most results to return.
+    pub size: usize,
+    /// The token of the page before, [`Paginated::next_page_token`]. Tokens
+    /// are opaque, and only good for the lookup they came from.
+    pub token: Option<String>,
+}
+
+impl Page {
+    /// Returns the first page of `size` results.
+    pub fn new(size: usize) -> Self {
+        Self { size, token: None }
+    }
+
+    /// Returns the page that follows the one `token` came with.
+    pub fn after(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    fn includes(&self, key: &str) -> bool {
+        self.token.as_deref().is_none_or(|token| key > token)
+    }
+
+    /// Takes the page from the ordered results after the token.
+    fn paginate<T: ToString>(&self, results: impl IntoIterator<Item = T>) -> Paginated<T> {
+        let mut items: Vec<T> = results.into_iter().take(self.size + 1).collect();
+        let next_page_token = if items.len() > self.size {
+            items.truncate(self.size);
+            items.last().map(ToString::to_string)
+        } else {
+            None
+        };
+        Paginated {
+            items,
+            next_page_token,
+        }
+    }
+}
+
+/// A page of lookup results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Paginated<T> {
+    /// The results on the page.
+    pub items: Vec<T>,
+    /// Where the next page starts, if there are more results.
+    pub next_page_token: Option<String>,
+}
+
+/// Checks whether the subject has the relation named by the verb to the
+/// resource, which is an object like `doc:design`. Subjects whose ids look
+/// like objects, like `uid:1000`, are checked as objects.
+impl<S: TupleStore> AccessControl for Zanzibar<S> {
+    async fn check(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> bool {
+        self.check_subject(subject, access, resource, Consistency::MinimizeLatency)
+            .await
+    }
+}
+
+/// Users are subjects by their text form, like `user123` or `uid:1000`.
+impl Subject for User {
+    fn id(&self) -> String {
+        self.to_string()
+    }
+
+    fn into_value(self) -> Value {
+        Value::String(self.to_string().into())
+    }
+}
+
+/// Objects are resources by their text form, like `doc:design`.
+impl Resource for Object {
+    fn id(&self) -> String {
+        self.to_string()
+    }
+
+    fn into_value(self) -> Value {
+        Value::String(self.to_string().into())
+    }
+}
+
+/// Checks access like [`Zanzibar`] does, in snapshots at least as fresh as a
+/// zookie. See [`Zanzibar::at_least_as_fresh`].
+#[derive(Debug)]
+pub struct AtLeastAsFresh<'a, S> {
+    acl: &'a Zanzibar<S>,
+    zookie: Zookie,
+}
+
+impl<S: TupleStore> AccessControl for AtLeastAsFresh<'_, S> {
+    async fn check(
+        &self,
+        subject: impl Subject,
+        access: impl Verb,
+        resource: impl Resource,
+    ) -> bool {
+        self.acl
+            .check_subject(
+                subject,
+                access,
+                resource,
+                Consistency::AtLeastAsFresh(self.zookie),
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::access_control::{
+            consistency::ZookieKey,
+            store::MemoryStore,
+            tuple::{ParseError, RelationTuple},
+        },
+        okstd::prelude::*,
+    };
+
+    async fn acl(tuples: &[&str]) -> Zanzibar<MemoryStore> {
+        let repo = Namespace::new("repo")
+            .relation("write", Rewrite::union([Rewrite::This, Rewrite::computed("admin")]))
+            .relation(
+                "read",
+                Rewrite::union([
+                    Rewrite::This,
+                    Rewrite::computed("write"),
+                    Rewrite::tuple_to_userset("owner", "member"),
+                ]),
+            );
+        let org = Namespace::new("org").relation(
+            "member",
+            Rewrite::union([Rewrite::This, Rewrite::computed("owner")]),
+        );
+        let acl = Zanzibar::new(MemoryStore::new())
+            .with_namespace(repo)
+            .with_namespace(org);
+        let tuples = tuples
+            .iter()
+            .map(|tuple| tuple.parse().unwrap())
+            .collect::<Vec<RelationTuple>>();
+        acl.store().write(&tuples).await.unwrap();
+        acl
+    }
+
+    async fn check(acl: &Zanzibar<MemoryStore>, object: &str, relation: &str, user: &str) -> bool {
+        acl.check_user(
+            &object.parse().unwrap(),
+            relation,
+            &user.parse().unwrap(),
+            Consistency::FullyConsistent,
+        )
+        .await
+        .unwrap()
+    }
+
+    #[okstd::test]
+    fn test_tuples_round_trip() {
+        for tuple in [
+            "doc:design#editor@user123",
+            "repo:main#write@team:eng#member",
+            "repo:main#owner@org:branch",
+        ] {
+            assert_eq!(tuple.parse::<RelationTuple>().unwrap().to_string(), tuple);
+        }
+        assert_eq!(
+            "doc#editor@user123".parse::<RelationTuple>(),
+            Err(ParseError::Object("doc".to_string()))
+        );
+        assert!("doc:design#@user123".parse::<RelationTuple>().is_err());
+        assert!("doc:design#editor".parse::<RelationTuple>().is_err());
+    }
+
+    #[okstd::test]
+    async fn test_check_follows_rewrites() {
+        let acl = acl(&[
+            "repo:main#admin@user1",
+            "repo:main#write@team:eng#member",
+            "team:eng#member@user456",
+            "repo:main#owner@org:branch",
+            "org:branch#owner@user789",
+        ])
+        .await;
+        // Admins can write, and so read.
+        assert!(check(&acl, "repo:main", "read", "user1").await);
+        // Through a team.
+        assert!(check(&acl, "repo:main", "write", "user456").await);
+        assert!(!check(&acl, "repo:main", "admin", "user456").await);
+        // The team itself, as well as its members.
+        assert!(check(&acl, "repo:main", "read", "team:eng#member").await);
+        // Owners of the organization that owns the repository are members.
+        assert!(check(&acl, "repo:main", "read", "user789").await);
+        assert!(!check(&acl, "repo:main", "write", "user789").await);
+        assert!(!check(&acl, "repo:other", "read", "user1").await);
+    }
+
+    #[okstd::test]
+    async fn test_check_stops_at_cycles() {
+        let acl = acl(&[
+            "group:a#member@group:b#member",
+            "group:b#member@group:a#member",
+            "group:b#member@user1",
+        ])
+        .await;
+        assert!(check(&acl, "group:a", "member", "user1").await);
+        assert!(!check(&acl, "group:a", "member", "user2").await);
+    }
+
+    #[okstd::test]
+    async fn test_expand_follows_rewrites() {
+        let acl = acl(&[
+            "repo:main#admin@user1",
+            "repo:main#write@team:eng#member",
+            "repo:main#owner@org:branch",
+            "org:branch#owner@user789",
+        ])
+        .await;
+        let tree = acl
+            .expand(&"repo:main".parse().unwrap(), "read", Consistency::default())
+            .await
+            .unwrap();
+        let userset = |userset: &str| userset.parse::<Userset>().unwrap();
+        let leaf = |set: &str, users: &[&str]| {
+            UsersetTree::Leaf {
+                userset: userset(set),
+                users: users.iter().map(|user| user.parse().unwrap()).collect(),
+            }
+        };
+        assert_eq!(
+            tree,
+            UsersetTree::Union {
+                userset: userset("repo:main#read"),
+                children: vec![
+                    leaf("repo:main#read", &[]),
+                    UsersetTree::Union {
+                        userset: userset("repo:main#write"),
+                        children: vec![
+                            leaf("repo:main#write", &["team:eng#member"]),
+                            leaf("repo:main#admin", &["user1"]),
+                        ],
+                    },
+                    UsersetTree::Union {
+                        userset: userset("repo:main#read"),
+                        children: vec![UsersetTree::Union {
+                            userset: userset("org:branch#member"),
+                            children: vec![
+                                leaf("org:branch#member", &[]),
+                                leaf("org:branch#owner", &["user789"]),
+                            ],
+                        }],
+                    },
+                ],
+            }
+        );
+    }
+
+    #[okstd::test]
+    async fn test_expand_marks_only_cycles() {
+        let doc = Namespace::new("doc")
+            .relation(
+                "viewer",
+                Rewrite::union([Rewrite::computed("editor"), Rewrite::computed("commenter")]),
+            )
+            .relation("commenter", Rewrite::computed("editor"))
+            .relation("editor", Rewrite::union([Rewrite::This, Rewrite::computed("owner")]))
+            .relation("owner", Rewrite::union([Rewrite::This, Rewrite::computed("editor")]));
+        let acl = Zanzibar::new(MemoryStore::new()).with_namespace(doc);
+        acl.store()
+            .write(&["doc:design#editor@user1".parse().unwrap()])
+            .await
+            .unwrap();
+        let tree = acl
+            .expand(&"doc:design".parse().unwrap(), "viewer", Consistency::default())
+            .await
+            .unwrap();
+        let userset = |userset: &str| userset.parse::<Userset>().unwrap();
+        // Owners are editors are owners, but editors are viewers and
+        // commenters both without that being a cycle.
+        let editor = UsersetTree::Union {
+            userset: userset("doc:design#editor"),
+            children: vec![
+                UsersetTree::Leaf {
+                    userset: userset("doc:design#editor"),
+                    users: vec!["user1".parse().unwrap()],
+                },
+                UsersetTree::Union {
+                    userset: userset("doc:design#owner"),
+                    children: vec![
+                        UsersetTree::Leaf {
+                            userset: userset("doc:design#owner"),
+                            users: vec![],
+                        },
+                        UsersetTree::Cycle {
+                            userset: userset("doc:design#editor"),
+                        },
+                    ],
+                },
+            ],
+        };
+        assert_eq!(
+            tree,
+            UsersetTree::Union {
+                userset: userset("doc:design#viewer"),
+                children: vec![editor.clone(), editor],
+            }
+        );
+    }
+
+    #[okstd::test]
+    async fn test_lookups_are_paginated() {
+        let acl = acl(&[
+            "repo:a#write@team:eng#member",
+            "repo:b#admin@user1",
+            "repo:c#read@user1",
+            "repo:d#write@user2",
+            "team:eng#member@user1",
+            "team:eng#member@user3",
+        ])
+        .await;
+        let user1 = "user1".parse().unwrap();
+        let first = acl
+            .lookup_resources("repo", "write", &user1, &Page::new(1), Consistency::default())
+            .await
+            .unwrap();
+        assert_eq!(first.items, vec!["repo:a".parse().unwrap()]);
+        let token = first.next_page_token.unwrap();
+        let second = acl
+            .lookup_resources(
+                "repo",
+                "write",
+                &user1,
+                &Page::new(1).after(token),
+                Consistency::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(second.items, vec!["repo:b".parse().unwrap()]);
+        assert_eq!(second.next_page_token, None);
+        for forged in ["3", "team:eng"] {
+            let forged = Page::new(1).after(forged);
+            assert!(matches!(
+                acl.lookup_resources("repo", "write", &user1, &forged, Consistency::default())
+                    .await,
+                Err(Error::InvalidPageToken(_))
+            ));
+        }
+
+        let repo_a = "repo:a".parse().unwrap();
+        let first = acl
+            .lookup_subjects(&repo_a, "read", &Page::new(2), Consistency::default())
+            .await
+            .unwrap();
+        let team = "team:eng#member".parse().unwrap();
+        assert_eq!(first.items, vec![team, "user1".parse().unwrap()]);
+        let token = first.next_page_token.unwrap();
+        let second = acl
+            .lookup_subjects(
+                &repo_a,
+                "read",
+                &Page::new(10).after(token),
+                Consistency::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(second.items, vec!["user3".parse().unwrap()]);
+        assert_eq!(second.next_page_token, None);
+        let forged = Page::new(1).after("user1");
+        assert!(matches!(
+            acl.lookup_subjects(&repo_a, "read", &forged, Consistency::default()).await,
+            Err(Error::InvalidPageToken(_))
+        ));
+    }
+
+    #[okstd::test]
+    async fn test_deleted_tuples_are_forgotten() {
+        let acl = acl(&["doc:design#editor@user123"]).await;
+        assert!(check(&acl, "doc:design", "editor", "user123").await);
+        acl.store()
+            .delete(&["doc:design#editor@user123".parse().unwrap()])
+            .await
+            .unwrap();
+        assert!(!check(&acl, "doc:design", "editor", "user123").await);
+    }
+
+    #[okstd::test]
+    async fn test_zookies_pick_snapshots() {
+        let key = ZookieKey::random();
+        let acl = Zanzibar::new(MemoryStore::with_key(key.clone()));
+        let store = acl.store();
+        let tuple: RelationTuple = "doc:design#viewer@bob".parse().unwrap();
+        let added = store.write(std::slice::from_ref(&tuple)).await.unwrap();
+        let removed = store.delete(std::slice::from_ref(&tuple)).await.unwrap();
+        assert!(removed > added);
+        assert_eq!(removed.to_string().parse::<Zookie>(), Ok(removed));
+
+        // Older snapshots still have the tuple, until they are compacted.
+        let doc = "doc:design".parse().unwrap();
+        assert_eq!(store.read(&doc, "viewer", added).await.unwrap(), vec![tuple.user]);
+        assert!(store.read(&doc, "viewer", removed).await.unwrap().is_empty());
+        store.compact(removed);
+        assert!(matches!(
+            store.read(&doc, "viewer", added).await,
+            Err(crate::Error::SnapshotUnavailable(zookie)) if zookie == added
+        ));
+        assert!(store.read(&doc, "viewer", removed).await.unwrap().is_empty());
+
+        // Bob can't see content protected after he was removed.
+        let fresh = acl.at_least_as_fresh(removed);
+        assert!(!fresh.check(User::Id("bob".to_string()), "viewer", doc.clone()).await);
+        let future = key.zookie(removed.revision() + 1);
+        assert!(matches!(
+            acl.check_user(
+                &doc,
+                "viewer",
+                &"bob".parse().unwrap(),
+                Consistency::AtLeastAsFresh(future),
+            )
+            .await,
+            Err(crate::Error::SnapshotUnavailable(zookie)) if zookie == future
+        ));
+
+        // Nor by forging a zookie, or bringing one from another store.
+        let tag = removed.to_string().split_once('.').unwrap().1.to_string();
+        let forged: Zookie = format!("zk{:x}.{}", removed.revision() + 1, tag).parse().unwrap();
+        let other = MemoryStore::new().write(&[]).await.unwrap();
+        for zookie in [forged, other] {
+            let fresh = Consistency::AtLeastAsFresh(zookie);
+            assert!(matches!(
+                acl.check_user(&doc, "viewer", &"bob".parse().unwrap(), fresh).await,
+                Err(crate::Error::InvalidZookie(_))
+            ));
+        }
+    }
+}
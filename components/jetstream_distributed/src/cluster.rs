@@ -1,15 +1,60 @@
 //! Cluster provides memebership primitives for JetStream.
-use {jetstream_rpc::Protocol, mac_address::MacAddressIterator};
+use {
+    futures::Stream,
+    jetstream_rpc::Protocol,
+    jetstream_wireformat::JetStreamWireFormat,
+    mac_address::MacAddressIterator,
+    std::collections::BTreeMap,
+};
 
 use super::{coordinate::Coordinate, Result};
 
+pub mod node;
+pub mod swim;
+
 /// Cluster trait
 #[trait_variant::make(Send+Sync)]
 pub trait Cluster {
-    /// Join a cluster
-    fn join(&self, node: impl IntoNode) -> Result<()>;
-    /// Leave a cluster
-    fn leave(&self, node: impl IntoNode) -> Result<()>;
+    /// How members are reached, like a socket address.
+    type Address: Send + Sync;
+    /// Join a cluster, through the members at `seeds`. Returns how many of
+    /// them answered, failing with [`Error::JoinFailed`](crate::Error::JoinFailed)
+    /// if none did.
+    async fn join(&self, seeds: &[Self::Address]) -> Result<usize>;
+    /// Leave a cluster, telling the other members.
+    async fn leave(&self) -> Result<()>;
+    /// Returns the membership changes from now on, to rebalance placement
+    /// or drain connections as members come and go.
+    fn subscribe(&self) -> impl Stream<Item = MemberEvent> + Send + 'static;
+}
+
+/// A member of a cluster, as far as the local member knows.
+#[derive(Debug, Clone, PartialEq, Eq, JetStreamWireFormat)]
+pub struct Member {
+    /// The member's name, unique in the cluster.
+    pub name: String,
+    /// Where the member is reached.
+    pub addr: String,
+    /// Incremented by the member whenever it changes how it is known, like
+    /// its tags, or to refute suspicions about it.
+    pub incarnation: u64,
+    /// Whether the member is alive.
+    pub health: Health,
+    /// Metadata the member advertises, like its zone or role.
+    pub tags: BTreeMap<String, String>,
+}
+
+/// A change in the membership.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemberEvent {
+    /// The member joined, or came back after failing or leaving.
+    Join(Member),
+    /// The member left.
+    Leave(Member),
+    /// The member failed.
+    Fail(Member),
+    /// The member changed its address or tags.
+    Update(Member),
 }
 
 /// A Node trait
@@ -19,8 +64,27 @@ pub trait Node {
     fn id(&self) -> NodeId;
     /// Coordinate
     fn coordinate(&self) -> Result<Coordinate>;
-    /// dial
-    async fn dial<P: Protocol>(&self) -> Result<impl Protocol>;
+    /// Health, as far as the local node knows.
+    fn health(&self) -> Health {
+        Health::Alive
+    }
+    /// Connects to the node, returning a transport for the calls of `P`,
+    /// like the `inner` of a generated channel.
+    async fn dial<P: Protocol>(&self) -> Result<node::Connection<P>>;
+}
+
+/// How a node is doing, as far as the local node knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, JetStreamWireFormat)]
+pub enum Health {
+    /// The node answers.
+    #[default]
+    Alive,
+    /// The node stopped answering, and may have failed.
+    Suspect,
+    /// The node failed.
+    Dead,
+    /// The node left.
+    Left,
 }
 
 /// IntoNode trait
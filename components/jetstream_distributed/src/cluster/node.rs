@@ -0,0 +1,461 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Nodes reached over the network.
+//!
+//! A [`RemoteNode`] has the addresses it advertises, tried in order until
+//! one connects. Connections are pooled per node and protocol: a
+//! [`Connection`] that is dropped after a call completes goes back to the
+//! pool, and the next [`dial`](Node::dial) for the same protocol reuses it.
+//!
+//! ```ignore
+//! let node = RemoteNode::new(id, ["quic://10.0.0.2:4433".parse()?, "tcp://10.0.0.2:4434".parse()?])
+//!     .with_quic(client);
+//! let mut transport = node.dial::<EchoChannel>().await?;
+//! let mut chan = EchoChannel { inner: Box::new(&mut transport) };
+//! chan.ping().await?;
+//! ```
+
+use {
+    super::{Health, IntoNode, Node, NodeId},
+    crate::{
+        coordinate::{ping::Coordinates, Coordinate},
+        Error,
+        Result,
+    },
+    futures::{Sink, Stream},
+    jetstream_client::ClientCodec,
+    jetstream_rpc::{Frame, Protocol},
+    std::{
+        collections::HashMap,
+        fmt,
+        io,
+        path::PathBuf,
+        pin::Pin,
+        str::FromStr,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    },
+    tokio::io::{AsyncRead, AsyncWrite},
+    tokio_util::codec::Framed,
+};
+
+/// How many idle connections are kept per protocol.
+const MAX_IDLE: usize = 8;
+
+/// Where a node is reached, written as a URL, like `tcp://10.0.0.2:4434`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    /// `quic://host:port`, dialed with the client given to
+    /// [`RemoteNode::with_quic`].
+    Quic(String),
+    /// `tcp://host:port`.
+    Tcp(String),
+    /// `vsock://cid:port`, for virtual machines and their host.
+    Vsock {
+        /// The context ID of the virtual machine, or the host.
+        cid: u32,
+        /// The port.
+        port: u32,
+    },
+    /// `unix:///path`, for nodes on the same machine.
+    Unix(PathBuf),
+}
+
+/// Why an address couldn't be parsed.
+#[derive(Debug, thiserror::Error)]
+pub enum AddressError {
+    /// The scheme isn't one of quic, tcp, vsock or unix.
+    #[error("unknown scheme in {0}")]
+    UnknownScheme(String),
+    /// The address doesn't fit its scheme.
+    #[error("invalid address {0}")]
+    Invalid(String),
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let invalid = || AddressError::Invalid(s.to_string());
+        let (scheme, rest) = s.split_once("://").ok_or_else(invalid)?;
+        match scheme {
+            "quic" | "tcp" => {
+                let (host, port) = rest.rsplit_once(':').ok_or_else(invalid)?;
+                if host.is_empty() || port.parse::<u16>().is_err() {
+                    return Err(invalid());
+                }
+                match scheme {
+                    "quic" => Ok(Address::Quic(rest.to_string())),
+                    _ => Ok(Address::Tcp(rest.to_string())),
+                }
+            }
+            "vsock" => {
+                let (cid, port) = rest.split_once(':').ok_or_else(invalid)?;
+                Ok(Address::Vsock {
+                    cid: cid.parse().map_err(|_| invalid())?,
+                    port: port.parse().map_err(|_| invalid())?,
+                })
+            }
+            "unix" if !rest.is_empty() => Ok(Address::Unix(PathBuf::from(rest))),
+            "unix" => Err(invalid()),
+            _ => Err(AddressError::UnknownScheme(s.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Quic(addr) => write!(f, "quic://{}", addr),
+            Address::Tcp(addr) => write!(f, "tcp://{}", addr),
+            Address::Vsock { cid, port } => write!(f, "vsock://{}:{}", cid, port),
+            Address::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+/// A byte stream to a node, whatever the transport.
+trait Io: AsyncRead + AsyncWrite + Send + Sync + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Sync + Unpin> Io for T {}
+
+/// The idle connections to a node, by protocol version.
+#[derive(Default)]
+struct Pool {
+    idle: Mutex<HashMap<&'static str, Vec<Box<dyn Io>>>>,
+    /// The QUIC connection streams are opened on.
+    #[cfg(feature = "quic")]
+    quic: tokio::sync::Mutex<Option<s2n_quic::Connection>>,
+}
+
+impl Pool {
+    fn checkout(&self, version: &'static str) -> Option<Box<dyn Io>> {
+        self.idle.lock().unwrap().get_mut(version)?.pop()
+    }
+
+    fn checkin(&self, version: &'static str, io: Box<dyn Io>) {
+        let mut idle = self.idle.lock().unwrap();
+        let idle = idle.entry(version).or_default();
+        if idle.len() < MAX_IDLE {
+            idle.push(io);
+        }
+    }
+}
+
+/// A node reached at the addresses it advertises. Clones share the
+/// connection pool.
+#[derive(Clone)]
+pub struct RemoteNode {
+    id: NodeId,
+    addresses: Vec<Address>,
+    coordinate: Option<Coordinate>,
+    /// Where pings keep the node's coordinate, and the node's name there.
+    sampled: Option<(Coordinates, String)>,
+    health: Health,
+    #[cfg(feature = "quic")]
+    quic: Option<s2n_quic::Client>,
+    pool: Arc<Pool>,
+}
+
+impl fmt::Debug for RemoteNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RemoteNode")
+            .field("id", &self.id)
+            .field("addresses", &self.addresses)
+            .field("coordinate", &self.coordinate)
+            .field("health", &self.health)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RemoteNode {
+    /// Returns the node `id`, reached at `addresses`, most preferred first.
+    pub fn new(id: NodeId, addresses: impl IntoIterator<Item = Address>) -> Self {
+        Self {
+            id,
+            addresses: addresses.into_iter().collect(),
+            coordinate: None,
+            sampled: None,
+            health: Health::Alive,
+            #[cfg(feature = "quic")]
+            quic: None,
+            pool: Arc::new(Pool::default()),
+        }
+    }
+
+    /// Sets the node's coordinate, as gossiped or sampled by pings.
+    pub fn with_coordinate(mut self, coordinate: Coordinate) -> Self {
+        self.coordinate = Some(coordinate);
+        self
+    }
+
+    /// Reads the node's coordinate from `coordinates`, which a
+    /// [`Pinger`](crate::coordinate::ping::Pinger) keeps up to date under the
+    /// node's member `name`. Until the node is pinged, the coordinate set
+    /// with [`with_coordinate`](Self::with_coordinate) is used.
+    pub fn with_coordinates(mut self, coordinates: Coordinates, name: impl Into<String>) -> Self {
+        self.sampled = Some((coordinates, name.into()));
+        self
+    }
+
+    /// Sets the node's health, as far as the local node knows.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Dials QUIC addresses with `client`. Without one, they are skipped.
+    #[cfg(feature = "quic")]
+    pub fn with_quic(mut self, client: s2n_quic::Client) -> Self {
+        self.quic = Some(client);
+        self
+    }
+
+    /// Returns the addresses the node is reached at.
+    pub fn addresses(&self) -> &[Address] {
+        &self.addresses
+    }
+
+    /// Opens a stream to the first address that connects.
+    async fn open(&self) -> Result<Box<dyn Io>> {
+        let mut last = io::Error::new(io::ErrorKind::AddrNotAvailable, "no addresses");
+        for address in &self.addresses {
+            match self.open_address(address).await {
+                Ok(io) => return Ok(io),
+                Err(err) => {
+                    tracing::debug!(%address, %err, "dial failed");
+                    last = err;
+                }
+            }
+        }
+        Err(Error::Dial(last))
+    }
+
+    async fn open_address(&self, address: &Address) -> io::Result<Box<dyn Io>> {
+        match address {
+            Address::Quic(addr) => self.open_quic(addr).await,
+            Address::Tcp(addr) => {
+                let stream = tokio::net::TcpStream::connect(addr.as_str()).await?;
+                stream.set_nodelay(true)?;
+                Ok(Box::new(stream))
+            }
+            #[cfg(feature = "vsock")]
+            Address::Vsock { cid, port } => {
+                let addr = tokio_vsock::VsockAddr::new(*cid, *port);
+                Ok(Box::new(tokio_vsock::VsockStream::connect(addr).await?))
+            }
+            #[cfg(not(feature = "vsock"))]
+            Address::Vsock { .. } => Err(unsupported("vsock")),
+            Address::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        }
+    }
+
+    /// Opens a stream on the node's QUIC connection, connecting first if
+    /// there is none or it was closed.
+    #[cfg(feature = "quic")]
+    async fn open_quic(&self, addr: &str) -> io::Result<Box<dyn Io>> {
+        let Some(client) = &self.quic else {
+            return Err(unsupported("QUIC without a client"));
+        };
+        let mut connection = self.pool.quic.lock().await;
+        if let Some(conn) = connection.as_mut() {
+            match conn.open_bidirectional_stream().await {
+                Ok(stream) => return Ok(Box::new(stream)),
+                Err(_) => *connection = None,
+            }
+        }
+
+        let server_name = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let server_name = server_name.trim_start_matches('[').trim_end_matches(']');
+        let socket_addr = tokio::net::lookup_host(addr).await?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "no address for host")
+        })?;
+        let connect = s2n_quic::client::Connect::new(socket_addr).with_server_name(server_name);
+        let mut conn = client.connect(connect).await.map_err(io::Error::other)?;
+        conn.keep_alive(true).map_err(io::Error::other)?;
+        let stream = conn.open_bidirectional_stream().await.map_err(io::Error::other)?;
+        *connection = Some(conn);
+        Ok(Box::new(stream))
+    }
+
+    #[cfg(not(feature = "quic"))]
+    async fn open_quic(&self, _addr: &str) -> io::Result<Box<dyn Io>> {
+        Err(unsupported("QUIC"))
+    }
+}
+
+fn unsupported(transport: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{} isn't supported", transport),
+    )
+}
+
+impl Node for RemoteNode {
+    fn id(&self) -> NodeId {
+        self.id.clone()
+    }
+
+    fn coordinate(&self) -> Result<Coordinate> {
+        self.sampled
+            .as_ref()
+            .and_then(|(coordinates, name)| coordinates.peer(name))
+            .or_else(|| self.coordinate.clone())
+            .ok_or(Error::NoCoordinate)
+    }
+
+    fn health(&self) -> Health {
+        self.health
+    }
+
+    async fn dial<P: Protocol>(&self) -> Result<Connection<P>> {
+        let io = match self.pool.checkout(P::VERSION) {
+            Some(io) => io,
+            None => self.open().await?,
+        };
+        Ok(Connection {
+            framed: Some(Framed::new(io, ClientCodec::default())),
+            pool: Some(self.pool.clone()),
+            healthy: true,
+            outstanding: 0,
+        })
+    }
+}
+
+impl IntoNode for RemoteNode {
+    fn into_node(self) -> impl Node {
+        self
+    }
+}
+
+/// A connection to a node, for the calls of `P`. One dialed by a
+/// [`RemoteNode`] goes back to its pool when dropped, unless it failed or a
+/// call on it was cut short.
+pub struct Connection<P: Protocol> {
+    /// Only taken when dropped.
+    framed: Option<Framed<Box<dyn Io>, ClientCodec<P>>>,
+    pool: Option<Arc<Pool>>,
+    healthy: bool,
+    /// Requests sent that weren't answered yet. Their responses would be
+    /// read by the next user of the connection.
+    outstanding: usize,
+}
+
+impl<P: Protocol> Connection<P> {
+    /// Returns a connection over `stream`, which isn't pooled, for nodes
+    /// dialed some other way.
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + Sync + Unpin + 'static,
+    {
+        Self {
+            framed: Some(Framed::new(Box::new(stream), ClientCodec::default())),
+            pool: None,
+            healthy: true,
+            outstanding: 0,
+        }
+    }
+
+    fn framed(&mut self) -> Pin<&mut Framed<Box<dyn Io>, ClientCodec<P>>> {
+        Pin::new(self.framed.as_mut().unwrap())
+    }
+
+    /// Marks the connection failed if `poll` is an error.
+    fn check<T>(&mut self, poll: Poll<io::Result<T>>) -> Poll<io::Result<T>> {
+        if let Poll::Ready(Err(_)) = &poll {
+            self.healthy = false;
+        }
+        poll
+    }
+}
+
+impl<P: Protocol> Sink<Frame<P::Request>> for Connection<P> {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = self.framed().poll_ready(cx);
+        self.check(poll)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Frame<P::Request>) -> io::Result<()> {
+        let res = self.framed().start_send(item);
+        match res {
+            Ok(()) => self.outstanding += 1,
+            Err(_) => self.healthy = false,
+        }
+        res
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let poll = self.framed().poll_flush(cx);
+        self.check(poll)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.healthy = false;
+        self.framed().poll_close(cx)
+    }
+}
+
+impl<P: Protocol> Stream for Connection<P> {
+    type Item = io::Result<Frame<P::Response>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = self.framed().poll_next(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(_))) => self.outstanding = self.outstanding.saturating_sub(1),
+            Poll::Ready(None | Some(Err(_))) => self.healthy = false,
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+impl<P: Protocol> Drop for Connection<P> {
+    fn drop(&mut self) {
+        let (Some(framed), Some(pool)) = (self.framed.take(), &self.pool) else {
+            return;
+        };
+        if !self.healthy || self.outstanding > 0 {
+            return;
+        }
+        // Leftovers mean a call was cut short, and its response would be
+        // read by the next one.
+        let parts = framed.into_parts();
+        if parts.read_buf.is_empty() && parts.write_buf.is_empty() {
+            pool.checkin(P::VERSION, parts.io);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_addresses_round_trip() {
+        for addr in [
+            "quic://node.local:4433",
+            "tcp://10.0.0.2:4434",
+            "tcp://[::1]:4434",
+            "vsock://3:1024",
+            "unix:///run/jetstream.sock",
+        ] {
+            let address: Address = addr.parse().unwrap();
+            assert_eq!(address.to_string(), addr);
+        }
+        assert_eq!(
+            "vsock://3:1024".parse::<Address>().unwrap(),
+            Address::Vsock { cid: 3, port: 1024 }
+        );
+        for addr in ["10.0.0.2:4434", "tcp://10.0.0.2", "vsock://host:1", "unix://"] {
+            assert!(matches!(addr.parse::<Address>(), Err(AddressError::Invalid(_))));
+        }
+        assert!(matches!(
+            "http://10.0.0.2:80".parse::<Address>(),
+            Err(AddressError::UnknownScheme(_))
+        ));
+    }
+}
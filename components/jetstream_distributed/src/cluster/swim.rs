@@ -0,0 +1,736 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! SWIM membership, over jetstream RPC.
+//!
+//! Every probe interval a member pings another, picked round robin. A member
+//! that doesn't answer is pinged indirectly, through a few others, in case
+//! only the link between the two is down. If none of them reach it either,
+//! it becomes [`Health::Suspect`], and [`Health::Dead`] once the suspicion
+//! times out, unless it refutes the suspicion first.
+//!
+//! Changes are gossiped by piggybacking them on pings and their responses.
+//! Every member has an incarnation number, which only it increments, to
+//! refute suspicions about it or to change its tags. Updates with a higher
+//! incarnation override older ones.
+//!
+//! ```ignore
+//! let swim = Swim::new("node-a", "10.0.0.1:7946", Config::default(), |addr: String| async move {
+//!     Ok(Framed::new(TcpStream::connect(addr).await?, ClientCodec::default()))
+//! });
+//! // For every connection:
+//! let mut serv = gossip_protocol::GossipService { inner: swim.server() };
+//! run(&mut serv, Framed::new(stream, ServerCodec::default())).await?;
+//!
+//! swim.join(&["10.0.0.2:7946".to_string()]).await?;
+//! tokio::spawn({ let swim = swim.clone(); async move { swim.run().await } });
+//! ```
+
+use {
+    super::{Cluster, Health, Member, MemberEvent},
+    futures::Stream,
+    gossip_protocol::GossipChannel,
+    jetstream_macros::service,
+    jetstream_rpc::{ClientTransport, Deadline, Error},
+    rand::seq::SliceRandom,
+    std::{
+        collections::{BTreeMap, HashMap},
+        future::Future,
+        io,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::{
+        sync::broadcast::{self, error::RecvError},
+        task::JoinSet,
+        time::{Instant, MissedTickBehavior},
+    },
+};
+
+/// The calls members make to each other.
+#[service(crate = jetstream_rpc)]
+pub trait Gossip {
+    /// Checks the callee is alive, exchanging updates.
+    async fn ping(
+        &mut self,
+        updates: Vec<Member>,
+    ) -> Result<Vec<Member>, Error>;
+    /// Asks the callee to ping `target`, for a caller that couldn't reach
+    /// it. Returns true if it answered.
+    async fn ping_req(&mut self, target: Member) -> Result<bool, Error>;
+    /// Adds `member` to the callee's cluster, and returns every member the
+    /// callee knows of.
+    async fn join(
+        &mut self,
+        member: Member,
+    ) -> Result<Vec<Member>, Error>;
+}
+
+/// Dials the transports members are called over.
+///
+/// This is implemented for closures taking an address and returning a future
+/// that resolves to a transport.
+pub trait Dial: Send + Sync + 'static {
+    /// The transport dialed.
+    type Transport: for<'a> ClientTransport<GossipChannel<'a>> + 'static;
+    /// The future returned by [`Dial::dial`].
+    type Future: Future<Output = io::Result<Self::Transport>> + Send + 'static;
+    /// Dials the member at `addr`.
+    fn dial(&self, addr: &str) -> Self::Future;
+}
+
+impl<F, Fut, T> Dial for F
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: for<'a> ClientTransport<GossipChannel<'a>> + 'static,
+{
+    type Future = Fut;
+    type Transport = T;
+
+    fn dial(&self, addr: &str) -> Self::Future {
+        self(addr.to_string())
+    }
+}
+
+/// Timing and fan out of the protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// How often a member is probed.
+    pub probe_interval: Duration,
+    /// How long a ping may take before the member is pinged indirectly.
+    pub probe_timeout: Duration,
+    /// How many members ping a member that didn't answer directly.
+    pub indirect_checks: usize,
+    /// How long a member is suspected before it is declared dead.
+    pub suspicion_timeout: Duration,
+    /// Updates are gossiped `retransmit_mult * ceil(log10(n + 1))` times, in
+    /// a cluster of `n` members.
+    pub retransmit_mult: usize,
+    /// The most updates piggybacked on a single message.
+    pub max_updates: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            probe_interval: Duration::from_secs(1),
+            probe_timeout: Duration::from_millis(500),
+            indirect_checks: 3,
+            suspicion_timeout: Duration::from_secs(5),
+            retransmit_mult: 4,
+            max_updates: 32,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Known {
+    member: Member,
+    /// When the member's health last changed.
+    changed: Instant,
+}
+
+#[derive(Debug)]
+struct Broadcast {
+    member: Member,
+    transmits: usize,
+}
+
+#[derive(Debug)]
+struct State {
+    local: Member,
+    members: BTreeMap<String, Known>,
+    broadcasts: Vec<Broadcast>,
+    /// The members left to probe this round.
+    probes: Vec<String>,
+}
+
+impl State {
+    /// Queues `member` to be gossiped, replacing older updates about it.
+    fn broadcast(&mut self, member: Member) {
+        self.broadcasts
+            .retain(|broadcast| broadcast.member.name != member.name);
+        self.broadcasts.push(Broadcast {
+            member,
+            transmits: 0,
+        });
+    }
+
+    /// Takes the updates to piggyback on a message, those sent the fewest
+    /// times first.
+    fn take_broadcasts(&mut self, config: &Config) -> Vec<Member> {
+        let members = (self.members.len() + 2) as f64;
+        let limit = config.retransmit_mult * (members.log10().ceil() as usize).max(1);
+        self.broadcasts.sort_by_key(|broadcast| broadcast.transmits);
+        let updates = self
+            .broadcasts
+            .iter_mut()
+            .take(config.max_updates)
+            .map(|broadcast| {
+                broadcast.transmits += 1;
+                broadcast.member.clone()
+            })
+            .collect();
+        self.broadcasts
+            .retain(|broadcast| broadcast.transmits < limit);
+        updates
+    }
+
+    /// Applies an update, gossiping it on if it was news.
+    fn apply(&mut self, update: Member) -> Option<MemberEvent> {
+        if update.name == self.local.name {
+            // Others think we're not alive, tell them otherwise.
+            if update.health != Health::Alive
+                && self.local.health == Health::Alive
+                && update.incarnation >= self.local.incarnation
+            {
+                self.local.incarnation = update.incarnation + 1;
+                self.broadcast(self.local.clone());
+            }
+            return None;
+        }
+
+        let now = Instant::now();
+        let Some(known) = self.members.get_mut(&update.name) else {
+            // Members that are gone before we heard of them don't matter.
+            if update.health != Health::Alive {
+                return None;
+            }
+            self.members.insert(
+                update.name.clone(),
+                Known {
+                    member: update.clone(),
+                    changed: now,
+                },
+            );
+            self.broadcast(update.clone());
+            return Some(MemberEvent::Join(update));
+        };
+
+        let current = &known.member;
+        let accept = match (current.health, update.health) {
+            (_, Health::Alive) => update.incarnation > current.incarnation,
+            (Health::Alive, Health::Suspect) => update.incarnation >= current.incarnation,
+            (Health::Suspect, Health::Suspect) => update.incarnation > current.incarnation,
+            (Health::Alive | Health::Suspect, Health::Dead | Health::Left) => {
+                update.incarnation >= current.incarnation
+            }
+            _ => false,
+        };
+        if !accept {
+            return None;
+        }
+
+        let event: Option<fn(Member) -> MemberEvent> = match (current.health, update.health) {
+            (Health::Dead | Health::Left, Health::Alive) => Some(MemberEvent::Join),
+            (Health::Alive | Health::Suspect, Health::Dead) => Some(MemberEvent::Fail),
+            (Health::Alive | Health::Suspect, Health::Left) => Some(MemberEvent::Leave),
+            (Health::Alive | Health::Suspect, Health::Alive)
+                if update.addr != current.addr || update.tags != current.tags =>
+            {
+                Some(MemberEvent::Update)
+            }
+            _ => None,
+        };
+        if current.health != update.health {
+            known.changed = now;
+        }
+        known.member = update.clone();
+        self.broadcast(update.clone());
+        event.map(|event| event(update))
+    }
+
+    /// Declares suspects whose suspicion timed out dead.
+    fn expire_suspects(&mut self, timeout: Duration) -> Vec<MemberEvent> {
+        let expired: Vec<Member> = self
+            .members
+            .values()
+            .filter(|known| {
+                known.member.health == Health::Suspect && known.changed.elapsed() >= timeout
+            })
+            .map(|known| Member {
+                health: Health::Dead,
+                ..known.member.clone()
+            })
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|member| self.apply(member))
+            .collect()
+    }
+
+    /// Returns the next member to probe. Every alive or suspect member is
+    /// probed once a round, in random order.
+    fn next_probe(&mut self) -> Option<Member> {
+        loop {
+            if self.probes.is_empty() {
+                self.probes = self.reachable().map(|member| member.name.clone()).collect();
+                self.probes.shuffle(&mut rand::thread_rng());
+            }
+            let name = self.probes.pop()?;
+            if let Some(known) = self.members.get(&name) {
+                if matches!(known.member.health, Health::Alive | Health::Suspect) {
+                    return Some(known.member.clone());
+                }
+            }
+        }
+    }
+
+    fn reachable(&self) -> impl Iterator<Item = &Member> {
+        self.members
+            .values()
+            .map(|known| &known.member)
+            .filter(|member| matches!(member.health, Health::Alive | Health::Suspect))
+    }
+}
+
+struct Inner<D: Dial> {
+    config: Config,
+    dial: D,
+    state: Mutex<State>,
+    /// Idle transports, by address.
+    pool: Mutex<HashMap<String, Vec<D::Transport>>>,
+    events: broadcast::Sender<MemberEvent>,
+}
+
+/// A member of a cluster, using SWIM to keep track of the others. Clones
+/// share the membership.
+pub struct Swim<D: Dial> {
+    inner: Arc<Inner<D>>,
+}
+
+impl<D: Dial> Clone for Swim<D> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<D: Dial> Swim<D> {
+    /// Returns the member `name`, reached at `addr`, which calls other
+    /// members over transports from `dial`. It is alone until it
+    /// [joins](Cluster::join) a cluster.
+    pub fn new(name: impl Into<String>, addr: impl Into<String>, config: Config, dial: D) -> Self {
+        let local = Member {
+            name: name.into(),
+            addr: addr.into(),
+            incarnation: 0,
+            health: Health::Alive,
+            tags: BTreeMap::new(),
+        };
+        Self {
+            inner: Arc::new(Inner {
+                config,
+                dial,
+                state: Mutex::new(State {
+                    local,
+                    members: BTreeMap::new(),
+                    broadcasts: Vec::new(),
+                    probes: Vec::new(),
+                }),
+                pool: Mutex::new(HashMap::new()),
+                events: broadcast::channel(128).0,
+            }),
+        }
+    }
+
+    /// Returns the local member.
+    pub fn local(&self) -> Member {
+        self.inner.state.lock().unwrap().local.clone()
+    }
+
+    /// Returns every member, including the local one and those that failed
+    /// or left, ordered by name.
+    pub fn members(&self) -> Vec<Member> {
+        let state = self.inner.state.lock().unwrap();
+        let mut members: Vec<Member> = state
+            .members
+            .values()
+            .map(|known| known.member.clone())
+            .chain([state.local.clone()])
+            .collect();
+        members.sort_by(|a, b| a.name.cmp(&b.name));
+        members
+    }
+
+    /// Replaces the local member's tags, and gossips them to the others.
+    pub fn set_tags(&self, tags: BTreeMap<String, String>) {
+        let mut state = self.inner.state.lock().unwrap();
+        state.local.tags = tags;
+        state.local.incarnation += 1;
+        let local = state.local.clone();
+        state.broadcast(local);
+    }
+
+    /// Returns the handler for the calls of other members.
+    pub fn server(&self) -> GossipServer<D> {
+        GossipServer { swim: self.clone() }
+    }
+
+    /// Probes a member every probe interval, until the local member leaves.
+    pub async fn run(&self) {
+        let mut ticks = tokio::time::interval(self.inner.config.probe_interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if self.local().health == Health::Left {
+                return;
+            }
+            let events = {
+                let mut state = self.inner.state.lock().unwrap();
+                state.expire_suspects(self.inner.config.suspicion_timeout)
+            };
+            self.notify(events);
+            self.probe().await;
+        }
+    }
+
+    /// Pings the next member, and suspects it if neither it nor the members
+    /// asked to ping it indirectly get an answer.
+    async fn probe(&self) {
+        let config = &self.inner.config;
+        let Some(target) = self.inner.state.lock().unwrap().next_probe() else {
+            return;
+        };
+        if self.ping(&target, config.probe_timeout).await {
+            return;
+        }
+
+        let helpers: Vec<Member> = {
+            let state = self.inner.state.lock().unwrap();
+            let candidates: Vec<&Member> = state
+                .reachable()
+                .filter(|member| member.health == Health::Alive && member.name != target.name)
+                .collect();
+            candidates
+                .choose_multiple(&mut rand::thread_rng(), config.indirect_checks)
+                .map(|member| (*member).clone())
+                .collect()
+        };
+        let mut checks = JoinSet::new();
+        for helper in helpers {
+            let swim = self.clone();
+            let target = target.clone();
+            let timeout = config.probe_interval;
+            checks.spawn(async move { swim.ping_req(&helper, target, timeout).await });
+        }
+        while let Some(acked) = checks.join_next().await {
+            if acked.unwrap_or(false) {
+                return;
+            }
+        }
+
+        tracing::debug!(member = target.name, "suspecting unreachable member");
+        self.apply([Member {
+            health: Health::Suspect,
+            ..target
+        }]);
+    }
+
+    /// Pings `member`, piggybacking the pending updates. Returns true if it
+    /// answered within `timeout`.
+    async fn ping(&self, member: &Member, timeout: Duration) -> bool {
+        let updates = self
+            .inner
+            .state
+            .lock()
+            .unwrap()
+            .take_broadcasts(&self.inner.config);
+        self.send_ping(member, updates, timeout).await
+    }
+
+    async fn send_ping(&self, member: &Member, updates: Vec<Member>, timeout: Duration) -> bool {
+        let res = Deadline::after(timeout)
+            .timeout(async {
+                let mut transport = self.checkout(&member.addr).await?;
+                let mut chan = GossipChannel {
+                    inner: Box::new(&mut transport),
+                };
+                let updates = chan.ping(updates).await?;
+                self.checkin(&member.addr, transport);
+                Ok::<_, Error>(updates)
+            })
+            .await;
+        match res {
+            Ok(Ok(updates)) => {
+                self.apply(updates);
+                true
+            }
+            Ok(Err(err)) | Err(err) => {
+                tracing::debug!(member = member.name, %err, "ping failed");
+                false
+            }
+        }
+    }
+
+    /// Asks `helper` to ping `target`. Returns true if it answered.
+    async fn ping_req(&self, helper: &Member, target: Member, timeout: Duration) -> bool {
+        let res = Deadline::after(timeout)
+            .timeout(async {
+                let mut transport = self.checkout(&helper.addr).await?;
+                let mut chan = GossipChannel {
+                    inner: Box::new(&mut transport),
+                };
+                let acked = chan.ping_req(target).await?;
+                self.checkin(&helper.addr, transport);
+                Ok::<_, Error>(acked)
+            })
+            .await;
+        matches!(res, Ok(Ok(true)))
+    }
+
+    /// Takes an idle transport to `addr`, or dials one.
+    async fn checkout(&self, addr: &str) -> Result<D::Transport, Error> {
+        let idle = self
+            .inner
+            .pool
+            .lock()
+            .unwrap()
+            .get_mut(addr)
+            .and_then(Vec::pop);
+        match idle {
+            Some(transport) => Ok(transport),
+            // Dialed on a task of its own, so the dial future needn't be Sync.
+            None => tokio::spawn(self.inner.dial.dial(addr))
+                .await
+                .map_err(|err| Error::Custom(err.to_string()))?
+                .map_err(Error::from),
+        }
+    }
+
+    /// Returns a transport that worked to the pool. Transports that failed
+    /// are dropped instead, and dialed again next time.
+    fn checkin(&self, addr: &str, transport: D::Transport) {
+        self.inner
+            .pool
+            .lock()
+            .unwrap()
+            .entry(addr.to_string())
+            .or_default()
+            .push(transport);
+    }
+
+    fn apply(&self, updates: impl IntoIterator<Item = Member>) {
+        let events: Vec<MemberEvent> = {
+            let mut state = self.inner.state.lock().unwrap();
+            updates
+                .into_iter()
+                .filter_map(|update| state.apply(update))
+                .collect()
+        };
+        self.notify(events);
+    }
+
+    fn notify(&self, events: Vec<MemberEvent>) {
+        for event in events {
+            tracing::info!(?event, "membership changed");
+            // Nobody may be listening.
+            let _ = self.inner.events.send(event);
+        }
+    }
+}
+
+impl<D: Dial> Cluster for Swim<D> {
+    type Address = String;
+
+    fn subscribe(&self) -> impl Stream<Item = MemberEvent> + Send + 'static {
+        let events = self.inner.events.subscribe();
+        futures::stream::unfold(events, |mut events| async move {
+            loop {
+                match events.recv().await {
+                    Ok(event) => return Some((event, events)),
+                    Err(RecvError::Lagged(missed)) => {
+                        tracing::warn!(missed, "membership events were dropped")
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+
+    async fn join(&self, seeds: &[String]) -> crate::Result<usize> {
+        let local = self.local();
+        let mut joined = 0;
+        for seed in seeds {
+            let res = Deadline::after(self.inner.config.probe_interval)
+                .timeout(async {
+                    let mut transport = self.checkout(seed).await?;
+                    let mut chan = GossipChannel {
+                        inner: Box::new(&mut transport),
+                    };
+                    let members = chan.join(local.clone()).await?;
+                    self.checkin(seed, transport);
+                    Ok::<_, Error>(members)
+                })
+                .await;
+            match res {
+                Ok(Ok(members)) => {
+                    self.apply(members);
+                    joined += 1;
+                }
+                Ok(Err(err)) | Err(err) => tracing::warn!(seed, %err, "join failed"),
+            }
+        }
+        if joined == 0 {
+            return Err(crate::Error::JoinFailed);
+        }
+        Ok(joined)
+    }
+
+    async fn leave(&self) -> crate::Result<()> {
+        let (local, members) = {
+            let mut state = self.inner.state.lock().unwrap();
+            state.local.health = Health::Left;
+            let local = state.local.clone();
+            state.broadcast(local.clone());
+            let members: Vec<Member> = state.reachable().cloned().collect();
+            (local, members)
+        };
+        // Tell everyone, rather than wait for the gossip to reach them.
+        for member in members {
+            self.send_ping(&member, vec![local.clone()], self.inner.config.probe_timeout)
+                .await;
+        }
+        Ok(())
+    }
+}
+
+/// Handles the calls of other members.
+pub struct GossipServer<D: Dial> {
+    swim: Swim<D>,
+}
+
+impl<D: Dial> Clone for GossipServer<D> {
+    fn clone(&self) -> Self {
+        Self {
+            swim: self.swim.clone(),
+        }
+    }
+}
+
+impl<D: Dial> Gossip for GossipServer<D> {
+    async fn ping(&mut self, updates: Vec<Member>) -> Result<Vec<Member>, Error> {
+        self.swim.apply(updates);
+        let mut state = self.swim.inner.state.lock().unwrap();
+        Ok(state.take_broadcasts(&self.swim.inner.config))
+    }
+
+    async fn ping_req(&mut self, target: Member) -> Result<bool, Error> {
+        let swim = self.swim.clone();
+        let timeout = swim.inner.config.probe_timeout;
+        // Spawned, so the dial future needn't be Sync.
+        let acked = tokio::spawn(async move { swim.ping(&target, timeout).await });
+        Ok(acked.await.unwrap_or(false))
+    }
+
+    async fn join(&mut self, member: Member) -> Result<Vec<Member>, Error> {
+        self.swim.apply([member]);
+        Ok(self.swim.members())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(name: &str, incarnation: u64, health: Health) -> Member {
+        Member {
+            name: name.to_string(),
+            addr: name.to_string(),
+            incarnation,
+            health,
+            tags: BTreeMap::new(),
+        }
+    }
+
+    fn state() -> State {
+        State {
+            local: member("local", 0, Health::Alive),
+            members: BTreeMap::new(),
+            broadcasts: Vec::new(),
+            probes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_updates_follow_incarnations() {
+        let mut state = state();
+        assert_eq!(
+            state.apply(member("a", 1, Health::Alive)),
+            Some(MemberEvent::Join(member("a", 1, Health::Alive)))
+        );
+        // Stale or repeated news is ignored.
+        assert_eq!(state.apply(member("a", 0, Health::Suspect)), None);
+        assert_eq!(state.apply(member("a", 1, Health::Alive)), None);
+        assert_eq!(state.members["a"].member.health, Health::Alive);
+
+        assert_eq!(state.apply(member("a", 1, Health::Suspect)), None);
+        assert_eq!(state.members["a"].member.health, Health::Suspect);
+        // The member refuted the suspicion.
+        assert_eq!(state.apply(member("a", 2, Health::Alive)), None);
+        assert_eq!(state.members["a"].member.health, Health::Alive);
+
+        assert_eq!(
+            state.apply(member("a", 2, Health::Dead)),
+            Some(MemberEvent::Fail(member("a", 2, Health::Dead)))
+        );
+        assert_eq!(state.apply(member("a", 2, Health::Alive)), None);
+        assert_eq!(
+            state.apply(member("a", 3, Health::Alive)),
+            Some(MemberEvent::Join(member("a", 3, Health::Alive)))
+        );
+        assert_eq!(
+            state.apply(member("a", 3, Health::Left)),
+            Some(MemberEvent::Leave(member("a", 3, Health::Left)))
+        );
+
+        let mut tags = member("a", 4, Health::Alive);
+        tags.tags.insert("zone".to_string(), "a".to_string());
+        state.apply(member("a", 4, Health::Alive));
+        assert_eq!(state.apply(tags.clone()), None);
+        tags.incarnation = 5;
+        assert_eq!(state.apply(tags.clone()), Some(MemberEvent::Update(tags)));
+
+        // Members that are gone before we heard of them are ignored.
+        assert_eq!(state.apply(member("b", 0, Health::Dead)), None);
+        assert!(!state.members.contains_key("b"));
+    }
+
+    #[test]
+    fn test_suspicions_are_refuted() {
+        let mut state = state();
+        state.apply(member("local", 3, Health::Suspect));
+        assert_eq!(state.local.incarnation, 4);
+        let updates = state.take_broadcasts(&Config::default());
+        assert_eq!(updates, [member("local", 4, Health::Alive)]);
+    }
+
+    #[test]
+    fn test_broadcasts_are_retransmitted_a_limited_number_of_times() {
+        let config = Config {
+            retransmit_mult: 2,
+            ..Default::default()
+        };
+        let mut state = state();
+        state.apply(member("a", 0, Health::Alive));
+        assert_eq!(state.take_broadcasts(&config).len(), 1);
+        assert_eq!(state.take_broadcasts(&config).len(), 1);
+        assert!(state.take_broadcasts(&config).is_empty());
+    }
+
+    #[test]
+    fn test_suspects_expire() {
+        let mut state = state();
+        state.apply(member("a", 0, Health::Alive));
+        state.apply(member("a", 0, Health::Suspect));
+        assert!(state.expire_suspects(Duration::from_secs(5)).is_empty());
+        assert_eq!(
+            state.expire_suspects(Duration::ZERO),
+            [MemberEvent::Fail(member("a", 0, Health::Dead))]
+        );
+        assert_eq!(state.members["a"].member.health, Health::Dead);
+    }
+}
@@ -24,7 +24,15 @@
 //!    class Vec1,Vec2,H1,H2,Adj1,Adj2 component
 //!    class ED,HD,ADJ,TD calculation
 //!
-use {jetstream_wireformat::JetStreamWireFormat, rand::Rng, std::time::Duration};
+
+pub mod nearest;
+pub mod ping;
+
+use {
+    jetstream_wireformat::JetStreamWireFormat,
+    rand::Rng,
+    std::{collections::HashMap, time::Duration},
+};
 
 // Constants
 const SECONDS_TO_NANOSECONDS: f64 = 1.0e9;
@@ -93,6 +101,9 @@ pub enum CoordinateError {
     /// The round trip time is not in a valid range
     #[error("round trip time not in valid range")]
     InvalidRtt,
+    /// The configuration has no dimensions
+    #[error("coordinate dimensionality must be positive")]
+    ZeroDimensionality,
 }
 
 impl Coordinate {
@@ -223,6 +234,172 @@ impl Coordinate {
     }
 }
 
+/// The longest round trip time an observation can have
+const MAX_RTT: Duration = Duration::from_secs(10);
+
+/// Owns the local node's coordinate, and moves it with every round trip time
+/// observed to another node.
+///
+/// Observations go through a median filter per node, so a single slow
+/// response doesn't throw the coordinate off, then the Vivaldi update, the
+/// adjustment window and a gravity pull toward the origin are applied in turn.
+#[derive(Debug, Clone)]
+pub struct Client {
+    /// The local coordinate
+    coord: Coordinate,
+    /// The origin, which gravity pulls the coordinate toward
+    origin: Coordinate,
+    config: Config,
+    /// Where the next adjustment sample goes
+    adjustment_index: usize,
+    /// The differences between observed and estimated round trip times
+    adjustment_samples: Vec<f64>,
+    /// The latest round trip times observed to each node, in seconds
+    latency_filter_samples: HashMap<String, Vec<f64>>,
+    /// How many times the coordinate was reset after becoming invalid
+    resets: usize,
+}
+
+impl Client {
+    /// Creates a client with a new coordinate at the origin
+    pub fn new(config: Config) -> Result<Self, CoordinateError> {
+        if config.dimensionality == 0 {
+            return Err(CoordinateError::ZeroDimensionality);
+        }
+        Ok(Self {
+            coord: Coordinate::new(&config),
+            origin: Coordinate::new(&config),
+            adjustment_index: 0,
+            adjustment_samples: vec![0.0; config.adjustment_window_size],
+            latency_filter_samples: HashMap::new(),
+            resets: 0,
+            config,
+        })
+    }
+
+    /// Returns the local coordinate
+    pub fn coordinate(&self) -> &Coordinate {
+        &self.coord
+    }
+
+    /// Replaces the local coordinate, for example with one saved before a
+    /// restart
+    pub fn set_coordinate(&mut self, coord: Coordinate) -> Result<(), CoordinateError> {
+        self.check_coordinate(&coord)?;
+        self.coord = coord;
+        Ok(())
+    }
+
+    /// Forgets the round trip times observed to `node`, once it has left
+    pub fn forget_node(&mut self, node: &str) {
+        self.latency_filter_samples.remove(node);
+    }
+
+    /// Returns how many times the coordinate was reset after becoming invalid
+    pub fn resets(&self) -> usize {
+        self.resets
+    }
+
+    /// Returns the estimated round trip time to a node at `other`
+    pub fn distance_to(&self, other: &Coordinate) -> Result<Duration, CoordinateError> {
+        self.coord.distance_to(other)
+    }
+
+    /// Applies a round trip time of `rtt` observed to `node`, whose
+    /// coordinate is `other`, and returns the updated local coordinate
+    pub fn update(
+        &mut self,
+        node: &str,
+        other: &Coordinate,
+        rtt: Duration,
+    ) -> Result<&Coordinate, CoordinateError> {
+        self.check_coordinate(other)?;
+        if rtt > MAX_RTT {
+            return Err(CoordinateError::InvalidRtt);
+        }
+
+        let rtt = self.latency_filter(node, rtt.as_secs_f64());
+        self.update_vivaldi(other, rtt)?;
+        self.update_adjustment(other, rtt);
+        self.update_gravity()?;
+        if !self.coord.is_valid() {
+            self.resets += 1;
+            self.coord = Coordinate::new(&self.config);
+        }
+        Ok(&self.coord)
+    }
+
+    fn check_coordinate(&self, coord: &Coordinate) -> Result<(), CoordinateError> {
+        if !self.coord.is_compatible_with(coord) {
+            return Err(CoordinateError::DimensionalityConflict);
+        }
+        if !coord.is_valid() {
+            return Err(CoordinateError::InvalidValues);
+        }
+        Ok(())
+    }
+
+    /// Records `rtt` for `node`, and returns the median of its latest
+    /// samples
+    fn latency_filter(&mut self, node: &str, rtt: f64) -> f64 {
+        let samples = self
+            .latency_filter_samples
+            .entry(node.to_string())
+            .or_default();
+        samples.push(rtt);
+        if samples.len() > self.config.latency_filter_size.max(1) {
+            samples.remove(0);
+        }
+
+        let mut sorted = samples.clone();
+        sorted.sort_by(f64::total_cmp);
+        sorted[sorted.len() / 2]
+    }
+
+    /// Moves the coordinate toward or away from `other`, by how far off the
+    /// estimate was, weighted by how confident each side is
+    fn update_vivaldi(&mut self, other: &Coordinate, rtt: f64) -> Result<(), CoordinateError> {
+        let rtt = rtt.max(ZERO_THRESHOLD);
+        let dist = self.coord.distance_to(other)?.as_secs_f64();
+        let wrongness = (dist - rtt).abs() / rtt;
+
+        let total_error = (self.coord.error + other.error).max(ZERO_THRESHOLD);
+        let weight = self.coord.error / total_error;
+
+        self.coord.error = (self.config.vivaldi_ce * weight * wrongness
+            + self.coord.error * (1.0 - self.config.vivaldi_ce * weight))
+            .min(self.config.vivaldi_error_max);
+
+        let force = self.config.vivaldi_cc * weight * (rtt - dist);
+        self.coord = self.coord.apply_force(&self.config, force, other)?;
+        Ok(())
+    }
+
+    /// Sets the adjustment to half the mean error of the latest estimates,
+    /// which the Euclidean model can't capture
+    fn update_adjustment(&mut self, other: &Coordinate, rtt: f64) {
+        if self.config.adjustment_window_size == 0 {
+            return;
+        }
+
+        let dist = self.coord.raw_distance_to(other);
+        self.adjustment_samples[self.adjustment_index] = rtt - dist;
+        self.adjustment_index = (self.adjustment_index + 1) % self.config.adjustment_window_size;
+
+        let sum: f64 = self.adjustment_samples.iter().sum();
+        self.coord.adjustment = sum / (2.0 * self.config.adjustment_window_size as f64);
+    }
+
+    /// Pulls the coordinate toward the origin, so coordinates don't drift
+    /// away together
+    fn update_gravity(&mut self) -> Result<(), CoordinateError> {
+        let dist = self.origin.distance_to(&self.coord)?.as_secs_f64();
+        let force = -(dist / self.config.gravity_rho).powi(2);
+        self.coord = self.coord.apply_force(&self.config, force, &self.origin)?;
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
@@ -401,4 +578,111 @@ mod tests {
             insta::assert_json_snapshot!(node_map);
         }
     }
+
+    #[test]
+    fn test_client_update() {
+        let config = Config {
+            dimensionality: 3,
+            ..Default::default()
+        };
+        let mut client = Client::new(config.clone()).unwrap();
+        _verify_equal_vectors(&client.coordinate().vec, &[0.0, 0.0, 0.0], 1e-6);
+
+        // Place a node right above the client and observe a round trip time
+        // longer than the client expects, given its distance.
+        let mut other = Coordinate::new(&config);
+        other.vec[2] = 0.001;
+        let rtt = Duration::from_secs_f64(2.0 * other.vec[2]);
+        client.update("node", &other, rtt).unwrap();
+
+        // The client should have scooted down to get away from it.
+        assert!(client.coordinate().vec[2] < 0.0);
+
+        let mut coord = client.coordinate().clone();
+        coord.vec[2] = 99.0;
+        client.set_coordinate(coord).unwrap();
+        assert_eq!(client.coordinate().vec[2], 99.0);
+    }
+
+    #[test]
+    fn test_client_rejects_invalid_observations() {
+        let config = Config {
+            dimensionality: 3,
+            ..Default::default()
+        };
+        let mut client = Client::new(config.clone()).unwrap();
+        let mut other = Coordinate::new(&config);
+        let rtt = Duration::from_millis(10);
+
+        other.vec[0] = f64::NAN;
+        assert!(matches!(
+            client.update("node", &other, rtt),
+            Err(CoordinateError::InvalidValues)
+        ));
+        let other = Coordinate::new(&config);
+        assert!(matches!(
+            client.update("node", &other, Duration::from_secs(11)),
+            Err(CoordinateError::InvalidRtt)
+        ));
+        let other = Coordinate::new(&Config::default());
+        assert!(matches!(
+            client.update("node", &other, rtt),
+            Err(CoordinateError::DimensionalityConflict)
+        ));
+        assert!(matches!(
+            Client::new(Config {
+                dimensionality: 0,
+                ..Default::default()
+            }),
+            Err(CoordinateError::ZeroDimensionality)
+        ));
+    }
+
+    #[test]
+    fn test_client_latency_filter() {
+        let mut client = Client::new(Config::default()).unwrap();
+
+        // Make sure we get the median, and that things age properly.
+        let mut filter = |node, rtt| client.latency_filter(node, rtt);
+        assert_eq!(filter("alice", 0.201), 0.201);
+        assert_eq!(filter("alice", 0.200), 0.201);
+        assert_eq!(filter("alice", 0.207), 0.201);
+
+        // This glitch gets filtered out, and is never seen by Vivaldi.
+        assert_eq!(filter("alice", 1.9), 0.207);
+        assert_eq!(filter("alice", 0.203), 0.207);
+        assert_eq!(filter("alice", 0.199), 0.203);
+        assert_eq!(filter("alice", 0.211), 0.203);
+
+        // Nodes are filtered separately.
+        assert_eq!(filter("bob", 0.310), 0.310);
+
+        client.forget_node("alice");
+        assert!(!client.latency_filter_samples.contains_key("alice"));
+        assert!(client.latency_filter_samples.contains_key("bob"));
+    }
+
+    #[test]
+    fn test_clients_converge() {
+        let config = Config::default();
+        let mut alice = Client::new(config.clone()).unwrap();
+        let mut bob = Client::new(config).unwrap();
+        let rtt = Duration::from_millis(10);
+
+        for _ in 0..200 {
+            let other = bob.coordinate().clone();
+            alice.update("bob", &other, rtt).unwrap();
+            let other = alice.coordinate().clone();
+            bob.update("alice", &other, rtt).unwrap();
+        }
+
+        let estimate = alice.distance_to(bob.coordinate()).unwrap().as_secs_f64();
+        assert!(
+            (estimate - rtt.as_secs_f64()).abs() < 0.001,
+            "estimated {estimate}s rather than {}s",
+            rtt.as_secs_f64()
+        );
+        assert!(alice.coordinate().error < 0.1);
+        assert_eq!(alice.resets(), 0);
+    }
 }
@@ -0,0 +1,206 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Nearest-node queries, to route calls to the replica with the lowest
+//! round trip time.
+//!
+//! ```ignore
+//! let local = coordinates.coordinate();
+//! let replicas = Query::new().limit(1).locate(&local, &placement, &key);
+//! if let Some(nearest) = replicas.first() {
+//!     let mut transport = nearest.node.dial::<P>().await?;
+//! }
+//! ```
+
+use {
+    super::Coordinate,
+    crate::{
+        cluster::{Health, IntoNode, Node},
+        placement::Placement,
+    },
+    jetstream_wireformat::WireFormat,
+    std::time::Duration,
+};
+
+/// A node, and its estimated round trip time from the origin of the query.
+#[derive(Debug, Clone)]
+pub struct Ranked<N> {
+    /// The node.
+    pub node: N,
+    /// The estimated round trip time, if the node has a coordinate that can
+    /// be compared with the origin.
+    pub rtt: Option<Duration>,
+}
+
+/// Which nodes to return, nearest first.
+///
+/// By default every [`Health::Alive`] node is returned. Nodes without a
+/// coordinate come after the others, in the order they were given.
+#[derive(Debug, Clone)]
+pub struct Query {
+    limit: Option<usize>,
+    health: Vec<Health>,
+}
+
+impl Default for Query {
+    fn default() -> Self {
+        Self {
+            limit: None,
+            health: vec![Health::Alive],
+        }
+    }
+}
+
+impl Query {
+    /// Returns a query for every alive node.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns at most the `k` nearest nodes.
+    pub fn limit(mut self, k: usize) -> Self {
+        self.limit = Some(k);
+        self
+    }
+
+    /// Also returns nodes in `health`, for example [`Health::Suspect`] ones
+    /// when there may be no alive node.
+    pub fn allow(mut self, health: Health) -> Self {
+        if !self.health.contains(&health) {
+            self.health.push(health);
+        }
+        self
+    }
+
+    /// Orders `nodes` by their estimated round trip time from `origin`.
+    pub fn rank<N: Node>(
+        &self,
+        origin: &Coordinate,
+        nodes: impl IntoIterator<Item = N>,
+    ) -> Vec<Ranked<N>> {
+        let mut ranked: Vec<_> = nodes
+            .into_iter()
+            .filter(|node| self.health.contains(&node.health()))
+            .map(|node| {
+                let rtt = node
+                    .coordinate()
+                    .ok()
+                    .and_then(|coordinate| origin.distance_to(&coordinate).ok());
+                Ranked { node, rtt }
+            })
+            .collect();
+        // Stable, so nodes at the same distance keep their order.
+        ranked.sort_by_key(|ranked| (ranked.rtt.is_none(), ranked.rtt));
+        if let Some(limit) = self.limit {
+            ranked.truncate(limit);
+        }
+        ranked
+    }
+
+    /// Orders the replicas `placement` locates `value` on by their estimated
+    /// round trip time from `origin`.
+    pub fn locate<'a, V, P>(
+        &self,
+        origin: &Coordinate,
+        placement: &'a P,
+        value: &'a V,
+    ) -> Vec<Ranked<impl Node + 'a>>
+    where
+        P: Placement,
+        V: WireFormat + Ord + Eq,
+    {
+        let nodes = placement.locate(value).into_iter().map(IntoNode::into_node);
+        self.rank(origin, nodes)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::*,
+        crate::{
+            cluster::{node::Connection, NodeId, TransientId},
+            coordinate::{Client, Config},
+            Result,
+        },
+        jetstream_rpc::Protocol,
+        sha2::{Digest, Sha256},
+    };
+
+    #[derive(Debug, Clone)]
+    struct TestNode {
+        name: &'static str,
+        coordinate: Option<Coordinate>,
+        health: Health,
+    }
+
+    impl Node for TestNode {
+        fn id(&self) -> NodeId {
+            NodeId::Transient(TransientId::from(Sha256::new()))
+        }
+
+        fn coordinate(&self) -> Result<Coordinate> {
+            self.coordinate.clone().ok_or(crate::Error::NoCoordinate)
+        }
+
+        fn health(&self) -> Health {
+            self.health
+        }
+
+        async fn dial<P: Protocol>(&self) -> Result<Connection<P>> {
+            Err(crate::Error::Dial(std::io::ErrorKind::Unsupported.into()))
+        }
+    }
+
+    /// Returns a coordinate `rtt` away from the origin, the way Vivaldi
+    /// would place it.
+    fn at(rtt: Duration) -> Coordinate {
+        let config = Config::default();
+        let mut client = Client::new(config.clone()).unwrap();
+        let origin = Coordinate::new(&config);
+        for _ in 0..500 {
+            client.update("origin", &origin, rtt).unwrap();
+        }
+        client.coordinate().clone()
+    }
+
+    fn node(name: &'static str, rtt: Option<u64>, health: Health) -> TestNode {
+        TestNode {
+            name,
+            coordinate: rtt.map(|rtt| at(Duration::from_millis(rtt))),
+            health,
+        }
+    }
+
+    fn names(ranked: &[Ranked<TestNode>]) -> Vec<&'static str> {
+        ranked.iter().map(|ranked| ranked.node.name).collect()
+    }
+
+    #[test]
+    fn test_nodes_are_ranked_by_rtt() {
+        let origin = Coordinate::new(&Config::default());
+        let nodes = vec![
+            node("unknown", None, Health::Alive),
+            node("far", Some(80), Health::Alive),
+            node("near", Some(5), Health::Alive),
+            node("suspect", Some(1), Health::Suspect),
+            node("dead", Some(1), Health::Dead),
+            node("middle", Some(30), Health::Alive),
+        ];
+
+        let ranked = Query::new().rank(&origin, nodes.clone());
+        assert_eq!(names(&ranked), ["near", "middle", "far", "unknown"]);
+        assert!(ranked[0].rtt.unwrap() < Duration::from_millis(10));
+        assert!(ranked[3].rtt.is_none());
+
+        let ranked = Query::new().limit(2).rank(&origin, nodes.clone());
+        assert_eq!(names(&ranked), ["near", "middle"]);
+
+        let ranked = Query::new()
+            .allow(Health::Suspect)
+            .limit(2)
+            .rank(&origin, nodes);
+        assert_eq!(names(&ranked), ["suspect", "near"]);
+    }
+}
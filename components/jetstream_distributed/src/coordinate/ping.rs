@@ -0,0 +1,339 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Sampling round trip times between nodes, to keep their coordinates
+//! accurate.
+//!
+//! Every node serves [`PingServer`], which answers pings with the node's
+//! coordinate, and runs a [`Pinger`], which pings a random member of the
+//! cluster every interval. The round trip time of the ping and the
+//! coordinate in its response are applied to the node's [`Coordinates`],
+//! which [`RemoteNode`](crate::cluster::node::RemoteNode)s read theirs from:
+//!
+//! ```ignore
+//! let coordinates = Coordinates::new(Config::default())?;
+//! // For every connection:
+//! let mut serv = ping_protocol::PingService { inner: PingServer::new(coordinates.clone()) };
+//! run(&mut serv, Framed::new(stream, ServerCodec::default())).await?;
+//!
+//! // Members are dialed at the address they gossip.
+//! let pinger = Pinger::new(coordinates.clone(), |addr: String| async move {
+//!     Ok(Framed::new(TcpStream::connect(addr).await?, ClientCodec::default()))
+//! });
+//! tokio::spawn(pinger.run(Duration::from_secs(1), swim.subscribe()));
+//!
+//! let node = RemoteNode::new(id, addresses).with_coordinates(coordinates.clone(), "node-b");
+//! let rtt = coordinates.estimate("node-b");
+//! ```
+//!
+//! The application still wires these up: it dials peers, serves
+//! [`PingServer`] on every connection it accepts, and spawns the [`Pinger`].
+//! Nothing here listens or spawns tasks on its own.
+
+use {
+    super::{Client, Config, Coordinate, CoordinateError},
+    crate::cluster::MemberEvent,
+    futures::{Stream, StreamExt},
+    jetstream_macros::service,
+    jetstream_rpc::{ClientTransport, Deadline, Error},
+    ping_protocol::PingChannel,
+    rand::seq::IteratorRandom,
+    std::{
+        collections::HashMap,
+        future::Future,
+        io,
+        pin::pin,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::time::Instant,
+};
+
+/// Exchanges coordinates with a peer. The caller times the call.
+#[service(crate = jetstream_rpc)]
+pub trait Ping {
+    /// Returns the callee's coordinate.
+    #[idempotent]
+    async fn ping(&mut self) -> Result<Coordinate, Error>;
+}
+
+#[derive(Debug)]
+struct State {
+    client: Client,
+    peers: HashMap<String, Coordinate>,
+}
+
+/// The local node's coordinate, and the latest coordinates of its peers.
+/// Clones share them.
+#[derive(Debug, Clone)]
+pub struct Coordinates {
+    state: Arc<Mutex<State>>,
+}
+
+impl Coordinates {
+    /// Starts at the origin, knowing no peers.
+    pub fn new(config: Config) -> Result<Self, CoordinateError> {
+        Ok(Self {
+            state: Arc::new(Mutex::new(State {
+                client: Client::new(config)?,
+                peers: HashMap::new(),
+            })),
+        })
+    }
+
+    /// Returns the local node's coordinate.
+    pub fn coordinate(&self) -> Coordinate {
+        self.state.lock().unwrap().client.coordinate().clone()
+    }
+
+    /// Applies a round trip time of `rtt` to `node`, which is at `other`,
+    /// and returns the updated local coordinate.
+    pub fn observe(
+        &self,
+        node: &str,
+        other: Coordinate,
+        rtt: Duration,
+    ) -> Result<Coordinate, CoordinateError> {
+        let mut state = self.state.lock().unwrap();
+        let coordinate = state.client.update(node, &other, rtt)?.clone();
+        state.peers.insert(node.to_string(), other);
+        Ok(coordinate)
+    }
+
+    /// Returns the latest coordinate of `node`, if it was observed.
+    pub fn peer(&self, node: &str) -> Option<Coordinate> {
+        self.state.lock().unwrap().peers.get(node).cloned()
+    }
+
+    /// Returns the estimated round trip time to `node`, if it was observed.
+    pub fn estimate(&self, node: &str) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        let other = state.peers.get(node)?;
+        state.client.distance_to(other).ok()
+    }
+
+    /// Forgets `node`, once it has left.
+    pub fn forget(&self, node: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.peers.remove(node);
+        state.client.forget_node(node);
+    }
+}
+
+/// Answers pings with the local node's coordinate.
+#[derive(Debug, Clone)]
+pub struct PingServer {
+    coordinates: Coordinates,
+}
+
+impl PingServer {
+    /// Returns a server answering with the coordinate in `coordinates`.
+    pub fn new(coordinates: Coordinates) -> Self {
+        Self { coordinates }
+    }
+}
+
+impl Ping for PingServer {
+    async fn ping(&mut self) -> Result<Coordinate, Error> {
+        Ok(self.coordinates.coordinate())
+    }
+}
+
+/// Dials the transports peers are pinged over.
+///
+/// This is implemented for closures taking an address and returning a future
+/// that resolves to a transport.
+pub trait Dial: Send + Sync + 'static {
+    /// The transport dialed.
+    type Transport: for<'a> ClientTransport<PingChannel<'a>> + 'static;
+    /// The future returned by [`Dial::dial`].
+    type Future: Future<Output = io::Result<Self::Transport>> + Send + 'static;
+    /// Dials the peer at `addr`.
+    fn dial(&self, addr: &str) -> Self::Future;
+}
+
+impl<F, Fut, T> Dial for F
+where
+    F: Fn(String) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = io::Result<T>> + Send + 'static,
+    T: for<'a> ClientTransport<PingChannel<'a>> + 'static,
+{
+    type Future = Fut;
+    type Transport = T;
+
+    fn dial(&self, addr: &str) -> Self::Future {
+        self(addr.to_string())
+    }
+}
+
+/// How long a ping may take by default.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A peer, and the transport to it once dialed.
+struct Peer<T> {
+    addr: String,
+    transport: Option<T>,
+}
+
+/// Pings peers, and applies the round trip times to the local node's
+/// [`Coordinates`]. Peers are dialed when first pinged, and again after a
+/// ping over their transport fails.
+pub struct Pinger<D: Dial> {
+    coordinates: Coordinates,
+    dial: D,
+    timeout: Duration,
+    peers: HashMap<String, Peer<D::Transport>>,
+}
+
+impl<D: Dial> Pinger<D> {
+    /// Returns a pinger, without peers, updating `coordinates`.
+    pub fn new(coordinates: Coordinates, dial: D) -> Self {
+        Self {
+            coordinates,
+            dial,
+            timeout: DEFAULT_TIMEOUT,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Gives up on pings that take longer than `timeout`, one second by
+    /// default.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Adds `node`, reached at `addr`.
+    pub fn with_peer(mut self, node: impl Into<String>, addr: impl Into<String>) -> Self {
+        self.add_peer(node, addr);
+        self
+    }
+
+    /// Adds `node`, reached at `addr`. A node that moved is dialed again.
+    pub fn add_peer(&mut self, node: impl Into<String>, addr: impl Into<String>) {
+        let addr = addr.into();
+        let peer = self.peers.entry(node.into()).or_insert_with(|| {
+            Peer {
+                addr: addr.clone(),
+                transport: None,
+            }
+        });
+        if peer.addr != addr {
+            *peer = Peer {
+                addr,
+                transport: None,
+            };
+        }
+    }
+
+    /// Removes `node`, and forgets its coordinate.
+    pub fn remove_peer(&mut self, node: &str) -> bool {
+        self.coordinates.forget(node);
+        self.peers.remove(node).is_some()
+    }
+
+    /// Pings `node` once, returning the round trip time.
+    pub async fn ping(&mut self, node: &str) -> Result<Duration, Error> {
+        let dial = &self.dial;
+        let peer = self
+            .peers
+            .get_mut(node)
+            .ok_or_else(|| Error::Custom(format!("unknown peer {}", node)))?;
+        // The transport is only put back if the ping worked, a broken one is
+        // dropped and dialed again next time.
+        let (other, rtt) = Deadline::after(self.timeout)
+            .timeout(async {
+                let mut transport = match peer.transport.take() {
+                    Some(transport) => transport,
+                    // Dialed on a task of its own, so the dial future needn't be Sync.
+                    None => tokio::spawn(dial.dial(&peer.addr))
+                        .await
+                        .map_err(|err| Error::Custom(err.to_string()))??,
+                };
+                let mut chan = PingChannel {
+                    inner: Box::new(&mut transport),
+                };
+                let start = Instant::now();
+                let other = chan.ping().await?;
+                let rtt = start.elapsed();
+                peer.transport = Some(transport);
+                Ok::<_, Error>((other, rtt))
+            })
+            .await??;
+        self.coordinates
+            .observe(node, other, rtt)
+            .map_err(|err| Error::Generic(Box::new(err)))?;
+        Ok(rtt)
+    }
+
+    /// Follows a membership change: members that join or move are pinged at
+    /// their address, those that leave or fail are forgotten.
+    pub fn follow(&mut self, event: MemberEvent) {
+        match event {
+            MemberEvent::Join(member) | MemberEvent::Update(member) => {
+                self.add_peer(member.name, member.addr)
+            }
+            MemberEvent::Leave(member) | MemberEvent::Fail(member) => {
+                self.remove_peer(&member.name);
+            }
+        }
+    }
+
+    /// Pings a random peer every `interval`, forever, following the
+    /// membership changes in `events`, like those of
+    /// [`Cluster::subscribe`](crate::cluster::Cluster::subscribe). Failed
+    /// pings are logged and skipped.
+    pub async fn run(mut self, interval: Duration, events: impl Stream<Item = MemberEvent>) {
+        // Membership streams may not be polled once they end.
+        let mut events = pin!(events.fuse());
+        let mut ticks = tokio::time::interval(interval);
+        loop {
+            tokio::select! {
+                Some(event) = events.next() => self.follow(event),
+                _ = ticks.tick() => {
+                    let Some(node) = self.peers.keys().choose(&mut rand::thread_rng()).cloned()
+                    else {
+                        continue;
+                    };
+                    if let Err(err) = self.ping(&node).await {
+                        tracing::warn!(node, %err, "ping failed");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use {
+        super::{ping_protocol::*, *},
+        jetstream_rpc::{Frame, Protocol},
+        okstd::prelude::*,
+    };
+
+    #[okstd::test]
+    async fn test_pings_are_answered_with_the_coordinate() {
+        let coordinates = Coordinates::new(Config::default()).unwrap();
+        let mut serv = PingService {
+            inner: PingServer::new(coordinates.clone()),
+        };
+
+        let mut other = coordinates.coordinate();
+        other.vec[0] = 0.01;
+        let moved = coordinates
+            .observe("node", other, Duration::from_millis(5))
+            .unwrap();
+        assert!(coordinates.estimate("node").is_some());
+
+        let rframe = serv.rpc(Frame::from((0, Tmessage::Ping(Tping {})))).await.unwrap();
+        let Rmessage::Ping(Rping(coordinate)) = rframe.msg else {
+            panic!("expected a coordinate");
+        };
+        assert_eq!(coordinate.vec, moved.vec);
+
+        coordinates.forget("node");
+        assert!(coordinates.peer("node").is_none());
+    }
+}
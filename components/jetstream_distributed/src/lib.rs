@@ -6,6 +6,9 @@
 )]
 //! # JetStream Distributed
 //! JetStream Distributed is a collection of primitives for building distributed systems with JetStream.
+//! ## Feature Flags
+//! - `quic` - Dials nodes over QUIC
+//! - `vsock` - Dials nodes over vsock
 pub mod access_control;
 pub mod cluster;
 pub mod coordinate;
@@ -17,6 +20,28 @@ pub enum Error {
     /// Failed to join node to the cluster
     #[error("Failed to join node")]
     JoinFailed,
+    /// A store failed to read or write
+    #[error("storage error: {0}")]
+    Storage(#[source] Box<dyn std::error::Error + Send + Sync>),
+    /// A store can't read the snapshot of the zookie, or one as fresh
+    #[error("no snapshot for {0}")]
+    SnapshotUnavailable(access_control::consistency::Zookie),
+    /// A zookie the store didn't issue
+    #[error("invalid zookie {0}")]
+    InvalidZookie(access_control::consistency::Zookie),
+    /// None of a node's addresses could be dialed
+    #[error("failed to dial node: {0}")]
+    Dial(#[source] std::io::Error),
+    /// A node's coordinate isn't known yet
+    #[error("no coordinate for node")]
+    NoCoordinate,
+    /// A page token that no lookup returned
+    #[error("invalid page token {0:?}")]
+    InvalidPageToken(String),
+    /// A placement rule spreading replicas across a level the hierarchy
+    /// doesn't have
+    #[error("replicas are spread across {0:?}, which isn't a level")]
+    UnknownLevel(String),
 }
 /// Result type for JetStream Cluster operations
 pub type Result<T> = std::result::Result<T, Error>;
@@ -1,16 +1,73 @@
 //! Placement module is used to determine the placement of the object,
 //! and later to locate the object.
+//!
+//! [`ring::Ring`] and [`rendezvous::Rendezvous`] hash objects onto nodes, so
+//! that every node agrees on the placement without coordinating, and few
+//! objects move when nodes come and go. [`crush::Crush`] also spreads the
+//! replicas of an object across failure domains, like racks or zones.
 
-use crate::cluster::IntoNode;
+use {
+    crate::cluster::IntoNode,
+    jetstream_wireformat::WireFormat,
+    sha2::{Digest, Sha256},
+};
+
+pub mod crush;
+pub mod rendezvous;
+pub mod ring;
 
 /// Placement trait.
 pub trait Placement {
     /// Returns the placement of the object.
     fn map<V>(&self, value: &V) -> Vec<impl IntoNode>
     where
-        V: Ord + Eq;
+        V: WireFormat + Ord + Eq;
     /// Returns the placement of the object.
     fn locate<V>(&self, value: &V) -> Vec<impl IntoNode>
     where
-        V: Ord + Eq;
+        V: WireFormat + Ord + Eq;
+}
+
+/// Hashes values the same way on every node, by their wire encoding. The
+/// [`Hash`](std::hash::Hash) implementations fed to a hasher may change
+/// between platforms and Rust releases, which would move every object.
+pub(crate) struct StableHasher(Sha256);
+
+impl StableHasher {
+    pub(crate) fn new() -> Self {
+        Self(Sha256::new())
+    }
+
+    /// Hashes the wire encoding of `value`.
+    pub(crate) fn value<V: WireFormat>(mut self, value: &V) -> Self {
+        // Writing to a hasher doesn't fail, and values that don't fit their
+        // wire format, like overlong strings, fail the same way everywhere.
+        let _ = value.encode(&mut self.0);
+        self
+    }
+
+    /// Hashes `name`, prefixed with its length, so that it can't run into
+    /// what is hashed next.
+    pub(crate) fn name(mut self, name: &str) -> Self {
+        self.0.update((name.len() as u64).to_le_bytes());
+        self.0.update(name.as_bytes());
+        self
+    }
+
+    pub(crate) fn finish(self) -> u64 {
+        let digest = self.0.finalize();
+        u64::from_le_bytes(digest[..8].try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_hashes_are_stable() {
+        // Placements change if these do.
+        assert_eq!(StableHasher::new().value(&42u32).finish(), 11779690937116173544);
+        assert_eq!(StableHasher::new().name("node").value(&7u64).finish(), 12487854204149775599);
+    }
 }
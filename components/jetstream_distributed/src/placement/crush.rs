@@ -0,0 +1,387 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Hierarchical placement across failure domains, after CRUSH.
+//!
+//! Nodes are arranged in a hierarchy, like region, zone and rack, read from
+//! the tags they advertise. A [`Rule`] spreads an object's replicas across a
+//! level of the hierarchy, so that no two replicas share a rack, say. The
+//! replicas go in the domains of that level with the highest straw2 draws, a
+//! hash of the object and the domain scaled by the capacity under it, and in
+//! each, on the node found by following the highest draws down. Draws don't
+//! depend on the other domains, so when nodes come and go or are reweighted,
+//! few objects move, and [`Crush::moves`] reports which.
+//!
+//! ```ignore
+//! let mut placement = Crush::new(["region", "zone", "rack"], Rule::new(3).spread_across("rack"))?;
+//! for member in swim.members() {
+//!     placement.add(member.name.clone(), &member.tags, capacity(&member), node(&member));
+//! }
+//! let replicas = placement.replicas(&key);
+//! ```
+
+use {
+    super::{Placement, StableHasher},
+    crate::{cluster::IntoNode, Error, Result},
+    jetstream_wireformat::WireFormat,
+    std::collections::{BTreeMap, BTreeSet},
+};
+
+/// How many replicas to place, and across which level of the hierarchy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    replicas: usize,
+    failure_domain: Option<String>,
+}
+
+impl Rule {
+    /// Returns a rule placing objects on `replicas` distinct nodes.
+    pub fn new(replicas: usize) -> Self {
+        Self {
+            replicas,
+            failure_domain: None,
+        }
+    }
+
+    /// Places no two replicas in the same domain of `level`, like "rack".
+    pub fn spread_across(mut self, level: impl Into<String>) -> Self {
+        self.failure_domain = Some(level.into());
+        self
+    }
+}
+
+/// An object whose replicas changed between two topologies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Move<'a, V: ?Sized> {
+    /// The object.
+    pub object: &'a V,
+    /// The names of the nodes the object was on, primary first.
+    pub from: Vec<String>,
+    /// The names of the nodes the object is on now, primary first.
+    pub to: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Device<N> {
+    /// The node's domain at every level, then its name.
+    path: Vec<String>,
+    weight: f64,
+    node: N,
+}
+
+/// Nodes, named uniquely, in a hierarchy of failure domains.
+#[derive(Debug, Clone)]
+pub struct Crush<N> {
+    levels: Vec<String>,
+    rule: Rule,
+    /// How far down the hierarchy replicas must differ; below the last
+    /// level, they only need different nodes.
+    depth: usize,
+    devices: BTreeMap<String, Device<N>>,
+}
+
+impl<N> Crush<N> {
+    /// Returns an empty hierarchy, with the tags naming its `levels`, from
+    /// the top down, placing objects by `rule`.
+    ///
+    /// Fails with [`Error::UnknownLevel`] if `rule` spreads replicas across
+    /// a level that isn't one of `levels`.
+    pub fn new(levels: impl IntoIterator<Item = impl Into<String>>, rule: Rule) -> Result<Self> {
+        let levels: Vec<String> = levels.into_iter().map(Into::into).collect();
+        let depth = match &rule.failure_domain {
+            Some(domain) => match levels.iter().position(|level| level == domain) {
+                Some(index) => index + 1,
+                None => return Err(Error::UnknownLevel(domain.clone())),
+            },
+            None => levels.len() + 1,
+        };
+        Ok(Self {
+            levels,
+            rule,
+            depth,
+            devices: BTreeMap::new(),
+        })
+    }
+
+    /// Adds `node`, named `name`, with `weight` proportional to its
+    /// capacity, replacing the node of the same name. Its domains are the
+    /// values of the level tags in `tags`; nodes without a tag share the
+    /// domain named "".
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        tags: &BTreeMap<String, String>,
+        weight: f64,
+        node: N,
+    ) -> Option<N> {
+        let name = name.into();
+        let mut path: Vec<String> = self
+            .levels
+            .iter()
+            .map(|level| tags.get(level).cloned().unwrap_or_default())
+            .collect();
+        path.push(name.clone());
+        let device = Device {
+            path,
+            weight: weight.max(0.0),
+            node,
+        };
+        self.devices.insert(name, device).map(|device| device.node)
+    }
+
+    /// Removes the node named `name`.
+    pub fn remove(&mut self, name: &str) -> Option<N> {
+        self.devices.remove(name).map(|device| device.node)
+    }
+
+    /// Sets the weight of the node named `name`. A node weighing nothing
+    /// holds no objects. Returns false if there is no such node.
+    pub fn reweight(&mut self, name: &str, weight: f64) -> bool {
+        match self.devices.get_mut(name) {
+            Some(device) => {
+                device.weight = weight.max(0.0);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the node named `name`.
+    pub fn get(&self, name: &str) -> Option<&N> {
+        self.devices.get(name).map(|device| &device.node)
+    }
+
+    /// Returns how many nodes there are.
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    /// Returns true if there are no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Returns the nodes `value` is placed on, primary first. There are
+    /// fewer than the rule asks for if there are fewer failure domains.
+    pub fn replicas<V: WireFormat>(&self, value: &V) -> Vec<&N> {
+        self.names(value)
+            .into_iter()
+            .map(|name| &self.devices[name].node)
+            .collect()
+    }
+
+    /// Returns the names of the nodes `value` is placed on, primary first.
+    pub fn names<V: WireFormat>(&self, value: &V) -> Vec<&str> {
+        let devices: Vec<&Device<N>> = self
+            .devices
+            .values()
+            .filter(|device| device.weight > 0.0)
+            .collect();
+        let mut domains = weigh(value, &devices, self.depth);
+        domains.sort_by(|(a, _), (b, _)| a.total_cmp(b).reverse());
+        domains
+            .into_iter()
+            .take(self.rule.replicas)
+            .map(|(_, domain)| self.descend(value, &devices, domain))
+            .collect()
+    }
+
+    /// Returns the objects whose replicas differ in `next`, ignoring their
+    /// order, to move them before switching to it.
+    pub fn moves<'a, V: WireFormat>(
+        &self,
+        next: &Self,
+        objects: impl IntoIterator<Item = &'a V>,
+    ) -> Vec<Move<'a, V>> {
+        objects
+            .into_iter()
+            .filter_map(|object| {
+                let from = self.names(object);
+                let to = next.names(object);
+                if from.iter().collect::<BTreeSet<_>>() == to.iter().collect::<BTreeSet<_>>() {
+                    return None;
+                }
+                Some(Move {
+                    object,
+                    from: from.into_iter().map(String::from).collect(),
+                    to: to.into_iter().map(String::from).collect(),
+                })
+            })
+            .collect()
+    }
+
+    /// Goes down the hierarchy from `domain`, picking the domain with the
+    /// highest straw2 draw at every level, and returns the name of the node
+    /// it ends at.
+    fn descend<'a, V: WireFormat>(
+        &self,
+        value: &V,
+        devices: &[&'a Device<N>],
+        domain: &[String],
+    ) -> &'a str {
+        let mut devices: Vec<&Device<N>> = devices
+            .iter()
+            .copied()
+            .filter(|device| device.path.starts_with(domain))
+            .collect();
+        for depth in domain.len() + 1..=self.levels.len() + 1 {
+            let draws = weigh(value, &devices, depth);
+            let (_, chosen) = draws
+                .into_iter()
+                .max_by(|(a, _), (b, _)| a.total_cmp(b))
+                .expect("domains hold a node");
+            let chosen = chosen.to_vec();
+            devices.retain(|device| device.path.starts_with(&chosen));
+        }
+        devices[0].path.last().unwrap()
+    }
+}
+
+/// Returns the domains `depth` levels down the hierarchy `devices` are in,
+/// with their draws for `value`.
+fn weigh<'a, N, V: WireFormat>(
+    value: &V,
+    devices: &[&'a Device<N>],
+    depth: usize,
+) -> Vec<(f64, &'a [String])> {
+    let mut weights: BTreeMap<&[String], f64> = BTreeMap::new();
+    for device in devices {
+        *weights.entry(&device.path[..depth]).or_default() += device.weight;
+    }
+    weights
+        .into_iter()
+        .map(|(domain, weight)| (straw2(value, domain, weight), domain))
+        .collect()
+}
+
+/// Returns the draw of `domain` for `value`. Taking the logarithm of a
+/// uniform hash, scaled by the inverse of the weight, makes the domain win
+/// in proportion to its weight, whatever the weights of the others.
+fn straw2<V: WireFormat>(value: &V, domain: &[String], weight: f64) -> f64 {
+    let hash = domain
+        .iter()
+        .fold(StableHasher::new().value(value), |hasher, name| hasher.name(name))
+        .finish();
+    // Uniform in (0, 1].
+    let uniform = ((hash >> 11) + 1) as f64 / (1u64 << 53) as f64;
+    uniform.ln() / weight
+}
+
+impl<N> Placement for Crush<N>
+where
+    N: IntoNode + Clone,
+{
+    fn map<V>(&self, value: &V) -> Vec<impl IntoNode>
+    where
+        V: WireFormat + Ord + Eq,
+    {
+        self.replicas(value).into_iter().cloned().collect::<Vec<N>>()
+    }
+
+    fn locate<V>(&self, value: &V) -> Vec<impl IntoNode>
+    where
+        V: WireFormat + Ord + Eq,
+    {
+        self.map(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn tags(zone: &str, rack: &str) -> BTreeMap<String, String> {
+        [
+            ("zone".to_string(), zone.to_string()),
+            ("rack".to_string(), rack.to_string()),
+        ]
+        .into()
+    }
+
+    /// Two zones of three racks of two nodes each.
+    fn topology(rule: Rule) -> Crush<String> {
+        let mut crush = Crush::new(["zone", "rack"], rule).unwrap();
+        for zone in ["a", "b"] {
+            for rack in 0..3 {
+                for node in 0..2 {
+                    let name = format!("{}{}-{}", zone, rack, node);
+                    crush.add(name.clone(), &tags(zone, &format!("{}{}", zone, rack)), 1.0, name);
+                }
+            }
+        }
+        crush
+    }
+
+    fn rack(name: &str) -> &str {
+        name.split('-').next().unwrap()
+    }
+
+    #[test]
+    fn test_replicas_are_spread_across_domains() {
+        let crush = topology(Rule::new(3).spread_across("rack"));
+        for key in 0..1_000 {
+            let names = crush.names(&key);
+            assert_eq!(names.len(), 3);
+            let racks: BTreeSet<_> = names.iter().map(|name| rack(name)).collect();
+            assert_eq!(racks.len(), 3, "{:?}", names);
+        }
+
+        // There are only two zones.
+        let crush = topology(Rule::new(3).spread_across("zone"));
+        for key in 0..100 {
+            let names = crush.names(&key);
+            assert_eq!(names.len(), 2);
+            assert_ne!(&names[0][..1], &names[1][..1]);
+        }
+    }
+
+    #[test]
+    fn test_unknown_levels_are_refused() {
+        let res = Crush::<String>::new(["zone", "rack"], Rule::new(3).spread_across("row"));
+        assert!(matches!(res, Err(Error::UnknownLevel(level)) if level == "row"));
+    }
+
+    #[test]
+    fn test_nodes_are_weighted_by_capacity() {
+        let mut crush = topology(Rule::new(1));
+        crush.reweight("a0-0", 2.0);
+        crush.reweight("b2-1", 0.0);
+        let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+        for key in 0..13_000 {
+            *counts.entry(crush.names(&key)[0]).or_default() += 1;
+        }
+        // Every other node holds about a thousand objects.
+        assert!((1_700..2_300).contains(&counts["a0-0"]), "{:?}", counts);
+        assert!((800..1_200).contains(&counts["a1-0"]), "{:?}", counts);
+        assert!(!counts.contains_key("b2-1"));
+    }
+
+    #[test]
+    fn test_moves_are_reported() {
+        let before = topology(Rule::new(3).spread_across("rack"));
+        let mut after = before.clone();
+        after.remove("a1-0");
+
+        let keys: Vec<u32> = (0..1_000).collect();
+        let moves = before.moves(&after, &keys);
+        for key in &keys {
+            if before.names(key).contains(&"a1-0") {
+                assert!(moves.iter().any(|m| m.object == key));
+            }
+        }
+        for m in &moves {
+            // Only objects in the rack that lost capacity move, and the
+            // replicas outside it stay.
+            assert!(m.from.iter().any(|name| rack(name) == "a1"), "{:?}", m);
+            let kept = m.from.iter().filter(|name| rack(name) != "a1");
+            assert!(kept.into_iter().all(|name| m.to.contains(name)), "{:?}", m);
+        }
+
+        let mut grown = before.clone();
+        grown.add("c0-0", &tags("c", "c0"), 1.0, "c0-0".to_string());
+        for m in before.moves(&grown, &keys) {
+            assert!(m.to.contains(&"c0-0".to_string()), "{:?}", m);
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Rendezvous, or highest random weight, hashing.
+//!
+//! Every node scores an object by hashing its name with the object, and the
+//! object is placed on the nodes with the highest scores. Scores don't
+//! depend on the other nodes, so only the objects a joining node outscores
+//! the others for move to it, and only a leaving node's objects move away.
+//! Unlike [`Ring`](super::ring::Ring), it needs no virtual nodes to spread
+//! objects evenly, but placing an object takes a hash per node.
+//!
+//! ```ignore
+//! let mut placement = Rendezvous::new(3);
+//! for member in swim.members() {
+//!     placement.add(member.name.clone(), node(&member));
+//! }
+//! let replicas = placement.replicas(&key);
+//! ```
+
+use {
+    super::{Placement, StableHasher},
+    crate::cluster::IntoNode,
+    jetstream_wireformat::WireFormat,
+    std::{cmp::Reverse, collections::BTreeMap},
+};
+
+/// Nodes, named uniquely, objects are placed on by rendezvous hashing.
+#[derive(Debug, Clone)]
+pub struct Rendezvous<N> {
+    replicas: usize,
+    nodes: BTreeMap<String, N>,
+}
+
+impl<N> Rendezvous<N> {
+    /// Returns an empty placement, placing objects on `replicas` nodes.
+    pub fn new(replicas: usize) -> Self {
+        Self {
+            replicas,
+            nodes: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `node`, named `name`, replacing the node of the same name.
+    pub fn add(&mut self, name: impl Into<String>, node: N) -> Option<N> {
+        self.nodes.insert(name.into(), node)
+    }
+
+    /// Removes the node named `name`.
+    pub fn remove(&mut self, name: &str) -> Option<N> {
+        self.nodes.remove(name)
+    }
+
+    /// Returns the node named `name`.
+    pub fn get(&self, name: &str) -> Option<&N> {
+        self.nodes.get(name)
+    }
+
+    /// Returns how many nodes there are.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns true if there are no nodes.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the nodes `value` is placed on, primary first. There are
+    /// fewer than the replication factor if there are fewer nodes.
+    pub fn replicas<V: WireFormat>(&self, value: &V) -> Vec<&N> {
+        self.names(value)
+            .into_iter()
+            .map(|name| &self.nodes[name])
+            .collect()
+    }
+
+    /// Returns the names of the nodes `value` is placed on, primary first.
+    pub fn names<V: WireFormat>(&self, value: &V) -> Vec<&str> {
+        let mut scored: Vec<(u64, &str)> = self
+            .nodes
+            .keys()
+            .map(|name| {
+                let score = StableHasher::new().name(name).value(value).finish();
+                (score, name.as_str())
+            })
+            .collect();
+        scored.sort_by_key(|&(score, name)| (Reverse(score), name));
+        scored.truncate(self.replicas);
+        scored.into_iter().map(|(_, name)| name).collect()
+    }
+}
+
+impl<N> Placement for Rendezvous<N>
+where
+    N: IntoNode + Clone,
+{
+    fn map<V>(&self, value: &V) -> Vec<impl IntoNode>
+    where
+        V: WireFormat + Ord + Eq,
+    {
+        self.replicas(value).into_iter().cloned().collect::<Vec<N>>()
+    }
+
+    fn locate<V>(&self, value: &V) -> Vec<impl IntoNode>
+    where
+        V: WireFormat + Ord + Eq,
+    {
+        self.map(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn placement(nodes: usize) -> Rendezvous<usize> {
+        let mut placement = Rendezvous::new(3);
+        for node in 0..nodes {
+            placement.add(format!("node-{}", node), node);
+        }
+        placement
+    }
+
+    #[test]
+    fn test_objects_are_spread_evenly() {
+        let placement = placement(10);
+        let mut counts = [0; 10];
+        for key in 0..10_000 {
+            let replicas = placement.replicas(&key);
+            assert_eq!(replicas.len(), 3);
+            counts[*replicas[0]] += 1;
+        }
+        for count in counts {
+            assert!((800..1_200).contains(&count), "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_few_objects_move() {
+        let before = placement(10);
+        let mut after = before.clone();
+        after.remove("node-3");
+
+        for key in 0..10_000 {
+            let (old, new) = (before.replicas(&key), after.replicas(&key));
+            let kept: Vec<_> = old.iter().filter(|&&&node| node != 3).collect();
+            // The other replicas stay, in the same order, and the next
+            // highest scoring node takes the removed node's place.
+            assert_eq!(kept, new.iter().take(kept.len()).collect::<Vec<_>>());
+            if kept.len() == old.len() {
+                assert_eq!(old, new);
+            }
+        }
+    }
+}
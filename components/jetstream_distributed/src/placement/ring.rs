@@ -0,0 +1,210 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Consistent hashing.
+//!
+//! Every node is hashed onto a ring at a number of points, its virtual
+//! nodes. An object is placed on the nodes of the first points at or after
+//! its own hash, going around the ring. When a node joins, it only takes the
+//! objects that now hash closest to its points, and when it leaves, only its
+//! objects move, to the nodes after it.
+//!
+//! ```ignore
+//! let mut ring = Ring::new(3);
+//! for member in swim.members() {
+//!     ring.add(member.name.clone(), node(&member));
+//! }
+//! let replicas = ring.replicas(&key);
+//! ```
+
+use {
+    super::{Placement, StableHasher},
+    crate::cluster::IntoNode,
+    jetstream_wireformat::WireFormat,
+    std::collections::BTreeMap,
+};
+
+/// How many points a node has on the ring, unless set with
+/// [`Ring::with_vnodes`].
+pub const DEFAULT_VNODES: usize = 128;
+
+/// A consistent hash ring of nodes, named uniquely.
+#[derive(Debug, Clone)]
+pub struct Ring<N> {
+    replicas: usize,
+    vnodes: usize,
+    nodes: BTreeMap<String, N>,
+    points: BTreeMap<u64, String>,
+}
+
+impl<N> Ring<N> {
+    /// Returns an empty ring placing objects on `replicas` nodes.
+    pub fn new(replicas: usize) -> Self {
+        Self {
+            replicas,
+            vnodes: DEFAULT_VNODES,
+            nodes: BTreeMap::new(),
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// Puts every node at `vnodes` points. More points spread objects more
+    /// evenly, at the cost of memory.
+    pub fn with_vnodes(mut self, vnodes: usize) -> Self {
+        self.vnodes = vnodes;
+        self.points.clear();
+        let names: Vec<String> = self.nodes.keys().cloned().collect();
+        for name in names {
+            self.insert_points(&name);
+        }
+        self
+    }
+
+    /// Adds `node`, named `name`, replacing the node of the same name.
+    pub fn add(&mut self, name: impl Into<String>, node: N) -> Option<N> {
+        let name = name.into();
+        self.insert_points(&name);
+        self.nodes.insert(name, node)
+    }
+
+    /// Removes the node named `name`.
+    pub fn remove(&mut self, name: &str) -> Option<N> {
+        let node = self.nodes.remove(name)?;
+        self.points.retain(|_, point| point != name);
+        Some(node)
+    }
+
+    /// Returns the node named `name`.
+    pub fn get(&self, name: &str) -> Option<&N> {
+        self.nodes.get(name)
+    }
+
+    /// Returns how many nodes are on the ring.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns true if there are no nodes on the ring.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Returns the nodes `value` is placed on, primary first. There are
+    /// fewer than the replication factor if there are fewer nodes.
+    pub fn replicas<V: WireFormat>(&self, value: &V) -> Vec<&N> {
+        self.names(value)
+            .into_iter()
+            .map(|name| &self.nodes[name])
+            .collect()
+    }
+
+    /// Returns the names of the nodes `value` is placed on, primary first.
+    pub fn names<V: WireFormat>(&self, value: &V) -> Vec<&str> {
+        let want = self.replicas.min(self.nodes.len());
+        let start = StableHasher::new().value(value).finish();
+        let mut names: Vec<&str> = Vec::with_capacity(want);
+        for name in self
+            .points
+            .range(start..)
+            .chain(self.points.range(..start))
+            .map(|(_, name)| name.as_str())
+        {
+            if names.len() == want {
+                break;
+            }
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    fn insert_points(&mut self, name: &str) {
+        for vnode in 0..self.vnodes {
+            let point = StableHasher::new().name(name).value(&(vnode as u64)).finish();
+            self.points.insert(point, name.to_string());
+        }
+    }
+}
+
+impl<N> Placement for Ring<N>
+where
+    N: IntoNode + Clone,
+{
+    fn map<V>(&self, value: &V) -> Vec<impl IntoNode>
+    where
+        V: WireFormat + Ord + Eq,
+    {
+        self.replicas(value).into_iter().cloned().collect::<Vec<N>>()
+    }
+
+    fn locate<V>(&self, value: &V) -> Vec<impl IntoNode>
+    where
+        V: WireFormat + Ord + Eq,
+    {
+        self.map(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ring(nodes: usize) -> Ring<usize> {
+        let mut ring = Ring::new(3);
+        for node in 0..nodes {
+            ring.add(format!("node-{}", node), node);
+        }
+        ring
+    }
+
+    #[test]
+    fn test_replicas_are_distinct() {
+        let ring = ring(5);
+        for key in 0..100 {
+            let mut replicas = ring.replicas(&key);
+            assert_eq!(replicas.len(), 3);
+            replicas.sort();
+            replicas.dedup();
+            assert_eq!(replicas.len(), 3);
+        }
+        assert_eq!(self::ring(2).replicas(&"key".to_string()).len(), 2);
+        assert!(self::ring(0).replicas(&"key".to_string()).is_empty());
+    }
+
+    #[test]
+    fn test_objects_are_spread_evenly() {
+        let ring = ring(10);
+        let mut counts = [0; 10];
+        for key in 0..10_000 {
+            counts[*ring.replicas(&key)[0]] += 1;
+        }
+        for count in counts {
+            assert!((500..1_500).contains(&count), "{:?}", counts);
+        }
+    }
+
+    #[test]
+    fn test_few_objects_move() {
+        let before = ring(10);
+        let mut after = before.clone();
+        after.add("node-10", 10);
+
+        let mut moved = 0;
+        for key in 0..10_000 {
+            let (old, new) = (before.replicas(&key), after.replicas(&key));
+            if old[0] != new[0] {
+                // Only to the new node.
+                assert_eq!(*new[0], 10);
+                moved += 1;
+            }
+        }
+        assert!((500..1_500).contains(&moved), "{} moved", moved);
+
+        after.remove("node-10");
+        for key in 0..1_000 {
+            assert_eq!(before.replicas(&key), after.replicas(&key));
+        }
+    }
+}
@@ -26,9 +26,16 @@ pub fn jetstream_wire_format(input: TokenStream) -> TokenStream {
 }
 
 /// Service attribute macro for creating RPC services
+///
+/// Takes `async_trait` to make the trait an `#[async_trait]`, and
+/// `crate = path` to find what the generated code uses somewhere other than
+/// `jetstream::prelude`, e.g. `#[service(crate = jetstream_rpc)]` in crates
+/// that can't depend on `jetstream`.
 #[proc_macro_attribute]
 pub fn service(attr: TokenStream, item: TokenStream) -> TokenStream {
-    let is_async_trait = !attr.is_empty() && attr.to_string().contains("async_trait");
+    let mut args = service::ServiceArgs::default();
+    let parser = syn::meta::parser(|meta| args.parse(meta));
+    parse_macro_input!(attr with parser);
     let item = parse_macro_input!(item as syn::ItemTrait);
-    service::service_impl(item, is_async_trait).into()
+    service::service_impl(item, &args).into()
 }
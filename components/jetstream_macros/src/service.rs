@@ -66,17 +66,22 @@ enum Direction {
 fn generate_frame(
     direction: Direction,
     msgs: &[(Ident, proc_macro2::TokenStream)],
+    idempotent: &[bool],
+    resources: &[Option<Box<syn::Pat>>],
 ) -> proc_macro2::TokenStream {
-    let enum_name = match direction {
-        Direction::Rx => quote! { Rmessage },
-        Direction::Tx => quote! { Tmessage },
+    let (enum_name, kind) = match direction {
+        Direction::Rx => (quote! { Rmessage }, "response"),
+        Direction::Tx => (quote! { Tmessage }, "request"),
     };
+    let enum_doc = format!("The {}s of this protocol.", kind);
 
     let msg_variants = msgs.iter().map(|(ident, _p)| {
         let name: IdentCased = ident.into();
         let variant_name: Ident = name.remove_prefix().to_pascale_case().into();
         let constant_name: Ident = name.to_screaming_snake_case().into();
+        let doc = format!("A `{}` {}.", name.remove_prefix().0, kind);
         quote! {
+            #[doc = #doc]
             #variant_name(#ident) = #constant_name,
         }
     });
@@ -112,11 +117,102 @@ fn generate_frame(
         }
     });
 
+    let name_arms = msgs.iter().map(|(ident, _)| {
+        let name: IdentCased = ident.into();
+        let method_name = name.remove_prefix().0.to_string();
+        let variant_name: Ident = name.remove_prefix().to_pascale_case().into();
+        quote! {
+            #enum_name::#variant_name(_) => #method_name,
+        }
+    });
+
+    // Responses can also be an error frame sent in place of the result.
+    let (error_variant, error_byte_size, error_encode, error_decode, error_name, error_status) =
+        match direction {
+            Direction::Rx => (
+                quote! {
+                    /// An error sent in place of a response.
+                    Error(Status) = ERROR_FRAME,
+                },
+                quote! { #enum_name::Error(status) => status.byte_size(), },
+                quote! { #enum_name::Error(status) => status.encode(writer)?, },
+                quote! { ERROR_FRAME => Ok(#enum_name::Error(WireFormat::decode(reader)?)), },
+                quote! { #enum_name::Error(_) => "error", },
+                quote! {
+                    fn status(&self) -> Option<&Status> {
+                        match &self {
+                            #enum_name::Error(status) => Some(status),
+                            _ => None,
+                        }
+                    }
+
+                    fn from_status(status: Status) -> Result<Self, Status> {
+                        Ok(#enum_name::Error(status))
+                    }
+                },
+            ),
+            Direction::Tx => (
+                quote! {},
+                quote! {},
+                quote! {},
+                quote! {},
+                quote! {},
+                quote! {},
+            ),
+        };
+
+    let (is_idempotent, resource) = match direction {
+        Direction::Rx => (quote! {}, quote! {}),
+        Direction::Tx => {
+            let idempotent_arms = std::iter::zip(msgs, idempotent).map(|((ident, _), idempotent)| {
+                let name: IdentCased = ident.into();
+                let variant_name: Ident = name.remove_prefix().to_pascale_case().into();
+                quote! {
+                    #enum_name::#variant_name(_) => #idempotent,
+                }
+            });
+            let resource_arms = std::iter::zip(msgs, resources).map(|((ident, _), resource)| {
+                let name: IdentCased = ident.into();
+                let variant_name: Ident = name.remove_prefix().to_pascale_case().into();
+                match resource {
+                    Some(field) => quote! {
+                        #enum_name::#variant_name(msg) => Some(msg.#field.to_string()),
+                    },
+                    None => quote! {
+                        #enum_name::#variant_name(_) => None,
+                    },
+                }
+            });
+            (
+                quote! {
+                    fn is_idempotent(&self) -> bool {
+                        match &self {
+                            #(
+                                #idempotent_arms
+                            )*
+                        }
+                    }
+                },
+                quote! {
+                    fn resource(&self) -> Option<String> {
+                        match &self {
+                            #(
+                                #resource_arms
+                            )*
+                        }
+                    }
+                },
+            )
+        }
+    };
+
     quote! {
+        #[doc = #enum_doc]
         #[derive(Debug)]
         #[repr(u8)]
         pub enum #enum_name {
             #( #msg_variants )*
+            #error_variant
         }
 
         impl Framer for #enum_name {
@@ -125,6 +221,7 @@ fn generate_frame(
                     #(
                         #cloned_byte_sizes,
                      )*
+                    #error_byte_size
                 }
             }
 
@@ -140,6 +237,7 @@ fn generate_frame(
                     #(
                         #encode_match_arms
                      )*
+                    #error_encode
                 }
 
                 Ok(())
@@ -150,32 +248,145 @@ fn generate_frame(
                     #(
                         #decode_bodies
                      )*
+                    #error_decode
                     _ => Err(std::io::Error::new(
                         std::io::ErrorKind::InvalidData,
                         format!("unknown message type: {}", ty),
                     )),
                 }
             }
+
+            fn message_name(&self) -> &'static str {
+                match &self {
+                    #(
+                        #name_arms
+                    )*
+                    #error_name
+                }
+            }
+
+            #is_idempotent
+
+            #resource
+
+            #error_status
         }
     }
 }
 
-fn generate_tframe(tmsgs: &[(Ident, proc_macro2::TokenStream)]) -> proc_macro2::TokenStream {
-    generate_frame(Direction::Tx, tmsgs)
+fn generate_tframe(
+    tmsgs: &[(Ident, proc_macro2::TokenStream)],
+    idempotent: &[bool],
+    resources: &[Option<Box<syn::Pat>>],
+) -> proc_macro2::TokenStream {
+    generate_frame(Direction::Tx, tmsgs, idempotent, resources)
 }
 
 fn generate_rframe(rmsgs: &[(Ident, proc_macro2::TokenStream)]) -> proc_macro2::TokenStream {
-    generate_frame(Direction::Rx, rmsgs)
+    generate_frame(Direction::Rx, rmsgs, &[], &[])
+}
+
+/// Returns true if the method is marked with `#[idempotent]`.
+fn is_idempotent(method: &syn::TraitItemFn) -> bool {
+    method
+        .attrs
+        .iter()
+        .any(|attr| attr.path().is_ident("idempotent"))
+}
+
+/// Returns the argument of the method marked with `#[resource]`, if any.
+fn resource_arg(method: &syn::TraitItemFn) -> syn::Result<Option<Box<syn::Pat>>> {
+    let mut marked = method.sig.inputs.iter().filter_map(|arg| {
+        match arg {
+            syn::FnArg::Typed(pat)
+                if pat.attrs.iter().any(|attr| attr.path().is_ident("resource")) =>
+            {
+                Some(pat)
+            }
+            _ => None,
+        }
+    });
+    let resource = marked.next().map(|pat| pat.pat.clone());
+    if let Some(extra) = marked.next() {
+        return Err(syn::Error::new_spanned(
+            extra,
+            "only one argument can be marked #[resource]",
+        ));
+    }
+    Ok(resource)
+}
+
+/// The arguments of `#[service]`.
+pub(crate) struct ServiceArgs {
+    /// Whether the trait is an `#[async_trait]` rather than a
+    /// `trait_variant` one.
+    pub(crate) is_async_trait: bool,
+    /// Where the generated code finds what it uses.
+    pub(crate) krate: syn::Path,
+}
+
+impl Default for ServiceArgs {
+    fn default() -> Self {
+        ServiceArgs {
+            is_async_trait: false,
+            krate: syn::parse_quote!(jetstream::prelude),
+        }
+    }
+}
+
+impl ServiceArgs {
+    pub(crate) fn parse(&mut self, meta: syn::meta::ParseNestedMeta) -> syn::Result<()> {
+        if meta.path.is_ident("async_trait") {
+            self.is_async_trait = true;
+            Ok(())
+        } else if meta.path.is_ident("crate") {
+            self.krate = meta.value()?.parse()?;
+            Ok(())
+        } else {
+            Err(meta.error("expected `async_trait` or `crate = path`"))
+        }
+    }
+}
+
+/// The message type of the first method's request.
+const MESSAGE_ID_START: u8 = 101;
+/// The lowest message type reserved by `jetstream_rpc`, for error frames;
+/// extended frames take the one above.
+const RESERVED_MESSAGE_IDS: u8 = 0xFE;
+
+/// Rejects traits with so many methods that their message types would run
+/// into the reserved ones.
+fn check_msg_ids(item: &ItemTrait) -> syn::Result<()> {
+    let mut methods = item.items.iter().filter_map(|item| match item {
+        TraitItem::Fn(method) => Some(method),
+        _ => None,
+    });
+    let max_methods = (RESERVED_MESSAGE_IDS - MESSAGE_ID_START) as usize / 2;
+    match methods.nth(max_methods) {
+        Some(method) => Err(syn::Error::new_spanned(
+            &method.sig.ident,
+            format!(
+                "a service can have at most {} methods, message types from {:#x} are reserved",
+                max_methods, RESERVED_MESSAGE_IDS
+            ),
+        )),
+        None => Ok(()),
+    }
 }
 
 fn generate_msg_id(index: usize, method_name: &Ident) -> proc_macro2::TokenStream {
     let upper_cased_method_name = method_name.to_string().to_uppercase();
     let tmsg_const_name = Ident::new(&format!("T{}", upper_cased_method_name), method_name.span());
     let rmsg_const_name = Ident::new(&format!("R{}", upper_cased_method_name), method_name.span());
-    let offset = 2 * index as u8;
+    // In range, see check_msg_ids.
+    let offset = (2 * index) as u8;
+    let tmsg_doc = format!("The message type of `{}` requests.", method_name);
+    let rmsg_doc = format!("The message type of `{}` responses.", method_name);
 
     quote! {
+        #[doc = #tmsg_doc]
         pub const #tmsg_const_name: u8 = MESSAGE_ID_START + #offset;
+        #[doc = #rmsg_doc]
         pub const #rmsg_const_name: u8 = MESSAGE_ID_START + #offset + 1;
     }
 }
@@ -189,15 +400,19 @@ fn generate_input_struct(
             syn::FnArg::Typed(pat) => {
                 let name = pat.pat.clone();
                 let ty = pat.ty.clone();
+                let doc = format!("The `{}` argument.", name.to_token_stream());
                 quote! {
+                    #[doc = #doc]
                     pub #name: #ty,
                 }
             }
             syn::FnArg::Receiver(_) => quote! {},
         }
     });
+    let doc = format!("The request of `{}`.", method_sig.ident);
 
     quote! {
+        #[doc = #doc]
         #[allow(non_camel_case_types)]
         #[derive(Debug, JetStreamWireFormat)]
         pub struct #request_struct_ident {
@@ -209,6 +424,7 @@ fn generate_return_struct(
     return_struct_ident: &Ident,
     method_sig: &syn::Signature,
 ) -> proc_macro2::TokenStream {
+    let doc = format!("The response of `{}`.", method_sig.ident);
     match &method_sig.output {
         syn::ReturnType::Type(_, ty) => {
             match &**ty {
@@ -222,6 +438,7 @@ fn generate_return_struct(
                                     args.args.first()
                                 {
                                     return quote! {
+                                        #[doc = #doc]
                                         #[allow(non_camel_case_types)]
                                         #[derive(Debug, JetStreamWireFormat)]
                                         pub struct #return_struct_ident(pub #success_type);
@@ -232,6 +449,7 @@ fn generate_return_struct(
                     }
                     // If not a Result or couldn't extract type, use the whole type
                     quote! {
+                        #[doc = #doc]
                         #[allow(non_camel_case_types)]
                         #[derive(Debug, JetStreamWireFormat)]
                         pub struct #return_struct_ident(pub #ty);
@@ -240,6 +458,7 @@ fn generate_return_struct(
                 // Handle other return type variants if needed
                 _ => {
                     quote! {
+                        #[doc = #doc]
                         #[allow(non_camel_case_types)]
                         #[derive(Debug, JetStreamWireFormat)]
                         pub struct #return_struct_ident(pub #ty);
@@ -249,6 +468,7 @@ fn generate_return_struct(
         }
         syn::ReturnType::Default => {
             quote! {
+               #[doc = #doc]
                #[allow(non_camel_case_types)]
                #[derive(Debug, JetStreamWireFormat)]
                pub struct #return_struct_ident;
@@ -257,6 +477,68 @@ fn generate_return_struct(
     }
 }
 
+/// Returns the type a method responds with, `T` for `Result<T, E>`.
+fn success_type(method_sig: &syn::Signature) -> proc_macro2::TokenStream {
+    match &method_sig.output {
+        syn::ReturnType::Type(_, ty) => {
+            if let syn::Type::Path(type_path) = &**ty {
+                if let Some(segment) = type_path.path.segments.last() {
+                    if segment.ident == "Result" {
+                        if let syn::PathArguments::AngleBracketed(args) = &segment.arguments {
+                            if let Some(syn::GenericArgument::Type(success_type)) =
+                                args.args.first()
+                            {
+                                return quote! { #success_type };
+                            }
+                        }
+                    }
+                }
+            }
+            quote! { #ty }
+        }
+        syn::ReturnType::Default => quote! { () },
+    }
+}
+
+fn generate_route(
+    messages_name: &Ident,
+    request_struct_ident: &Ident,
+    return_struct_ident: &Ident,
+    method_sig: &syn::Signature,
+) -> proc_macro2::TokenStream {
+    let method_name = &method_sig.ident;
+    let variant_name: Ident = IdentCased(method_name.clone()).to_pascale_case().into();
+    let const_name = Ident::new(
+        &format!("T{}", method_name.to_string().to_uppercase()),
+        method_name.span(),
+    );
+    let response = success_type(method_sig);
+    let into_response = match &method_sig.output {
+        syn::ReturnType::Type(..) => quote! { Rmessage::#variant_name(#return_struct_ident(response)) },
+        syn::ReturnType::Default => quote! { Rmessage::#variant_name(#return_struct_ident) },
+    };
+    quote! {
+        #[cfg(not(target_arch = "wasm32"))]
+        impl Route<#messages_name> for #request_struct_ident {
+            type Response = #response;
+            const MESSAGE_TYPE: u8 = #const_name;
+
+            fn from_request(request: Tmessage) -> Option<Self> {
+                match request {
+                    Tmessage::#variant_name(msg) => Some(msg),
+                    #[allow(unreachable_patterns)]
+                    _ => None,
+                }
+            }
+
+            #[allow(unused_variables)]
+            fn into_response(response: Self::Response) -> Rmessage {
+                #into_response
+            }
+        }
+    }
+}
+
 fn generate_match_arms(
     tmsgs: impl Iterator<Item = (Ident, proc_macro2::TokenStream)>,
 ) -> impl Iterator<Item = proc_macro2::TokenStream> {
@@ -278,10 +560,55 @@ fn handle_receiver(recv: &syn::Receiver) -> proc_macro2::TokenStream {
         (None, _) => quote! { self.inner, },
     }
 }
-pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream {
+pub(crate) fn service_impl(item: ItemTrait, args: &ServiceArgs) -> TokenStream {
+    let krate = &args.krate;
     let trait_name = &item.ident;
-    let trait_items = &item.items;
     let vis = &item.vis;
+    let idempotent: Vec<bool> = item
+        .items
+        .iter()
+        .filter_map(|item| {
+            match item {
+                TraitItem::Fn(method) => Some(is_idempotent(method)),
+                _ => None,
+            }
+        })
+        .collect();
+    let resources: Vec<Option<Box<syn::Pat>>> = match item
+        .items
+        .iter()
+        .filter_map(|item| {
+            match item {
+                TraitItem::Fn(method) => Some(resource_arg(method)),
+                _ => None,
+            }
+        })
+        .collect()
+    {
+        Ok(resources) => resources,
+        Err(err) => return err.to_compile_error(),
+    };
+    if let Err(err) = check_msg_ids(&item) {
+        return err.to_compile_error();
+    }
+    // `#[idempotent]` and `#[resource]` are only meaningful to this macro, so strip them from
+    // the emitted trait, and from what the protocol version is derived from, as they don't
+    // change the wire format.
+    let mut stripped = item.clone();
+    for item in stripped.items.iter_mut() {
+        if let TraitItem::Fn(method) = item {
+            method
+                .attrs
+                .retain(|attr| !attr.path().is_ident("idempotent"));
+            for arg in method.sig.inputs.iter_mut() {
+                if let syn::FnArg::Typed(pat) = arg {
+                    pat.attrs.retain(|attr| !attr.path().is_ident("resource"));
+                }
+            }
+        }
+    }
+    let trait_items = &stripped.items;
+    let trait_attrs = &stripped.attrs;
 
     // Generate message structs and enum variants
     // let mut message_structs = Vec::new();
@@ -290,7 +617,7 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
     let mut msg_ids = Vec::new();
     let service_name = format_ident!("{}Service", trait_name);
     let channel_name = format_ident!("{}Channel", trait_name);
-    let digest = sha256::digest(item.to_token_stream().to_string());
+    let digest = sha256::digest(stripped.to_token_stream().to_string());
 
     #[allow(clippy::to_string_in_format_args)]
     let protocol_version = format!(
@@ -302,6 +629,7 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
         digest[0..8].to_string()
     );
     let protocol_version = Literal::string(protocol_version.as_str());
+    let message_id_start = Literal::u8_unsuffixed(MESSAGE_ID_START);
     let mut calls = vec![];
     let tag_name = format_ident!("{}_TAG", trait_name.to_string().to_uppercase());
 
@@ -407,13 +735,23 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
                     syn::FnArg::Receiver(_) => quote! {},
                 }
             });
-            let inputs_clone = inputs.clone();
+            let fields = method.sig.inputs.iter().map(|arg| {
+                match arg {
+                    syn::FnArg::Typed(pat) => {
+                        let name = pat.pat.clone();
+                        quote! {
+                             #name,
+                        }
+                    }
+                    syn::FnArg::Receiver(_) => quote! {},
+                }
+            });
             let new = quote! {
                 #maybe_async fn #method_name(&mut self, #(#inputs)*)  #retn {
                     let tag =#tag_name.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
                     let req = Tmessage::#variant_name(#request_struct_ident {
                         #(
-                            #inputs_clone
+                            #fields
                         )*
                     });
                     let tframe= Frame::from((tag, req));
@@ -421,6 +759,8 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
                     let rmsg = rframe.msg;
                     match rmsg {
                         Rmessage::#variant_name(msg) => Ok(msg.0),
+                        #[allow(unreachable_patterns)]
+                        _ => Err(Error::Custom("unexpected response".to_string()).into()),
                     }
                 }
             };
@@ -442,7 +782,25 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
             #def
         }
     });
-    let tmessage = generate_tframe(&tmsgs);
+    let service_str = Literal::string(&trait_name.to_string());
+    let messages_name = format_ident!("{}Messages", trait_name);
+    let routes = item
+        .items
+        .iter()
+        .enumerate()
+        .filter_map(|(index, item)| {
+            let TraitItem::Fn(method) = item else {
+                return None;
+            };
+            Some(generate_route(
+                &messages_name,
+                &tmsgs[index].0,
+                &rmsgs[index].0,
+                &method.sig,
+            ))
+        })
+        .collect::<Vec<_>>();
+    let tmessage = generate_tframe(&tmsgs, &idempotent, &resources);
     let rmessage = generate_rframe(&rmsgs);
     let proto_mod = format_ident!("{}_protocol", trait_name.to_string().to_lowercase());
 
@@ -490,18 +848,29 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
         }
     });
 
-    let trait_attribute = if is_async_trait {
-        quote! { #[jetstream::prelude::async_trait] }
+    let trait_attribute = if args.is_async_trait {
+        quote! { #[#krate::async_trait] }
     } else {
-        quote! { #[jetstream::prelude::trait_variant::make(Send + Sync)] }
+        quote! { #[#krate::trait_variant::make(Send + Sync)] }
     };
+    let mod_doc = format!("The protocol of [`{}`].", trait_name);
+    let service_doc = format!("Serves a [`{}`].", trait_name);
+    let channel_doc = format!("Calls a [`{}`] over a transport.", trait_name);
     quote! {
+        #[doc = #mod_doc]
         #vis mod #proto_mod{
-            use jetstream::prelude::*;
+            // Named rather than globbed, so they win over the names of the
+            // trait's module that argument and return types may use.
+            use super::*;
+            use #krate::{ClientTransport, Error, Frame, Framer, JetStreamWireFormat, Protocol, Status, WireFormat, ERROR_FRAME};
+            #[cfg(not(target_arch = "wasm32"))]
+            use #krate::{ClientTransportExt, Deadline, Messages, Route};
+            #[cfg(target_arch = "wasm32")]
+            use #krate::Metadata;
             use std::io::{self,Read,Write};
             use std::mem;
-            use super::#trait_name;
-            const MESSAGE_ID_START: u8 = 101;
+            const MESSAGE_ID_START: u8 = #message_id_start;
+            /// The version of this protocol, which changes with the trait.
             pub const PROTOCOL_VERSION: &str = #protocol_version;
             const DIGEST: &str = #digest;
 
@@ -515,8 +884,23 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
 
             #rmessage
 
+            /// The messages of this protocol, for serving it with a [`Router`].
+            pub struct #messages_name;
+
+            #[cfg(not(target_arch = "wasm32"))]
+            impl Messages for #messages_name {
+                type Request = Tmessage;
+                type Response = Rmessage;
+                const VERSION: &'static str = PROTOCOL_VERSION;
+                const NAME: &'static str = #service_str;
+            }
+
+            #(#routes)*
+
+            #[doc = #service_doc]
             #[derive(Clone)]
             pub struct #service_name<T: #trait_name> {
+                /// The implementation requests are handled by.
                 pub inner: T,
             }
 
@@ -528,23 +912,47 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
                 type Response = Rmessage;
                 type Error = Error;
                 const VERSION: &'static str = PROTOCOL_VERSION;
+                const NAME: &'static str = #service_str;
 
                 fn rpc(&mut self, frame: Frame<<Self as Protocol>::Request>) -> impl ::core::future::Future<
                     Output = Result<Frame<<Self as Protocol>::Response>, Self::Error>,
                 > + Send + Sync {
                     Box::pin(async move {
                         let req: <Self as Protocol>::Request = frame.msg;
-                        let res: Result<<Self as Protocol>::Response, Self::Error> =match req {
+                        let handle = async {
+                            match req {
                                 #(
                                     #matches
                                 )*
+                            }
+                        };
+                        // Deadlines and metadata are task locals, which wasm doesn't have.
+                        #[cfg(not(target_arch = "wasm32"))]
+                        let handle = frame.header.metadata.scope(handle);
+                        #[cfg(not(target_arch = "wasm32"))]
+                        let (res, metadata) = match frame.header.timeout {
+                            Some(timeout) => Deadline::after(timeout).scope(handle).await,
+                            None => handle.await,
                         };
-                        let rframe: Frame<<Self as Protocol>::Response> = Frame::from((frame.tag, res?));
+                        #[cfg(target_arch = "wasm32")]
+                        let (res, metadata) = (handle.await, Metadata::new());
+                        let res: Result<<Self as Protocol>::Response, Self::Error> = res;
+                        let msg = match res {
+                            Ok(msg) => msg,
+                            // Refusals go back to the caller, rather than ending the connection.
+                            Err(Error::Status(status)) => return Frame::error(frame.tag, status).map_err(Error::from),
+                            Err(err) => return Err(err),
+                        };
+                        let mut rframe: Frame<<Self as Protocol>::Response> = Frame::from((frame.tag, msg));
+                        // What the handler added with `Metadata::add_to_response`.
+                        rframe.header.metadata = metadata;
                         Ok(rframe)
                     })
                 }
             }
+            #[doc = #channel_doc]
             pub struct #channel_name<'a> {
+                /// The transport calls are made over.
                 pub inner: Box<&'a mut dyn ClientTransport<Self>>,
             }
             impl<'a> Protocol for #channel_name<'a>
@@ -553,6 +961,14 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
                 type Response = Rmessage;
                 type Error = Error;
                 const VERSION: &'static str = PROTOCOL_VERSION;
+                const NAME: &'static str = #service_str;
+                #[cfg(not(target_arch = "wasm32"))]
+                fn rpc(&mut self, frame: Frame<<Self as Protocol>::Request>) -> impl ::core::future::Future<
+                    Output = Result<Frame<<Self as Protocol>::Response>, Self::Error>,
+                > + Send + Sync {
+                    Box::pin(ClientTransportExt::<Self>::call(&mut **self.inner, frame))
+                }
+                #[cfg(target_arch = "wasm32")]
                 fn rpc(&mut self, frame: Frame<<Self as Protocol>::Request>) -> impl ::core::future::Future<
                     Output = Result<Frame<<Self as Protocol>::Response>, Self::Error>,
                 > + Send + Sync {
@@ -576,6 +992,7 @@ pub(crate) fn service_impl(item: ItemTrait, is_async_trait: bool) -> TokenStream
 
         }
 
+        #(#trait_attrs)*
         #trait_attribute
         #vis trait #trait_name {
             #(#trait_items)*
@@ -617,30 +1034,45 @@ mod tests {
                 async fn ping(&self) -> Result<(), std::io::Error>;
             }
         };
-        let output = service_impl(input, false);
+        let output = service_impl(input, &ServiceArgs::default());
         let syntax_tree: syn::File = syn::parse2(output).unwrap();
         let output_str = prettyplease::unparse(&syntax_tree);
         run_test_with_filters(|| {
             insta::assert_snapshot!(output_str, @r###"
+            ///The protocol of [`Echo`].
             pub mod echo_protocol {
-                use jetstream::prelude::*;
+                use super::*;
+                use jetstream::prelude::{
+                    ClientTransport, Error, Frame, Framer, JetStreamWireFormat, Protocol, Status,
+                    WireFormat, ERROR_FRAME,
+                };
+                #[cfg(not(target_arch = "wasm32"))]
+                use jetstream::prelude::{ClientTransportExt, Deadline, Messages, Route};
+                #[cfg(target_arch = "wasm32")]
+                use jetstream::prelude::Metadata;
                 use std::io::{self, Read, Write};
                 use std::mem;
-                use super::Echo;
                 const MESSAGE_ID_START: u8 = 101;
+                /// The version of this protocol, which changes with the trait.
                 pub const PROTOCOL_VERSION: &str = "dev.branch.jetstream.proto/NAME/VERSION-HASH";
                 const DIGEST: &str = "DIGEST_HASH";
+                ///The message type of `ping` requests.
                 pub const TPING: u8 = MESSAGE_ID_START + 0u8;
+                ///The message type of `ping` responses.
                 pub const RPING: u8 = MESSAGE_ID_START + 0u8 + 1;
+                ///The request of `ping`.
                 #[allow(non_camel_case_types)]
                 #[derive(Debug, JetStreamWireFormat)]
                 pub struct Tping {}
+                ///The response of `ping`.
                 #[allow(non_camel_case_types)]
                 #[derive(Debug, JetStreamWireFormat)]
                 pub struct Rping(pub ());
+                ///The requests of this protocol.
                 #[derive(Debug)]
                 #[repr(u8)]
                 pub enum Tmessage {
+                    ///A `ping` request.
                     Ping(Tping) = TPING,
                 }
                 impl Framer for Tmessage {
@@ -671,16 +1103,36 @@ mod tests {
                             }
                         }
                     }
+                    fn message_name(&self) -> &'static str {
+                        match &self {
+                            Tmessage::Ping(_) => "ping",
+                        }
+                    }
+                    fn is_idempotent(&self) -> bool {
+                        match &self {
+                            Tmessage::Ping(_) => false,
+                        }
+                    }
+                    fn resource(&self) -> Option<String> {
+                        match &self {
+                            Tmessage::Ping(_) => None,
+                        }
+                    }
                 }
+                ///The responses of this protocol.
                 #[derive(Debug)]
                 #[repr(u8)]
                 pub enum Rmessage {
+                    ///A `ping` response.
                     Ping(Rping) = RPING,
+                    /// An error sent in place of a response.
+                    Error(Status) = ERROR_FRAME,
                 }
                 impl Framer for Rmessage {
                     fn byte_size(&self) -> u32 {
                         match &self {
                             Rmessage::Ping(msg) => msg.byte_size(),
+                            Rmessage::Error(status) => status.byte_size(),
                         }
                     }
                     fn message_type(&self) -> u8 {
@@ -689,12 +1141,14 @@ mod tests {
                     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
                         match &self {
                             Rmessage::Ping(msg) => msg.encode(writer)?,
+                            Rmessage::Error(status) => status.encode(writer)?,
                         }
                         Ok(())
                     }
                     fn decode<R: Read>(reader: &mut R, ty: u8) -> io::Result<Rmessage> {
                         match ty {
                             RPING => Ok(Rmessage::Ping(WireFormat::decode(reader)?)),
+                            ERROR_FRAME => Ok(Rmessage::Error(WireFormat::decode(reader)?)),
                             _ => {
                                 Err(
                                     std::io::Error::new(
@@ -705,9 +1159,51 @@ mod tests {
                             }
                         }
                     }
+                    fn message_name(&self) -> &'static str {
+                        match &self {
+                            Rmessage::Ping(_) => "ping",
+                            Rmessage::Error(_) => "error",
+                        }
+                    }
+                    fn status(&self) -> Option<&Status> {
+                        match &self {
+                            Rmessage::Error(status) => Some(status),
+                            _ => None,
+                        }
+                    }
+                    fn from_status(status: Status) -> Result<Self, Status> {
+                        Ok(Rmessage::Error(status))
+                    }
                 }
+                /// The messages of this protocol, for serving it with a [`Router`].
+                pub struct EchoMessages;
+                #[cfg(not(target_arch = "wasm32"))]
+                impl Messages for EchoMessages {
+                    type Request = Tmessage;
+                    type Response = Rmessage;
+                    const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                impl Route<EchoMessages> for Tping {
+                    type Response = ();
+                    const MESSAGE_TYPE: u8 = TPING;
+                    fn from_request(request: Tmessage) -> Option<Self> {
+                        match request {
+                            Tmessage::Ping(msg) => Some(msg),
+                            #[allow(unreachable_patterns)]
+                            _ => None,
+                        }
+                    }
+                    #[allow(unused_variables)]
+                    fn into_response(response: Self::Response) -> Rmessage {
+                        Rmessage::Ping(Rping(response))
+                    }
+                }
+                ///Serves a [`Echo`].
                 #[derive(Clone)]
                 pub struct EchoService<T: Echo> {
+                    /// The implementation requests are handled by.
                     pub inner: T,
                 }
                 impl<T> Protocol for EchoService<T>
@@ -718,6 +1214,7 @@ mod tests {
                     type Response = Rmessage;
                     type Error = Error;
                     const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
                     fn rpc(
                         &mut self,
                         frame: Frame<<Self as Protocol>::Request>,
@@ -726,22 +1223,44 @@ mod tests {
                     > + Send + Sync {
                         Box::pin(async move {
                             let req: <Self as Protocol>::Request = frame.msg;
-                            let res: Result<<Self as Protocol>::Response, Self::Error> = match req {
-                                Tmessage::Ping(msg) => {
-                                    let msg = Echo::ping(&self.inner).await?;
-                                    let ret = Rping(msg);
-                                    Ok(Rmessage::Ping(ret))
+                            let handle = async {
+                                match req {
+                                    Tmessage::Ping(msg) => {
+                                        let msg = Echo::ping(&self.inner).await?;
+                                        let ret = Rping(msg);
+                                        Ok(Rmessage::Ping(ret))
+                                    }
                                 }
                             };
-                            let rframe: Frame<<Self as Protocol>::Response> = Frame::from((
+                            #[cfg(not(target_arch = "wasm32"))]
+                            let handle = frame.header.metadata.scope(handle);
+                            #[cfg(not(target_arch = "wasm32"))]
+                            let (res, metadata) = match frame.header.timeout {
+                                Some(timeout) => Deadline::after(timeout).scope(handle).await,
+                                None => handle.await,
+                            };
+                            #[cfg(target_arch = "wasm32")]
+                            let (res, metadata) = (handle.await, Metadata::new());
+                            let res: Result<<Self as Protocol>::Response, Self::Error> = res;
+                            let msg = match res {
+                                Ok(msg) => msg,
+                                Err(Error::Status(status)) => {
+                                    return Frame::error(frame.tag, status).map_err(Error::from);
+                                }
+                                Err(err) => return Err(err),
+                            };
+                            let mut rframe: Frame<<Self as Protocol>::Response> = Frame::from((
                                 frame.tag,
-                                res?,
+                                msg,
                             ));
+                            rframe.header.metadata = metadata;
                             Ok(rframe)
                         })
                     }
                 }
+                ///Calls a [`Echo`] over a transport.
                 pub struct EchoChannel<'a> {
+                    /// The transport calls are made over.
                     pub inner: Box<&'a mut dyn ClientTransport<Self>>,
                 }
                 impl<'a> Protocol for EchoChannel<'a> {
@@ -749,6 +1268,17 @@ mod tests {
                     type Response = Rmessage;
                     type Error = Error;
                     const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
+                    #[cfg(not(target_arch = "wasm32"))]
+                    fn rpc(
+                        &mut self,
+                        frame: Frame<<Self as Protocol>::Request>,
+                    ) -> impl ::core::future::Future<
+                        Output = Result<Frame<<Self as Protocol>::Response>, Self::Error>,
+                    > + Send + Sync {
+                        Box::pin(ClientTransportExt::<Self>::call(&mut **self.inner, frame))
+                    }
+                    #[cfg(target_arch = "wasm32")]
                     fn rpc(
                         &mut self,
                         frame: Frame<<Self as Protocol>::Request>,
@@ -776,6 +1306,8 @@ mod tests {
                         let rmsg = rframe.msg;
                         match rmsg {
                             Rmessage::Ping(msg) => Ok(msg.0),
+                            #[allow(unreachable_patterns)]
+                            _ => Err(Error::Custom("unexpected response".to_string()).into()),
                         }
                     }
                 }
@@ -795,32 +1327,48 @@ mod tests {
                 async fn ping(&self, message: String) -> Result<String, std::io::Error>;
             }
         };
-        let output = service_impl(input, false);
+        let output = service_impl(input, &ServiceArgs::default());
         let syntax_tree: syn::File = syn::parse2(output).unwrap();
         let output_str = prettyplease::unparse(&syntax_tree);
         run_test_with_filters(|| {
             insta::assert_snapshot!(output_str, @r###"
+            ///The protocol of [`Echo`].
             pub mod echo_protocol {
-                use jetstream::prelude::*;
+                use super::*;
+                use jetstream::prelude::{
+                    ClientTransport, Error, Frame, Framer, JetStreamWireFormat, Protocol, Status,
+                    WireFormat, ERROR_FRAME,
+                };
+                #[cfg(not(target_arch = "wasm32"))]
+                use jetstream::prelude::{ClientTransportExt, Deadline, Messages, Route};
+                #[cfg(target_arch = "wasm32")]
+                use jetstream::prelude::Metadata;
                 use std::io::{self, Read, Write};
                 use std::mem;
-                use super::Echo;
                 const MESSAGE_ID_START: u8 = 101;
+                /// The version of this protocol, which changes with the trait.
                 pub const PROTOCOL_VERSION: &str = "dev.branch.jetstream.proto/NAME/VERSION-HASH";
                 const DIGEST: &str = "DIGEST_HASH";
+                ///The message type of `ping` requests.
                 pub const TPING: u8 = MESSAGE_ID_START + 0u8;
+                ///The message type of `ping` responses.
                 pub const RPING: u8 = MESSAGE_ID_START + 0u8 + 1;
+                ///The request of `ping`.
                 #[allow(non_camel_case_types)]
                 #[derive(Debug, JetStreamWireFormat)]
                 pub struct Tping {
+                    ///The `message` argument.
                     pub message: String,
                 }
+                ///The response of `ping`.
                 #[allow(non_camel_case_types)]
                 #[derive(Debug, JetStreamWireFormat)]
                 pub struct Rping(pub String);
+                ///The requests of this protocol.
                 #[derive(Debug)]
                 #[repr(u8)]
                 pub enum Tmessage {
+                    ///A `ping` request.
                     Ping(Tping) = TPING,
                 }
                 impl Framer for Tmessage {
@@ -851,16 +1399,36 @@ mod tests {
                             }
                         }
                     }
+                    fn message_name(&self) -> &'static str {
+                        match &self {
+                            Tmessage::Ping(_) => "ping",
+                        }
+                    }
+                    fn is_idempotent(&self) -> bool {
+                        match &self {
+                            Tmessage::Ping(_) => false,
+                        }
+                    }
+                    fn resource(&self) -> Option<String> {
+                        match &self {
+                            Tmessage::Ping(_) => None,
+                        }
+                    }
                 }
+                ///The responses of this protocol.
                 #[derive(Debug)]
                 #[repr(u8)]
                 pub enum Rmessage {
+                    ///A `ping` response.
                     Ping(Rping) = RPING,
+                    /// An error sent in place of a response.
+                    Error(Status) = ERROR_FRAME,
                 }
                 impl Framer for Rmessage {
                     fn byte_size(&self) -> u32 {
                         match &self {
                             Rmessage::Ping(msg) => msg.byte_size(),
+                            Rmessage::Error(status) => status.byte_size(),
                         }
                     }
                     fn message_type(&self) -> u8 {
@@ -869,12 +1437,14 @@ mod tests {
                     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
                         match &self {
                             Rmessage::Ping(msg) => msg.encode(writer)?,
+                            Rmessage::Error(status) => status.encode(writer)?,
                         }
                         Ok(())
                     }
                     fn decode<R: Read>(reader: &mut R, ty: u8) -> io::Result<Rmessage> {
                         match ty {
                             RPING => Ok(Rmessage::Ping(WireFormat::decode(reader)?)),
+                            ERROR_FRAME => Ok(Rmessage::Error(WireFormat::decode(reader)?)),
                             _ => {
                                 Err(
                                     std::io::Error::new(
@@ -885,9 +1455,51 @@ mod tests {
                             }
                         }
                     }
+                    fn message_name(&self) -> &'static str {
+                        match &self {
+                            Rmessage::Ping(_) => "ping",
+                            Rmessage::Error(_) => "error",
+                        }
+                    }
+                    fn status(&self) -> Option<&Status> {
+                        match &self {
+                            Rmessage::Error(status) => Some(status),
+                            _ => None,
+                        }
+                    }
+                    fn from_status(status: Status) -> Result<Self, Status> {
+                        Ok(Rmessage::Error(status))
+                    }
+                }
+                /// The messages of this protocol, for serving it with a [`Router`].
+                pub struct EchoMessages;
+                #[cfg(not(target_arch = "wasm32"))]
+                impl Messages for EchoMessages {
+                    type Request = Tmessage;
+                    type Response = Rmessage;
+                    const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
+                }
+                #[cfg(not(target_arch = "wasm32"))]
+                impl Route<EchoMessages> for Tping {
+                    type Response = String;
+                    const MESSAGE_TYPE: u8 = TPING;
+                    fn from_request(request: Tmessage) -> Option<Self> {
+                        match request {
+                            Tmessage::Ping(msg) => Some(msg),
+                            #[allow(unreachable_patterns)]
+                            _ => None,
+                        }
+                    }
+                    #[allow(unused_variables)]
+                    fn into_response(response: Self::Response) -> Rmessage {
+                        Rmessage::Ping(Rping(response))
+                    }
                 }
+                ///Serves a [`Echo`].
                 #[derive(Clone)]
                 pub struct EchoService<T: Echo> {
+                    /// The implementation requests are handled by.
                     pub inner: T,
                 }
                 impl<T> Protocol for EchoService<T>
@@ -898,6 +1510,7 @@ mod tests {
                     type Response = Rmessage;
                     type Error = Error;
                     const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
                     fn rpc(
                         &mut self,
                         frame: Frame<<Self as Protocol>::Request>,
@@ -906,22 +1519,44 @@ mod tests {
                     > + Send + Sync {
                         Box::pin(async move {
                             let req: <Self as Protocol>::Request = frame.msg;
-                            let res: Result<<Self as Protocol>::Response, Self::Error> = match req {
-                                Tmessage::Ping(msg) => {
-                                    let msg = Echo::ping(&self.inner, msg.message).await?;
-                                    let ret = Rping(msg);
-                                    Ok(Rmessage::Ping(ret))
+                            let handle = async {
+                                match req {
+                                    Tmessage::Ping(msg) => {
+                                        let msg = Echo::ping(&self.inner, msg.message).await?;
+                                        let ret = Rping(msg);
+                                        Ok(Rmessage::Ping(ret))
+                                    }
                                 }
                             };
-                            let rframe: Frame<<Self as Protocol>::Response> = Frame::from((
+                            #[cfg(not(target_arch = "wasm32"))]
+                            let handle = frame.header.metadata.scope(handle);
+                            #[cfg(not(target_arch = "wasm32"))]
+                            let (res, metadata) = match frame.header.timeout {
+                                Some(timeout) => Deadline::after(timeout).scope(handle).await,
+                                None => handle.await,
+                            };
+                            #[cfg(target_arch = "wasm32")]
+                            let (res, metadata) = (handle.await, Metadata::new());
+                            let res: Result<<Self as Protocol>::Response, Self::Error> = res;
+                            let msg = match res {
+                                Ok(msg) => msg,
+                                Err(Error::Status(status)) => {
+                                    return Frame::error(frame.tag, status).map_err(Error::from);
+                                }
+                                Err(err) => return Err(err),
+                            };
+                            let mut rframe: Frame<<Self as Protocol>::Response> = Frame::from((
                                 frame.tag,
-                                res?,
+                                msg,
                             ));
+                            rframe.header.metadata = metadata;
                             Ok(rframe)
                         })
                     }
                 }
+                ///Calls a [`Echo`] over a transport.
                 pub struct EchoChannel<'a> {
+                    /// The transport calls are made over.
                     pub inner: Box<&'a mut dyn ClientTransport<Self>>,
                 }
                 impl<'a> Protocol for EchoChannel<'a> {
@@ -929,6 +1564,17 @@ mod tests {
                     type Response = Rmessage;
                     type Error = Error;
                     const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
+                    #[cfg(not(target_arch = "wasm32"))]
+                    fn rpc(
+                        &mut self,
+                        frame: Frame<<Self as Protocol>::Request>,
+                    ) -> impl ::core::future::Future<
+                        Output = Result<Frame<<Self as Protocol>::Response>, Self::Error>,
+                    > + Send + Sync {
+                        Box::pin(ClientTransportExt::<Self>::call(&mut **self.inner, frame))
+                    }
+                    #[cfg(target_arch = "wasm32")]
                     fn rpc(
                         &mut self,
                         frame: Frame<<Self as Protocol>::Request>,
@@ -950,12 +1596,14 @@ mod tests {
                 impl<'a> Echo for EchoChannel<'a> {
                     async fn ping(&mut self, message: String) -> Result<String, std::io::Error> {
                         let tag = ECHO_TAG.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                        let req = Tmessage::Ping(Tping { message: String });
+                        let req = Tmessage::Ping(Tping { message });
                         let tframe = Frame::from((tag, req));
                         let rframe = self.rpc(tframe).await?;
                         let rmsg = rframe.msg;
                         match rmsg {
                             Rmessage::Ping(msg) => Ok(msg.0),
+                            #[allow(unreachable_patterns)]
+                            _ => Err(Error::Custom("unexpected response".to_string()).into()),
                         }
                     }
                 }
@@ -975,32 +1623,54 @@ mod tests {
                 async fn ping(&mut self, message: String) -> Result<String, std::io::Error>;
             }
         };
-        let output = service_impl(input, true);
+        let output = service_impl(
+            input,
+            &ServiceArgs {
+                is_async_trait: true,
+                ..Default::default()
+            },
+        );
         let syntax_tree: syn::File = syn::parse2(output).unwrap();
         let output_str = prettyplease::unparse(&syntax_tree);
         run_test_with_filters(|| {
             insta::assert_snapshot!(output_str, @r###"
+            ///The protocol of [`Echo`].
             pub mod echo_protocol {
-                use jetstream::prelude::*;
+                use super::*;
+                use jetstream::prelude::{
+                    ClientTransport, Error, Frame, Framer, JetStreamWireFormat, Protocol, Status,
+                    WireFormat, ERROR_FRAME,
+                };
+                #[cfg(not(target_arch = "wasm32"))]
+                use jetstream::prelude::{ClientTransportExt, Deadline, Messages, Route};
+                #[cfg(target_arch = "wasm32")]
+                use jetstream::prelude::Metadata;
                 use std::io::{self, Read, Write};
                 use std::mem;
-                use super::Echo;
                 const MESSAGE_ID_START: u8 = 101;
+                /// The version of this protocol, which changes with the trait.
                 pub const PROTOCOL_VERSION: &str = "dev.branch.jetstream.proto/NAME/VERSION-HASH";
                 const DIGEST: &str = "DIGEST_HASH";
+                ///The message type of `ping` requests.
                 pub const TPING: u8 = MESSAGE_ID_START + 0u8;
+                ///The message type of `ping` responses.
                 pub const RPING: u8 = MESSAGE_ID_START + 0u8 + 1;
+                ///The request of `ping`.
                 #[allow(non_camel_case_types)]
                 #[derive(Debug, JetStreamWireFormat)]
                 pub struct Tping {
+                    ///The `message` argument.
                     pub message: String,
                 }
+                ///The response of `ping`.
                 #[allow(non_camel_case_types)]
                 #[derive(Debug, JetStreamWireFormat)]
                 pub struct Rping(pub String);
+                ///The requests of this protocol.
                 #[derive(Debug)]
                 #[repr(u8)]
                 pub enum Tmessage {
+                    ///A `ping` request.
                     Ping(Tping) = TPING,
                 }
                 impl Framer for Tmessage {
@@ -1031,16 +1701,36 @@ mod tests {
                             }
                         }
                     }
+                    fn message_name(&self) -> &'static str {
+                        match &self {
+                            Tmessage::Ping(_) => "ping",
+                        }
+                    }
+                    fn is_idempotent(&self) -> bool {
+                        match &self {
+                            Tmessage::Ping(_) => false,
+                        }
+                    }
+                    fn resource(&self) -> Option<String> {
+                        match &self {
+                            Tmessage::Ping(_) => None,
+                        }
+                    }
                 }
+                ///The responses of this protocol.
                 #[derive(Debug)]
                 #[repr(u8)]
                 pub enum Rmessage {
+                    ///A `ping` response.
                     Ping(Rping) = RPING,
+                    /// An error sent in place of a response.
+                    Error(Status) = ERROR_FRAME,
                 }
                 impl Framer for Rmessage {
                     fn byte_size(&self) -> u32 {
                         match &self {
                             Rmessage::Ping(msg) => msg.byte_size(),
+                            Rmessage::Error(status) => status.byte_size(),
                         }
                     }
                     fn message_type(&self) -> u8 {
@@ -1049,12 +1739,14 @@ mod tests {
                     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
                         match &self {
                             Rmessage::Ping(msg) => msg.encode(writer)?,
+                            Rmessage::Error(status) => status.encode(writer)?,
                         }
                         Ok(())
                     }
                     fn decode<R: Read>(reader: &mut R, ty: u8) -> io::Result<Rmessage> {
                         match ty {
                             RPING => Ok(Rmessage::Ping(WireFormat::decode(reader)?)),
+                            ERROR_FRAME => Ok(Rmessage::Error(WireFormat::decode(reader)?)),
                             _ => {
                                 Err(
                                     std::io::Error::new(
@@ -1065,9 +1757,51 @@ mod tests {
                             }
                         }
                     }
+                    fn message_name(&self) -> &'static str {
+                        match &self {
+                            Rmessage::Ping(_) => "ping",
+                            Rmessage::Error(_) => "error",
+                        }
+                    }
+                    fn status(&self) -> Option<&Status> {
+                        match &self {
+                            Rmessage::Error(status) => Some(status),
+                            _ => None,
+                        }
+                    }
+                    fn from_status(status: Status) -> Result<Self, Status> {
+                        Ok(Rmessage::Error(status))
+                    }
+                }
+                /// The messages of this protocol, for serving it with a [`Router`].
+                pub struct EchoMessages;
+                #[cfg(not(target_arch = "wasm32"))]
+                impl Messages for EchoMessages {
+                    type Request = Tmessage;
+                    type Response = Rmessage;
+                    const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
                 }
+                #[cfg(not(target_arch = "wasm32"))]
+                impl Route<EchoMessages> for Tping {
+                    type Response = String;
+                    const MESSAGE_TYPE: u8 = TPING;
+                    fn from_request(request: Tmessage) -> Option<Self> {
+                        match request {
+                            Tmessage::Ping(msg) => Some(msg),
+                            #[allow(unreachable_patterns)]
+                            _ => None,
+                        }
+                    }
+                    #[allow(unused_variables)]
+                    fn into_response(response: Self::Response) -> Rmessage {
+                        Rmessage::Ping(Rping(response))
+                    }
+                }
+                ///Serves a [`Echo`].
                 #[derive(Clone)]
                 pub struct EchoService<T: Echo> {
+                    /// The implementation requests are handled by.
                     pub inner: T,
                 }
                 impl<T> Protocol for EchoService<T>
@@ -1078,6 +1812,7 @@ mod tests {
                     type Response = Rmessage;
                     type Error = Error;
                     const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
                     fn rpc(
                         &mut self,
                         frame: Frame<<Self as Protocol>::Request>,
@@ -1086,22 +1821,44 @@ mod tests {
                     > + Send + Sync {
                         Box::pin(async move {
                             let req: <Self as Protocol>::Request = frame.msg;
-                            let res: Result<<Self as Protocol>::Response, Self::Error> = match req {
-                                Tmessage::Ping(msg) => {
-                                    let msg = Echo::ping(&mut self.inner, msg.message).await?;
-                                    let ret = Rping(msg);
-                                    Ok(Rmessage::Ping(ret))
+                            let handle = async {
+                                match req {
+                                    Tmessage::Ping(msg) => {
+                                        let msg = Echo::ping(&mut self.inner, msg.message).await?;
+                                        let ret = Rping(msg);
+                                        Ok(Rmessage::Ping(ret))
+                                    }
+                                }
+                            };
+                            #[cfg(not(target_arch = "wasm32"))]
+                            let handle = frame.header.metadata.scope(handle);
+                            #[cfg(not(target_arch = "wasm32"))]
+                            let (res, metadata) = match frame.header.timeout {
+                                Some(timeout) => Deadline::after(timeout).scope(handle).await,
+                                None => handle.await,
+                            };
+                            #[cfg(target_arch = "wasm32")]
+                            let (res, metadata) = (handle.await, Metadata::new());
+                            let res: Result<<Self as Protocol>::Response, Self::Error> = res;
+                            let msg = match res {
+                                Ok(msg) => msg,
+                                Err(Error::Status(status)) => {
+                                    return Frame::error(frame.tag, status).map_err(Error::from);
                                 }
+                                Err(err) => return Err(err),
                             };
-                            let rframe: Frame<<Self as Protocol>::Response> = Frame::from((
+                            let mut rframe: Frame<<Self as Protocol>::Response> = Frame::from((
                                 frame.tag,
-                                res?,
+                                msg,
                             ));
+                            rframe.header.metadata = metadata;
                             Ok(rframe)
                         })
                     }
                 }
+                ///Calls a [`Echo`] over a transport.
                 pub struct EchoChannel<'a> {
+                    /// The transport calls are made over.
                     pub inner: Box<&'a mut dyn ClientTransport<Self>>,
                 }
                 impl<'a> Protocol for EchoChannel<'a> {
@@ -1109,6 +1866,17 @@ mod tests {
                     type Response = Rmessage;
                     type Error = Error;
                     const VERSION: &'static str = PROTOCOL_VERSION;
+                    const NAME: &'static str = "Echo";
+                    #[cfg(not(target_arch = "wasm32"))]
+                    fn rpc(
+                        &mut self,
+                        frame: Frame<<Self as Protocol>::Request>,
+                    ) -> impl ::core::future::Future<
+                        Output = Result<Frame<<Self as Protocol>::Response>, Self::Error>,
+                    > + Send + Sync {
+                        Box::pin(ClientTransportExt::<Self>::call(&mut **self.inner, frame))
+                    }
+                    #[cfg(target_arch = "wasm32")]
                     fn rpc(
                         &mut self,
                         frame: Frame<<Self as Protocol>::Request>,
@@ -1130,12 +1898,14 @@ mod tests {
                 impl<'a> Echo for EchoChannel<'a> {
                     async fn ping(&mut self, message: String) -> Result<String, std::io::Error> {
                         let tag = ECHO_TAG.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                        let req = Tmessage::Ping(Tping { message: String });
+                        let req = Tmessage::Ping(Tping { message });
                         let tframe = Frame::from((tag, req));
                         let rframe = self.rpc(tframe).await?;
                         let rmsg = rframe.msg;
                         match rmsg {
                             Rmessage::Ping(msg) => Ok(msg.0),
+                            #[allow(unreachable_patterns)]
+                            _ => Err(Error::Custom("unexpected response".to_string()).into()),
                         }
                     }
                 }
@@ -1147,4 +1917,50 @@ mod tests {
             "###)
         })
     }
+
+    /// Returns the `PROTOCOL_VERSION` generated for `input`.
+    fn protocol_version(input: ItemTrait) -> String {
+        let output = service_impl(input, &ServiceArgs::default()).to_string();
+        let start = output.find("\"dev.branch.jetstream.proto/").unwrap() + 1;
+        let len = output[start..].find('"').unwrap();
+        output[start..start + len].to_string()
+    }
+
+    #[test]
+    fn test_helper_attributes_dont_change_the_version() {
+        let plain = protocol_version(parse_quote! {
+            pub trait Echo {
+                async fn ping(&mut self, message: String) -> Result<String, std::io::Error>;
+            }
+        });
+        let marked = protocol_version(parse_quote! {
+            pub trait Echo {
+                #[idempotent]
+                async fn ping(&mut self, #[resource] message: String) -> Result<String, std::io::Error>;
+            }
+        });
+        let changed = protocol_version(parse_quote! {
+            pub trait Echo {
+                async fn ping(&mut self, message: u64) -> Result<String, std::io::Error>;
+            }
+        });
+        assert_eq!(plain, marked);
+        assert_ne!(plain, changed);
+    }
+
+    #[test]
+    fn test_message_ids_stay_clear_of_reserved_ones() {
+        let service = |methods: usize| {
+            let methods = (0..methods).map(|index| format_ident!("method{}", index));
+            let input: ItemTrait = parse_quote! {
+                pub trait Wide {
+                    #(async fn #methods(&mut self) -> Result<(), std::io::Error>;)*
+                }
+            };
+            service_impl(input, &ServiceArgs::default()).to_string()
+        };
+        // The 76th method's response is 0xFD.
+        assert!(!service(76).contains("compile_error"));
+        assert!(service(77).contains("compile_error"));
+    }
 }
@@ -0,0 +1,78 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use {
+    crate::{
+        metrics::{CallMetrics, Side},
+        trace,
+        ClientTransport,
+        Deadline,
+        Error,
+        Frame,
+        Framer,
+        Metadata,
+        Protocol,
+    },
+    futures::{SinkExt, StreamExt},
+    std::{future::Future, io},
+};
+
+/// Request/response calls over a [`ClientTransport`].
+pub trait ClientTransportExt<P: Protocol>: ClientTransport<P> {
+    /// Sends `frame` and waits for the response with the same tag.
+    ///
+    /// The call is traced and recorded in [`metrics`](crate::metrics). It
+    /// carries the current [`Deadline`], giving up with [`Error::Timeout`]
+    /// once it passes, and the metadata set with
+    /// [`with_metadata`](crate::with_metadata). The metadata of the response
+    /// is passed on to [`receive_metadata`](crate::receive_metadata). Error
+    /// frames are returned as [`Error::Status`].
+    fn call(
+        &mut self,
+        frame: Frame<P::Request>,
+    ) -> impl Future<Output = Result<Frame<P::Response>, Error>> + Send + Sync;
+}
+
+impl<P: Protocol, T: ClientTransport<P> + ?Sized> ClientTransportExt<P> for T {
+    async fn call(&mut self, mut frame: Frame<P::Request>) -> Result<Frame<P::Response>, Error> {
+        let tag = frame.tag;
+        let deadline = Deadline::current();
+        if let Some(deadline) = deadline {
+            frame.header.timeout = Some(deadline.remaining());
+        }
+        if let Some(metadata) = Metadata::outgoing() {
+            frame.header.metadata.extend(metadata);
+        }
+        let metrics = CallMetrics::start(Side::Client, P::NAME, frame.msg.message_name());
+        let span = trace::client::<P>(&mut frame);
+        let exchange = async {
+            self.send(frame).await?;
+            loop {
+                let rframe = match self.next().await {
+                    Some(rframe) => rframe?,
+                    None => return Err(Error::Io(io::ErrorKind::UnexpectedEof.into())),
+                };
+                // Responses to calls that already timed out can still arrive, skip them.
+                // A server going away tells whoever is waiting, whatever the tag.
+                if rframe.tag == tag || rframe.is_go_away() {
+                    rframe.header.metadata.receive();
+                    return match rframe.msg.status() {
+                        Some(status) => Err(Error::Status(status.clone())),
+                        None => Ok(rframe),
+                    };
+                }
+            }
+        };
+        let res = span
+            .instrument(async {
+                match deadline {
+                    Some(deadline) => deadline.timeout(exchange).await?,
+                    None => exchange.await,
+                }
+            })
+            .await;
+        metrics.finish(&res);
+        res
+    }
+}
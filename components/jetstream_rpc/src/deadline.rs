@@ -0,0 +1,75 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Per-call deadlines.
+//!
+//! Deadlines are ambient: a client wraps a call in [`with_timeout`] (or
+//! [`Deadline::scope`]) and every request sent from within it carries the
+//! remaining time in its [`Header`](crate::Header). Services run each handler
+//! inside the caller's deadline, so handlers can check [`Deadline::current`]
+//! and outgoing calls they make inherit it.
+
+use {
+    crate::Error,
+    std::{future::Future, time::Duration},
+    tokio::time::Instant,
+};
+
+tokio::task_local! {
+    static CURRENT: Deadline;
+}
+
+/// The point in time after which the caller no longer wants a response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Deadline(Instant);
+
+impl Deadline {
+    /// Returns a deadline `timeout` from now.
+    pub fn after(timeout: Duration) -> Self {
+        Self(Instant::now() + timeout)
+    }
+
+    /// Returns the instant the deadline expires at.
+    pub fn instant(&self) -> Instant {
+        self.0
+    }
+
+    /// Returns how much time is left, or zero if the deadline has passed.
+    pub fn remaining(&self) -> Duration {
+        self.0.saturating_duration_since(Instant::now())
+    }
+
+    /// Returns true if the deadline has passed.
+    pub fn is_expired(&self) -> bool {
+        Instant::now() >= self.0
+    }
+
+    /// Returns the deadline of the call the current task is part of, if any.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|deadline| *deadline).ok()
+    }
+
+    /// Runs `fut` with `self` as the current deadline.
+    ///
+    /// Deadlines only ever get tighter, if the task already has an earlier
+    /// deadline that one stays in effect.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        let deadline = Self::current().map_or(self, |current| current.min(self));
+        CURRENT.scope(deadline, fut).await
+    }
+
+    /// Runs `fut` to completion unless the deadline passes first, in which case
+    /// `fut` is dropped and [`Error::Timeout`] is returned.
+    pub async fn timeout<F: Future>(self, fut: F) -> Result<F::Output, Error> {
+        tokio::time::timeout_at(self.0, fut)
+            .await
+            .map_err(|_| Error::Timeout)
+    }
+}
+
+/// Runs `fut`, giving every call made from within it at most `timeout` to
+/// complete.
+pub async fn with_timeout<F: Future>(timeout: Duration, fut: F) -> F::Output {
+    Deadline::after(timeout).scope(fut).await
+}
@@ -0,0 +1,73 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use {
+    crate::{Context, Error, FromContext, FromContextParts},
+    jetstream_wireformat::WireFormat,
+    std::{future::Future, pin::Pin},
+};
+
+/// An async function that handles messages of type `T`.
+///
+/// Implemented for functions whose arguments all implement
+/// [`FromContextParts`], except for the last one which may consume the
+/// [`Context`] through [`FromContext`], usually to take the message itself.
+pub trait Handler<T: WireFormat, Args>: Clone + Send + Sync + Sized + 'static {
+    /// The value the handler responds with.
+    type Output: Send;
+    /// The future returned by [`Handler::call`].
+    type Future: Future<Output = Result<Self::Output, Error>> + Send + Sync;
+
+    fn call(self, context: Context<T>) -> Self::Future;
+}
+
+impl<F, Fut, R, T> Handler<T, ()> for F
+where
+    F: FnOnce() -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Result<R, Error>> + Send + Sync,
+    R: Send,
+    T: WireFormat,
+{
+    type Output = R;
+    type Future = Fut;
+
+    fn call(self, _context: Context<T>) -> Self::Future {
+        self()
+    }
+}
+
+macro_rules! impl_handler {
+    ([$($ty:ident),*], $last:ident) => {
+        impl<F, Fut, R, T, M, $($ty,)* $last> Handler<T, (M, $($ty,)* $last,)> for F
+        where
+            F: FnOnce($($ty,)* $last) -> Fut + Clone + Send + Sync + 'static,
+            Fut: Future<Output = Result<R, Error>> + Send + Sync + 'static,
+            R: Send,
+            T: WireFormat + Send + Sync + 'static,
+            $($ty: FromContextParts<T> + Send + Sync + 'static,)*
+            $last: FromContext<T, M> + Send + Sync + 'static,
+        {
+            type Output = R;
+            type Future = Pin<Box<dyn Future<Output = Result<R, Error>> + Send + Sync>>;
+
+            #[allow(non_snake_case)]
+            fn call(self, context: Context<T>) -> Self::Future {
+                Box::pin(async move {
+                    $(let $ty = $ty::from_context_parts(&context)?;)*
+                    let $last = $last::from_context(context)?;
+                    self($($ty,)* $last).await
+                })
+            }
+        }
+    };
+}
+
+impl_handler!([], A1);
+impl_handler!([A1], A2);
+impl_handler!([A1, A2], A3);
+impl_handler!([A1, A2, A3], A4);
+impl_handler!([A1, A2, A3, A4], A5);
+impl_handler!([A1, A2, A3, A4, A5], A6);
+impl_handler!([A1, A2, A3, A4, A5, A6], A7);
+impl_handler!([A1, A2, A3, A4, A5, A6, A7], A8);
@@ -0,0 +1,122 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Optional per-frame header extensions.
+//!
+//! Frames without extensions are encoded exactly as before. A frame carrying
+//! a non-empty [`Header`] is sent with the reserved [`EXTENDED_FRAME`] type and
+//! looks like:
+//!
+//! ```text
+//! size[4] EXTENDED_FRAME[1] tag[2] count[2] (key[1] value[Data])* type[1] msg
+//! ```
+//!
+//! Unknown keys are skipped, so new extensions can be added without breaking
+//! older peers. Peers that predate headers altogether can't decode extended
+//! frames, so both ends only send them once they know the other end
+//! understands them: servers to clients that sent one first, and clients
+//! when turned on per connection with `ClientCodec::with_headers`.
+
+use {
+    crate::Metadata,
+    jetstream_wireformat::{Data, WireFormat},
+    std::{
+        io::{self, Read, Write},
+        mem,
+        time::Duration,
+    },
+};
+
+/// Message type reserved for frames that carry a [`Header`].
+pub const EXTENDED_FRAME: u8 = 0xFF;
+
+const TIMEOUT: u8 = 1;
+const METADATA: u8 = 2;
+const TRACEPARENT: u8 = 3;
+
+/// Extensions sent in front of a frame's message.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Header {
+    /// How long the caller is willing to wait for the response, measured from
+    /// when the request was sent.
+    pub timeout: Option<Duration>,
+    /// Application defined key/value pairs.
+    pub metadata: Metadata,
+    /// The caller's W3C trace context, see `trace::TraceContext`.
+    pub traceparent: Option<String>,
+}
+
+impl Header {
+    /// Returns true if the header has no extensions set, in which case it is
+    /// left off the wire.
+    pub fn is_empty(&self) -> bool {
+        self.timeout.is_none() && self.metadata.is_empty() && self.traceparent.is_none()
+    }
+
+    fn entries(&self) -> io::Result<Vec<(u8, Data)>> {
+        let mut entries = Vec::new();
+        if let Some(timeout) = self.timeout {
+            let micros = u64::try_from(timeout.as_micros()).unwrap_or(u64::MAX);
+            entries.push((TIMEOUT, Data(micros.to_le_bytes().to_vec())));
+        }
+        if !self.metadata.is_empty() {
+            let mut value = Vec::with_capacity(self.metadata.byte_size() as usize);
+            self.metadata.encode(&mut value)?;
+            entries.push((METADATA, Data(value)));
+        }
+        if let Some(traceparent) = &self.traceparent {
+            entries.push((TRACEPARENT, Data(traceparent.as_bytes().to_vec())));
+        }
+        Ok(entries)
+    }
+}
+
+impl WireFormat for Header {
+    fn byte_size(&self) -> u32 {
+        let mut size = mem::size_of::<u16>() as u32;
+        if self.timeout.is_some() {
+            // key + length + micros
+            size += (mem::size_of::<u8>() + mem::size_of::<u32>() + mem::size_of::<u64>()) as u32;
+        }
+        if !self.metadata.is_empty() {
+            // key + length + entries
+            size += (mem::size_of::<u8>() + mem::size_of::<u32>()) as u32 + self.metadata.byte_size();
+        }
+        if let Some(traceparent) = &self.traceparent {
+            // key + length + text
+            size += (mem::size_of::<u8>() + mem::size_of::<u32>() + traceparent.len()) as u32;
+        }
+        size
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let entries = self.entries()?;
+        (entries.len() as u16).encode(writer)?;
+        for (key, value) in entries {
+            key.encode(writer)?;
+            value.encode(writer)?;
+        }
+        Ok(())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let count: u16 = WireFormat::decode(reader)?;
+        let mut header = Header::default();
+        for _ in 0..count {
+            let key: u8 = WireFormat::decode(reader)?;
+            let value: Data = WireFormat::decode(reader)?;
+            if key == TIMEOUT {
+                let micros = u64::decode(&mut value.as_slice())?;
+                header.timeout = Some(Duration::from_micros(micros));
+            } else if key == METADATA {
+                header.metadata = Metadata::decode(&mut value.as_slice())?;
+            } else if key == TRACEPARENT {
+                let traceparent = String::from_utf8(value.0)
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                header.traceparent = Some(traceparent);
+            }
+        }
+        Ok(header)
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Middleware for [`Protocol`]s.
+//!
+//! A [`Layer`] wraps a protocol in another protocol that sees every frame on
+//! its way in and out, which is where cross-cutting concerns like logging,
+//! metrics, auth and timeouts live. Any protocol can be wrapped, including
+//! the `{Trait}Service` generated by `#[service]`:
+//!
+//! ```ignore
+//! let mut service = ProtocolBuilder::new()
+//!     .layer(TimeoutLayer::new(Duration::from_secs(5)))
+//!     .layer(AuthLayer::new(policy))
+//!     .service(EchoService { inner: EchoImpl {} });
+//! ```
+//!
+//! Layers added first are outermost, they see requests first and responses
+//! last.
+
+use {
+    crate::{Deadline, Error, Frame, Protocol, Status},
+    std::time::Duration,
+};
+
+/// Decorates a protocol with another protocol.
+pub trait Layer<P> {
+    /// The wrapped protocol.
+    type Protocol;
+
+    fn layer(&self, inner: P) -> Self::Protocol;
+}
+
+impl<P, L: Layer<P>> Layer<P> for &L {
+    type Protocol = L::Protocol;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        (**self).layer(inner)
+    }
+}
+
+/// A layer that leaves the protocol as is.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Identity;
+
+impl<P> Layer<P> for Identity {
+    type Protocol = P;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        inner
+    }
+}
+
+/// Two layers applied one after the other, `Outer` wraps `Inner`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Stack<Inner, Outer> {
+    inner: Inner,
+    outer: Outer,
+}
+
+impl<Inner, Outer> Stack<Inner, Outer> {
+    pub fn new(inner: Inner, outer: Outer) -> Self {
+        Self { inner, outer }
+    }
+}
+
+impl<P, Inner, Outer> Layer<P> for Stack<Inner, Outer>
+where
+    Inner: Layer<P>,
+    Outer: Layer<Inner::Protocol>,
+{
+    type Protocol = Outer::Protocol;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        self.outer.layer(self.inner.layer(inner))
+    }
+}
+
+/// A layer built from a function, see [`layer_fn`].
+#[derive(Debug, Clone, Copy)]
+pub struct LayerFn<F>(F);
+
+/// Returns a layer that wraps protocols by calling `f`.
+pub fn layer_fn<F>(f: F) -> LayerFn<F> {
+    LayerFn(f)
+}
+
+impl<P, Q, F: Fn(P) -> Q> Layer<P> for LayerFn<F> {
+    type Protocol = Q;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        (self.0)(inner)
+    }
+}
+
+/// Composes layers and applies them to a protocol.
+#[derive(Debug, Clone, Default)]
+pub struct ProtocolBuilder<L> {
+    layer: L,
+}
+
+impl ProtocolBuilder<Identity> {
+    pub fn new() -> Self {
+        Self { layer: Identity }
+    }
+}
+
+impl<L> ProtocolBuilder<L> {
+    /// Adds `layer` inside the layers added so far.
+    pub fn layer<T>(self, layer: T) -> ProtocolBuilder<Stack<T, L>> {
+        ProtocolBuilder {
+            layer: Stack::new(layer, self.layer),
+        }
+    }
+
+    /// Returns the composed layer.
+    pub fn into_inner(self) -> L {
+        self.layer
+    }
+
+    /// Wraps `protocol` in all the layers.
+    pub fn service<P>(&self, protocol: P) -> L::Protocol
+    where
+        L: Layer<P>,
+    {
+        self.layer.layer(protocol)
+    }
+}
+
+/// Adds [`Layer`] support to every [`Protocol`].
+pub trait ProtocolExt: Protocol {
+    /// Wraps `self` in `layer`.
+    fn layer<L: Layer<Self>>(self, layer: L) -> L::Protocol {
+        layer.layer(self)
+    }
+}
+
+impl<P: Protocol> ProtocolExt for P {}
+
+/// Bounds how long a request may be handled for, on top of the deadline the
+/// caller set. Requests that run out of time are answered with a
+/// [`Code::DeadlineExceeded`](crate::Code::DeadlineExceeded) error frame.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeoutLayer {
+    timeout: Duration,
+}
+
+impl TimeoutLayer {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout }
+    }
+}
+
+impl<P> Layer<P> for TimeoutLayer {
+    type Protocol = Timeout<P>;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        Timeout {
+            inner,
+            timeout: self.timeout,
+        }
+    }
+}
+
+/// The protocol [`TimeoutLayer`] wraps protocols in.
+#[derive(Debug, Clone)]
+pub struct Timeout<P> {
+    inner: P,
+    timeout: Duration,
+}
+
+impl<P> Protocol for Timeout<P>
+where
+    P: Protocol,
+    P::Error: From<Error>,
+{
+    type Request = P::Request;
+    type Response = P::Response;
+    type Error = P::Error;
+    const VERSION: &'static str = P::VERSION;
+    const NAME: &'static str = P::NAME;
+
+    async fn rpc(
+        &mut self,
+        frame: Frame<Self::Request>,
+    ) -> Result<Frame<Self::Response>, Self::Error> {
+        // The caller may be willing to wait less than the layer allows.
+        let timeout = match frame.header.timeout {
+            Some(timeout) => timeout.min(self.timeout),
+            None => self.timeout,
+        };
+        let tag = frame.tag;
+        match Deadline::after(timeout).timeout(self.inner.rpc(frame)).await {
+            Ok(res) => res,
+            // Only this request ran out of time, the connection is fine.
+            Err(_) => {
+                let status = Status::deadline_exceeded(format!("gave up after {:?}", timeout));
+                Frame::error(tag, status).map_err(|status| Error::from(status).into())
+            }
+        }
+    }
+}
@@ -7,9 +7,53 @@
 //! Of note is the `Protocol` trait which is meant to be used with the `service` attribute macro.
 #![cfg_attr(docsrs, feature(doc_cfg, doc_auto_cfg))]
 
+#[cfg(not(target_arch = "wasm32"))]
+mod call;
+#[cfg(not(target_arch = "wasm32"))]
+mod deadline;
+mod extensions;
+mod handler;
+mod header;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod layer;
+mod metadata;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod metrics;
+mod peer;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod rate_limit;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod router;
+mod status;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod trace;
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use call::ClientTransportExt;
+#[cfg(not(target_arch = "wasm32"))]
+pub use deadline::{with_timeout, Deadline};
+#[cfg(not(target_arch = "wasm32"))]
+pub use layer::{Layer, ProtocolBuilder, ProtocolExt};
+#[cfg(not(target_arch = "wasm32"))]
+pub use metadata::{receive_metadata, with_metadata};
+#[cfg(not(target_arch = "wasm32"))]
+pub use router::{Messages, Route, Router};
+pub use {
+    extensions::Extensions,
+    handler::Handler,
+    header::{Header, EXTENDED_FRAME},
+    metadata::Metadata,
+    peer::Peer,
+    status::{Code, Status, ERROR_FRAME},
+};
+
+// So `#[service(crate = jetstream_rpc)]` finds everything it expands to.
+pub use jetstream_wireformat::{JetStreamWireFormat, WireFormat};
+#[doc(hidden)]
+pub use trait_variant;
+
 use {
     futures::{Sink, Stream},
-    jetstream_wireformat::WireFormat,
     std::{
         io::{self, ErrorKind, Read, Write},
         mem,
@@ -19,6 +63,7 @@ use {
 /// A trait representing a message that can be encoded and decoded.
 pub trait Message: WireFormat + Send + Sync {}
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(transparent)]
 pub struct Tag(u16);
 
@@ -28,29 +73,104 @@ impl From<u16> for Tag {
     }
 }
 
+impl From<Tag> for u16 {
+    fn from(tag: Tag) -> Self {
+        tag.0
+    }
+}
+
+/// Everything known about a request while it is being handled.
 pub struct Context<T: WireFormat> {
     pub tag: Tag,
     pub msg: T,
+    /// When the caller stops waiting for the response, if it set a timeout.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub deadline: Option<Deadline>,
+    /// Metadata the caller sent along with the request.
+    pub metadata: Metadata,
+    /// Values attached to the request by the server, see [`Frame::extensions`].
+    pub extensions: Extensions,
+}
+
+mod private {
+    #[derive(Debug, Clone, Copy)]
+    pub enum ViaContext {}
+
+    #[derive(Debug, Clone, Copy)]
+    pub enum ViaParts {}
+}
+
+/// Extracts a handler argument by consuming the [`Context`].
+///
+/// Only the last argument of a [`Handler`] can consume the context, which is
+/// how handlers take ownership of the message.
+pub trait FromContext<T: WireFormat, M = private::ViaContext>: Sized {
+    fn from_context(ctx: Context<T>) -> Result<Self, Error>;
 }
 
-pub trait FromContext<T: WireFormat> {
-    fn from_context(ctx: Context<T>) -> Self;
+/// Extracts a handler argument from a borrowed [`Context`].
+pub trait FromContextParts<T: WireFormat>: Sized {
+    fn from_context_parts(ctx: &Context<T>) -> Result<Self, Error>;
 }
 
 impl<T: WireFormat> FromContext<T> for T {
-    fn from_context(ctx: Context<T>) -> Self {
-        ctx.msg
+    fn from_context(ctx: Context<T>) -> Result<Self, Error> {
+        Ok(ctx.msg)
+    }
+}
+
+impl<T: WireFormat, E: FromContextParts<T>> FromContext<T, private::ViaParts> for E {
+    fn from_context(ctx: Context<T>) -> Result<Self, Error> {
+        E::from_context_parts(&ctx)
     }
 }
 
-impl<T: WireFormat> FromContext<T> for Tag {
-    fn from_context(ctx: Context<T>) -> Self {
-        ctx.tag
+impl<T: WireFormat> FromContextParts<T> for Tag {
+    fn from_context_parts(ctx: &Context<T>) -> Result<Self, Error> {
+        Ok(ctx.tag)
     }
 }
 
-pub trait Handler<T: WireFormat> {
-    fn call(self, context: Context<T>);
+impl<T: WireFormat> FromContextParts<T> for Metadata {
+    fn from_context_parts(ctx: &Context<T>) -> Result<Self, Error> {
+        Ok(ctx.metadata.clone())
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl<T: WireFormat> FromContextParts<T> for Option<Deadline> {
+    fn from_context_parts(ctx: &Context<T>) -> Result<Self, Error> {
+        Ok(ctx.deadline)
+    }
+}
+
+impl<T: WireFormat> FromContextParts<T> for Peer {
+    fn from_context_parts(ctx: &Context<T>) -> Result<Self, Error> {
+        ctx.extensions
+            .get::<Peer>()
+            .cloned()
+            .ok_or(Error::MissingExtension(std::any::type_name::<Peer>()))
+    }
+}
+
+impl<T: WireFormat> FromContextParts<T> for Option<Peer> {
+    fn from_context_parts(ctx: &Context<T>) -> Result<Self, Error> {
+        Ok(ctx.extensions.get::<Peer>().cloned())
+    }
+}
+
+/// Extracts state shared by all handlers of a [`Router`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct State<S>(pub S);
+
+impl<T: WireFormat, S: Clone + Send + Sync + 'static> FromContextParts<T> for State<S> {
+    fn from_context_parts(ctx: &Context<T>) -> Result<Self, Error> {
+        ctx.extensions
+            .get::<S>()
+            .cloned()
+            .map(State)
+            .ok_or(Error::MissingExtension(std::any::type_name::<S>()))
+    }
 }
 
 /// Defines the request and response types for the JetStream protocol.
@@ -60,6 +180,8 @@ pub trait Protocol: Send + Sync {
     type Response: Framer;
     type Error: std::error::Error + Send + Sync + 'static;
     const VERSION: &'static str;
+    /// The name of the service, as reported in traces and metrics.
+    const NAME: &'static str = Self::VERSION;
     async fn rpc(
         &mut self,
         frame: Frame<Self::Request>,
@@ -74,16 +196,30 @@ pub enum Error {
     Generic(#[from] Box<dyn std::error::Error + Send + Sync>),
     #[error("{0}")]
     Custom(String),
+    #[error("deadline exceeded")]
+    Timeout,
+    #[error("missing extension: {0}")]
+    MissingExtension(&'static str),
+    #[error("{0}")]
+    Status(#[from] Status),
 }
 
 pub struct Frame<T: Framer> {
     pub tag: u16,
+    pub header: Header,
     pub msg: T,
+    /// Values attached by the local side, they are not sent to the peer.
+    pub extensions: Extensions,
 }
 
 impl<T: Framer> From<(u16, T)> for Frame<T> {
     fn from((tag, msg): (u16, T)) -> Self {
-        Self { tag, msg }
+        Self {
+            tag,
+            header: Header::default(),
+            msg,
+            extensions: Extensions::default(),
+        }
     }
 }
 
@@ -91,7 +227,14 @@ impl<T: Framer> WireFormat for Frame<T> {
     fn byte_size(&self) -> u32 {
         let msg_size = self.msg.byte_size();
         // size + type + tag + message size
-        (mem::size_of::<u32>() + mem::size_of::<u8>() + mem::size_of::<u16>()) as u32 + msg_size
+        let size = (mem::size_of::<u32>() + mem::size_of::<u8>() + mem::size_of::<u16>()) as u32
+            + msg_size;
+        if self.header.is_empty() {
+            size
+        } else {
+            // header + the real message type
+            size + self.header.byte_size() + mem::size_of::<u8>() as u32
+        }
     }
 
     fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
@@ -99,8 +242,15 @@ impl<T: Framer> WireFormat for Frame<T> {
 
         let ty = self.msg.message_type();
 
-        ty.encode(writer)?;
-        self.tag.encode(writer)?;
+        if self.header.is_empty() {
+            ty.encode(writer)?;
+            self.tag.encode(writer)?;
+        } else {
+            EXTENDED_FRAME.encode(writer)?;
+            self.tag.encode(writer)?;
+            self.header.encode(writer)?;
+            ty.encode(writer)?;
+        }
 
         self.msg.encode(writer)
     }
@@ -124,9 +274,21 @@ impl<T: Framer> WireFormat for Frame<T> {
         reader.read_exact(&mut ty)?;
 
         let tag: u16 = WireFormat::decode(reader)?;
+        let header = if ty[0] == EXTENDED_FRAME {
+            let header = WireFormat::decode(reader)?;
+            reader.read_exact(&mut ty)?;
+            header
+        } else {
+            Header::default()
+        };
         let msg = T::decode(reader, ty[0])?;
 
-        Ok(Frame { tag, msg })
+        Ok(Frame {
+            tag,
+            header,
+            msg,
+            extensions: Extensions::default(),
+        })
     }
 }
 
@@ -140,6 +302,34 @@ pub trait Framer: Sized + Send + Sync {
 
     /// Decodes `Self` from `reader`.
     fn decode<R: Read>(reader: &mut R, ty: u8) -> io::Result<Self>;
+
+    /// Returns true if handling `self` more than once has the same effect as handling it once,
+    /// which makes it safe for clients to retry after a transport failure.
+    fn is_idempotent(&self) -> bool {
+        false
+    }
+
+    /// Returns the name of the message, as reported in traces and metrics.
+    fn message_name(&self) -> &'static str {
+        "unknown"
+    }
+
+    /// Returns the resource the request acts on, the argument of its method
+    /// marked `#[resource]`, for authorization.
+    fn resource(&self) -> Option<String> {
+        None
+    }
+
+    /// Returns the error status `self` carries, if it is an error response.
+    fn status(&self) -> Option<&Status> {
+        None
+    }
+
+    /// Wraps `status` in a response, or gives it back if `Self` can't carry
+    /// one.
+    fn from_status(status: Status) -> Result<Self, Status> {
+        Err(status)
+    }
 }
 
 pub trait ServiceTransport<P: Protocol>:
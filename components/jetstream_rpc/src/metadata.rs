@@ -0,0 +1,231 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Key/value metadata sent alongside requests and responses.
+//!
+//! Metadata travels in the frame [`Header`](crate::Header), which codecs
+//! leave off the wire for peers that predate it. Typical uses are trace ids,
+//! auth tokens and tenant ids.
+//!
+//! Clients attach metadata to requests with [`with_metadata`] and read that of
+//! responses with [`receive_metadata`]. Handlers read the request's with
+//! [`Metadata::current`] and add to the response's with
+//! [`Metadata::add_to_response`].
+
+use {
+    jetstream_wireformat::{Data, WireFormat},
+    std::{
+        collections::{btree_map, BTreeMap},
+        io::{self, Read, Write},
+        mem,
+    },
+};
+
+/// An ordered map of string keys to byte values.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Metadata(BTreeMap<String, Vec<u8>>);
+
+impl Metadata {
+    /// Creates an empty map.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value for `key`.
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.0.get(key).map(Vec::as_slice)
+    }
+
+    /// Returns the value for `key` if it is valid UTF-8.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key)
+            .and_then(|value| std::str::from_utf8(value).ok())
+    }
+
+    /// Sets `key` to `value`, returning the previous value.
+    pub fn insert(&mut self, key: impl Into<String>, value: impl Into<Vec<u8>>) -> Option<Vec<u8>> {
+        self.0.insert(key.into(), value.into())
+    }
+
+    /// Removes `key`, returning its value.
+    pub fn remove(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.0.remove(key)
+    }
+
+    /// Returns true if `key` is set.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Returns the number of entries.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns true if there are no entries.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Removes all entries.
+    pub fn clear(&mut self) {
+        self.0.clear()
+    }
+
+    /// Iterates over the entries in key order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &[u8])> {
+        self.0
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_slice()))
+    }
+
+    pub(crate) fn byte_size(&self) -> u32 {
+        mem::size_of::<u16>() as u32
+            + self
+                .0
+                .iter()
+                .map(|(key, value)| {
+                    key.byte_size() + mem::size_of::<u32>() as u32 + value.len() as u32
+                })
+                .sum::<u32>()
+    }
+
+    pub(crate) fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.0.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "too many metadata entries",
+            ));
+        }
+        (self.0.len() as u16).encode(writer)?;
+        for (key, value) in &self.0 {
+            key.encode(writer)?;
+            (value.len() as u32).encode(writer)?;
+            writer.write_all(value)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let count: u16 = WireFormat::decode(reader)?;
+        let mut metadata = Metadata::new();
+        for _ in 0..count {
+            let key: String = WireFormat::decode(reader)?;
+            let value: Data = WireFormat::decode(reader)?;
+            metadata.0.insert(key, value.0);
+        }
+        Ok(metadata)
+    }
+}
+
+impl<K: Into<String>, V: Into<Vec<u8>>> FromIterator<(K, V)> for Metadata {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+impl<K: Into<String>, V: Into<Vec<u8>>> Extend<(K, V)> for Metadata {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        self.0.extend(
+            iter.into_iter()
+                .map(|(key, value)| (key.into(), value.into())),
+        )
+    }
+}
+
+impl IntoIterator for Metadata {
+    type Item = (String, Vec<u8>);
+    type IntoIter = btree_map::IntoIter<String, Vec<u8>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+mod scope {
+    use {
+        super::Metadata,
+        std::{future::Future, mem, sync::Mutex},
+    };
+
+    tokio::task_local! {
+        static INCOMING: Metadata;
+        static OUTGOING: Metadata;
+        static RESPONSE: Mutex<Metadata>;
+        static RECEIVED: Mutex<Metadata>;
+    }
+
+    impl Metadata {
+        /// Returns the metadata of the request the current task is handling.
+        pub fn current() -> Option<Self> {
+            INCOMING.try_with(Clone::clone).ok()
+        }
+
+        /// Returns the metadata [`with_metadata`] attaches to calls made from
+        /// the current task.
+        pub fn outgoing() -> Option<Self> {
+            OUTGOING.try_with(Clone::clone).ok()
+        }
+
+        /// Adds `self` to the metadata of the response to the request the
+        /// current task is handling, overriding keys added before. Returns
+        /// false if the task isn't handling a request.
+        pub fn add_to_response(self) -> bool {
+            RESPONSE
+                .try_with(|response| response.lock().unwrap().extend(self))
+                .is_ok()
+        }
+
+        /// Runs `fut` as the handler of a request that carried `self`, and
+        /// returns its output along with the metadata it added to the
+        /// response.
+        pub async fn scope<F: Future>(self, fut: F) -> (F::Output, Metadata) {
+            let handle = async {
+                let out = fut.await;
+                let response = RESPONSE.with(|response| mem::take(&mut *response.lock().unwrap()));
+                (out, response)
+            };
+            INCOMING
+                .scope(self, RESPONSE.scope(Mutex::new(Metadata::new()), handle))
+                .await
+        }
+
+        /// Records the metadata of a response for [`receive_metadata`].
+        pub(crate) fn receive(&self) {
+            if !self.is_empty() {
+                let _ = RECEIVED.try_with(|received| received.lock().unwrap().extend(self.clone()));
+            }
+        }
+    }
+
+    /// Runs `fut`, attaching `metadata` to every call made from within it.
+    ///
+    /// Nested scopes add to, and override keys of, the enclosing one. Metadata
+    /// of an incoming request is never forwarded on its own.
+    pub async fn with_metadata<F: Future>(metadata: Metadata, fut: F) -> F::Output {
+        let mut outgoing = Metadata::outgoing().unwrap_or_default();
+        outgoing.extend(metadata);
+        OUTGOING.scope(outgoing, fut).await
+    }
+
+    /// Runs `fut`, and returns its output along with the metadata of the
+    /// responses to the calls made from within it. Keys of later responses
+    /// override those of earlier ones.
+    pub async fn receive_metadata<F: Future>(fut: F) -> (F::Output, Metadata) {
+        let calls = async {
+            let out = fut.await;
+            let received = RECEIVED.with(|received| mem::take(&mut *received.lock().unwrap()));
+            (out, received)
+        };
+        RECEIVED.scope(Mutex::new(Metadata::new()), calls).await
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use scope::{receive_metadata, with_metadata};
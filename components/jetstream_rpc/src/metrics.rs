@@ -0,0 +1,162 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Metrics for RPCs.
+//!
+//! Calls are recorded through the [`metrics`] facade, on servers by
+//! `jetstream_server::service::run` and on clients by
+//! [`ClientTransportExt::call`](crate::ClientTransportExt::call) and the
+//! codecs. Nothing is collected until a recorder is installed, for example
+//! the Prometheus one behind the `prometheus` feature of `jetstream_server`.
+//!
+//! Every metric is labelled with `service` and `method`, and prefixed with
+//! `jetstream_server_` or `jetstream_client_`. Requests answered with an
+//! error frame, including those a server sheds, count as errors on both
+//! sides.
+//!
+//!
+//! | metric                     | type      | unit    |
+//! |----------------------------|-----------|---------|
+//! | `requests_total`           | counter   |         |
+//! | `errors_total`             | counter   |         |
+//! | `request_duration_seconds` | histogram | seconds |
+//! | `requests_in_flight`       | gauge     |         |
+//! | `received_bytes_total`     | counter   | bytes   |
+//! | `sent_bytes_total`         | counter   | bytes   |
+
+use {
+    crate::{Frame, Framer},
+    metrics::{
+        counter,
+        describe_counter,
+        describe_gauge,
+        describe_histogram,
+        gauge,
+        histogram,
+        Unit,
+    },
+    tokio::time::Instant,
+};
+
+/// Which end of a call a metric was recorded on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Side {
+    Client,
+    Server,
+}
+
+struct Names {
+    requests: &'static str,
+    errors: &'static str,
+    duration: &'static str,
+    in_flight: &'static str,
+    received_bytes: &'static str,
+    sent_bytes: &'static str,
+}
+
+const CLIENT: Names = Names {
+    requests: "jetstream_client_requests_total",
+    errors: "jetstream_client_errors_total",
+    duration: "jetstream_client_request_duration_seconds",
+    in_flight: "jetstream_client_requests_in_flight",
+    received_bytes: "jetstream_client_received_bytes_total",
+    sent_bytes: "jetstream_client_sent_bytes_total",
+};
+
+const SERVER: Names = Names {
+    requests: "jetstream_server_requests_total",
+    errors: "jetstream_server_errors_total",
+    duration: "jetstream_server_request_duration_seconds",
+    in_flight: "jetstream_server_requests_in_flight",
+    received_bytes: "jetstream_server_received_bytes_total",
+    sent_bytes: "jetstream_server_sent_bytes_total",
+};
+
+impl Side {
+    fn names(self) -> &'static Names {
+        match self {
+            Side::Client => &CLIENT,
+            Side::Server => &SERVER,
+        }
+    }
+}
+
+type Labels = [(&'static str, &'static str); 2];
+
+fn labels(service: &'static str, method: &'static str) -> Labels {
+    [("service", service), ("method", method)]
+}
+
+/// Registers descriptions and units of the metrics with the installed
+/// recorder. Call it once after installing the recorder.
+pub fn describe() {
+    for side in [Side::Client, Side::Server] {
+        let names = side.names();
+        describe_counter!(names.requests, Unit::Count, "RPCs started.");
+        describe_counter!(names.errors, Unit::Count, "RPCs that failed or timed out.");
+        describe_histogram!(names.duration, Unit::Seconds, "Time taken by RPCs.");
+        describe_gauge!(names.in_flight, Unit::Count, "RPCs in progress.");
+        describe_counter!(names.received_bytes, Unit::Bytes, "Bytes of frames received.");
+        describe_counter!(names.sent_bytes, Unit::Bytes, "Bytes of frames sent.");
+    }
+}
+
+/// Records a single call, from [`CallMetrics::start`] until it is finished
+/// or dropped. Calls dropped before they finish count as errors.
+#[derive(Debug)]
+#[must_use = "the call is recorded as failed when dropped"]
+pub struct CallMetrics {
+    side: Side,
+    labels: Labels,
+    start: Instant,
+    ok: bool,
+}
+
+impl CallMetrics {
+    /// Counts a new call of `method` and marks it as in flight.
+    pub fn start(side: Side, service: &'static str, method: &'static str) -> Self {
+        let labels = labels(service, method);
+        let names = side.names();
+        counter!(names.requests, &labels).increment(1);
+        gauge!(names.in_flight, &labels).increment(1.0);
+        Self {
+            side,
+            labels,
+            start: Instant::now(),
+            ok: false,
+        }
+    }
+
+    /// Ends the call, recording its latency and whether it failed.
+    pub fn finish<T, E>(mut self, res: &Result<T, E>) {
+        self.ok = res.is_ok();
+    }
+
+    /// Ends the call answered with `res`. Error frames count as failures,
+    /// as they do on the client, which sees them as [`Error::Status`](crate::Error::Status).
+    pub fn respond<R: Framer, E>(mut self, res: &Result<Frame<R>, E>) {
+        self.ok = matches!(res, Ok(frame) if frame.msg.status().is_none());
+    }
+}
+
+impl Drop for CallMetrics {
+    fn drop(&mut self) {
+        let names = self.side.names();
+        histogram!(names.duration, &self.labels).record(self.start.elapsed().as_secs_f64());
+        gauge!(names.in_flight, &self.labels).decrement(1.0);
+        if !self.ok {
+            counter!(names.errors, &self.labels).increment(1);
+        }
+    }
+}
+
+/// Counts the bytes of a frame received for `method`.
+pub fn record_received(side: Side, service: &'static str, method: &'static str, bytes: usize) {
+    counter!(side.names().received_bytes, &labels(service, method)).increment(bytes as u64);
+}
+
+/// Counts the bytes of a frame sent for `method`.
+pub fn record_sent(side: Side, service: &'static str, method: &'static str, bytes: usize) {
+    counter!(side.names().sent_bytes, &labels(service, method)).increment(bytes as u64);
+}
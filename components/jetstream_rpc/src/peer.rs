@@ -0,0 +1,36 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::net::SocketAddr;
+
+/// Who is on the other end of a connection, as established by the transport.
+///
+/// Servers attach the peer to the [`Extensions`](crate::Extensions) of every
+/// frame they read, handlers take it as an argument to get at it. See
+/// `jetstream_server::peer` for working it out from a transport.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Peer {
+    /// A peer connected over the network.
+    Socket(SocketAddr),
+    /// A peer that authenticated with a TLS client certificate.
+    Tls {
+        addr: SocketAddr,
+        /// The SHA-256 digest of the DER encoded certificate.
+        fingerprint: [u8; 32],
+        /// The certificate's subject, like `CN=client.localhost, O=client`.
+        subject: String,
+        /// The certificate's subject alternative names: DNS names, email
+        /// addresses, URIs and IP addresses.
+        sans: Vec<String>,
+    },
+    /// A process on this host connected over a Unix domain socket.
+    Unix {
+        uid: u32,
+        gid: u32,
+        pid: Option<i32>,
+    },
+    /// A virtual machine connected over vsock.
+    Vsock { cid: u32 },
+}
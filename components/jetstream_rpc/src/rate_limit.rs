@@ -0,0 +1,243 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Per client rate limiting.
+//!
+//! [`RateLimitLayer`] gives every client a token bucket, keyed by the
+//! [`Peer`] the transport attached to its requests, as
+//! `jetstream_server::service::serve` does. Each request takes
+//! tokens from its client's bucket, one unless its method costs more, and is
+//! answered with a [`Code::RateLimited`](crate::Code::RateLimited) error frame
+//! when there aren't enough left. Buckets refill at a steady rate up to a
+//! burst size.
+//!
+//! ```ignore
+//! let limit = RateLimitLayer::new(100.0, 20).with_cost("write", 10);
+//! loop {
+//!     let (stream, _) = listener.accept().await?;
+//!     // One layer for every connection, so a client's connections share a bucket.
+//!     let mut service = EchoService { inner: EchoImpl {} }.layer(&limit);
+//!     tokio::spawn(async move { serve(&mut service, stream).await });
+//! }
+//! ```
+
+use {
+    crate::{Error, Frame, Framer, Layer, Peer, Protocol, Status},
+    std::{
+        collections::HashMap,
+        net::IpAddr,
+        sync::{Arc, Mutex},
+        time::Duration,
+    },
+    tokio::time::Instant,
+};
+
+/// How often buckets that have filled up again are dropped.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The most clients with buckets at once. Beyond that, a new client's bucket
+/// replaces a full one, or else the one left alone the longest, whose client
+/// starts over with a full bucket if it comes back.
+const MAX_BUCKETS: usize = 65_536;
+
+/// The identity requests are rate limited by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PeerKey {
+    /// A TLS client certificate, by fingerprint.
+    Certificate([u8; 32]),
+    /// A local user, by uid.
+    Unix { uid: u32 },
+    /// A virtual machine, by context id.
+    Vsock { cid: u32 },
+    /// A network peer without a certificate, by address. The port is left
+    /// out, every connection from a host shares its bucket.
+    Ip(IpAddr),
+    /// Requests without a [`Peer`].
+    Anonymous,
+}
+
+impl From<&Peer> for PeerKey {
+    fn from(peer: &Peer) -> Self {
+        match peer {
+            Peer::Socket(addr) => PeerKey::Ip(addr.ip()),
+            Peer::Tls { fingerprint, .. } => PeerKey::Certificate(*fingerprint),
+            Peer::Unix { uid, .. } => PeerKey::Unix { uid: *uid },
+            Peer::Vsock { cid } => PeerKey::Vsock { cid: *cid },
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug)]
+struct Buckets {
+    rate: f64,
+    burst: f64,
+    max: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    buckets: HashMap<PeerKey, Bucket>,
+    pruned: Instant,
+}
+
+impl Buckets {
+    fn new(rate: f64, burst: f64, max: usize) -> Self {
+        Self {
+            rate,
+            burst,
+            max,
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                pruned: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `cost` tokens from the bucket of `key` at `now`, or returns how
+    /// long until there are enough, if there ever will be.
+    fn take(&self, key: PeerKey, cost: f64, now: Instant) -> Result<(), Option<Duration>> {
+        let mut state = self.state.lock().unwrap();
+        let State { buckets, pruned } = &mut *state;
+        let full = buckets.len() >= self.max && !buckets.contains_key(&key);
+        if full || now.duration_since(*pruned) >= PRUNE_INTERVAL {
+            // Full buckets are the same as new ones.
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst);
+            *pruned = now;
+        }
+        if buckets.len() >= self.max && !buckets.contains_key(&key) {
+            let idle = buckets.iter().min_by_key(|(_, bucket)| bucket.updated);
+            if let Some(idle) = idle.map(|(key, _)| key.clone()) {
+                buckets.remove(&idle);
+            }
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= cost {
+            bucket.tokens -= cost;
+            Ok(())
+        } else if cost > self.burst || self.rate <= 0.0 {
+            Err(None)
+        } else {
+            Err(Some(Duration::from_secs_f64((cost - bucket.tokens) / self.rate)))
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
+
+/// Rate limits the requests of each client, see the [module](self)
+/// documentation.
+#[derive(Debug, Clone)]
+pub struct RateLimitLayer {
+    buckets: Arc<Buckets>,
+    costs: Arc<HashMap<&'static str, u32>>,
+}
+
+impl RateLimitLayer {
+    /// Lets every client make `rate` requests a second, and up to `burst`
+    /// at once.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self {
+            buckets: Arc::new(Buckets::new(rate, burst as f64, MAX_BUCKETS)),
+            costs: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Charges `cost` tokens for requests of `method`, as named by
+    /// [`Framer::message_name`]. Methods that cost more than the burst are
+    /// always refused.
+    pub fn with_cost(mut self, method: &'static str, cost: u32) -> Self {
+        Arc::make_mut(&mut self.costs).insert(method, cost);
+        self
+    }
+}
+
+impl<P> Layer<P> for RateLimitLayer {
+    type Protocol = RateLimit<P>;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        RateLimit {
+            inner,
+            limit: self.clone(),
+        }
+    }
+}
+
+/// The protocol [`RateLimitLayer`] wraps protocols in.
+#[derive(Debug, Clone)]
+pub struct RateLimit<P> {
+    inner: P,
+    limit: RateLimitLayer,
+}
+
+impl<P> Protocol for RateLimit<P>
+where
+    P: Protocol,
+    P::Error: From<Error>,
+{
+    type Request = P::Request;
+    type Response = P::Response;
+    type Error = P::Error;
+    const VERSION: &'static str = P::VERSION;
+    const NAME: &'static str = P::NAME;
+
+    async fn rpc(
+        &mut self,
+        frame: Frame<Self::Request>,
+    ) -> Result<Frame<Self::Response>, Self::Error> {
+        let key = frame
+            .extensions
+            .get::<Peer>()
+            .map_or(PeerKey::Anonymous, PeerKey::from);
+        let method = frame.msg.message_name();
+        let cost = self.limit.costs.get(method).copied().unwrap_or(1);
+        if let Err(retry_after) = self.limit.buckets.take(key, cost as f64, Instant::now()) {
+            let mut status = Status::rate_limited(format!("too many {} requests", method));
+            status.retry_after = retry_after;
+            return Ok(Frame::error(frame.tag, status).map_err(Error::from)?);
+        }
+        self.inner.rpc(frame).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_idle_clients_make_room() {
+        // Buckets never refill, so none of them is ever full again.
+        let buckets = Buckets::new(0.0, 1.0, 2);
+        let start = Instant::now();
+        let take = |uid, millis| {
+            let now = start + Duration::from_millis(millis);
+            buckets.take(PeerKey::Unix { uid }, 1.0, now).is_ok()
+        };
+        assert!(take(1, 0));
+        assert!(take(2, 1));
+        assert!(!take(1, 2));
+        // Client 2 has been idle the longest, and makes room for client 3.
+        assert!(take(3, 3));
+        assert!(!take(1, 4));
+        assert!(!take(3, 5));
+        // Evicted, client 2 starts over.
+        assert!(take(2, 6));
+        assert!(!take(3, 7));
+    }
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Handler based routing, an alternative to implementing a `#[service]`
+//! trait.
+//!
+//! ```ignore
+//! async fn ping(tag: Tag, State(db): State<Db>, msg: Tping) -> Result<(), Error> {
+//!     Ok(())
+//! }
+//!
+//! let mut router = Router::<EchoMessages>::new()
+//!     .route::<Tping, _>(ping)
+//!     .with_state(db);
+//! run(&mut router, transport).await?;
+//! ```
+
+use {
+    crate::{
+        Context,
+        Deadline,
+        Error,
+        Extensions,
+        Frame,
+        Framer,
+        Handler,
+        Protocol,
+        Status,
+        Tag,
+    },
+    jetstream_wireformat::WireFormat,
+    std::{collections::HashMap, future::Future, marker::PhantomData, pin::Pin, sync::Arc},
+};
+
+/// The request and response types of a protocol.
+///
+/// `#[service]` generates an implementation named `{Trait}Messages`.
+pub trait Messages: Send + Sync + 'static {
+    type Request: Framer;
+    type Response: Framer;
+    const VERSION: &'static str;
+    /// The name of the service, as reported in traces and metrics.
+    const NAME: &'static str = Self::VERSION;
+}
+
+/// A request message of the protocol `M`, which can be routed to a handler.
+///
+/// `#[service]` implements it for every request message it generates.
+pub trait Route<M: Messages>: WireFormat + Send + Sync + Sized + 'static {
+    /// The value handlers of this message respond with.
+    type Response: Send;
+    /// The message type `Self` is framed with.
+    const MESSAGE_TYPE: u8;
+
+    /// Takes the message out of `request` if it is a `Self`.
+    fn from_request(request: M::Request) -> Option<Self>;
+
+    /// Wraps the value a handler responded with in a response.
+    fn into_response(response: Self::Response) -> M::Response;
+}
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send + Sync>>;
+
+trait ErasedRoute<M: Messages>: Send + Sync {
+    fn call(
+        &self,
+        frame: Frame<M::Request>,
+        state: &Extensions,
+    ) -> BoxFuture<Result<M::Response, Error>>;
+}
+
+struct RouteHandler<R, H, Args> {
+    handler: H,
+    _route: PhantomData<fn() -> (R, Args)>,
+}
+
+impl<M, R, H, Args> ErasedRoute<M> for RouteHandler<R, H, Args>
+where
+    M: Messages,
+    R: Route<M>,
+    H: Handler<R, Args, Output = R::Response>,
+    H::Future: 'static,
+{
+    fn call(
+        &self,
+        frame: Frame<M::Request>,
+        state: &Extensions,
+    ) -> BoxFuture<Result<M::Response, Error>> {
+        let Frame {
+            tag,
+            header,
+            msg,
+            mut extensions,
+        } = frame;
+        let Some(msg) = R::from_request(msg) else {
+            return Box::pin(async move {
+                Err(Error::Custom(format!(
+                    "message type {} was routed to the wrong handler",
+                    R::MESSAGE_TYPE
+                )))
+            });
+        };
+        extensions.merge(state);
+        let deadline = header.timeout.map(Deadline::after);
+        let context = Context {
+            tag: Tag::from(tag),
+            msg,
+            deadline,
+            metadata: header.metadata,
+            extensions,
+        };
+        let fut = self.handler.clone().call(context);
+        Box::pin(async move {
+            // Run the handler inside the caller's deadline so calls it makes inherit it.
+            let res = match deadline {
+                Some(deadline) => deadline.scope(fut).await,
+                None => fut.await,
+            };
+            res.map(R::into_response)
+        })
+    }
+}
+
+/// A [`Protocol`] that dispatches every request to the handler registered for
+/// its message type.
+pub struct Router<M: Messages> {
+    routes: HashMap<u8, Arc<dyn ErasedRoute<M>>>,
+    state: Extensions,
+}
+
+impl<M: Messages> Router<M> {
+    /// Creates a router without any routes.
+    pub fn new() -> Self {
+        Self {
+            routes: HashMap::new(),
+            state: Extensions::new(),
+        }
+    }
+
+    /// Routes requests of type `R` to `handler`, replacing any previous
+    /// handler for `R`.
+    pub fn route<R, Args>(mut self, handler: impl Handler<R, Args, Output = R::Response>) -> Self
+    where
+        R: Route<M>,
+        Args: 'static,
+    {
+        let route = RouteHandler::<R, _, Args> {
+            handler,
+            _route: PhantomData,
+        };
+        self.routes.insert(R::MESSAGE_TYPE, Arc::new(route));
+        self
+    }
+
+    /// Makes `state` available to handlers through the
+    /// [`State`](crate::State) extractor.
+    pub fn with_state<S: Clone + Send + Sync + 'static>(mut self, state: S) -> Self {
+        self.state.insert(state);
+        self
+    }
+}
+
+impl<M: Messages> Default for Router<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Messages> Clone for Router<M> {
+    fn clone(&self) -> Self {
+        Self {
+            routes: self.routes.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<M: Messages> Protocol for Router<M> {
+    type Request = M::Request;
+    type Response = M::Response;
+    type Error = Error;
+    const VERSION: &'static str = M::VERSION;
+    const NAME: &'static str = M::NAME;
+
+    async fn rpc(
+        &mut self,
+        frame: Frame<Self::Request>,
+    ) -> Result<Frame<Self::Response>, Self::Error> {
+        let tag = frame.tag;
+        let ty = frame.msg.message_type();
+        let Some(route) = self.routes.get(&ty).cloned() else {
+            // Answer on the request's tag rather than failing the connection,
+            // and with it the caller's other calls.
+            let status = Status::unimplemented(format!("no route for message type {}", ty));
+            return Frame::error(tag, status).map_err(Error::from);
+        };
+        let metadata = frame.header.metadata.clone();
+        let (res, metadata) = metadata.scope(route.call(frame, &self.state)).await;
+        let mut rframe = Frame::from((tag, res?));
+        rframe.header.metadata = metadata;
+        Ok(rframe)
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Errors sent back to the caller in place of a response.
+//!
+//! A server that refuses a request responds with an error frame, which has
+//! the reserved [`ERROR_FRAME`] type and a [`Status`] for its message:
+//!
+//! ```text
+//! size[4] ERROR_FRAME[1] tag[2] code[1] message[s] retry_after_ms[Option<u64>]
+//! ```
+//!
+//! Responses generated by `#[service]` decode error frames into their `Error`
+//! variant, and clients return them as [`Error::Status`](crate::Error::Status).
+//! Handlers refuse a request the same way, by returning an `Error::Status`,
+//! which the generated service sends back as an error frame.
+
+use {
+    crate::{Frame, Framer},
+    jetstream_wireformat::WireFormat,
+    std::{
+        fmt,
+        io::{self, Read, Write},
+        mem,
+        time::Duration,
+    },
+};
+
+/// Message type reserved for error frames.
+pub const ERROR_FRAME: u8 = 0xFE;
+
+/// Why a request was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Code {
+    /// A code this side doesn't know about.
+    Unknown,
+    /// The server has too much work queued, back off and retry later.
+    Overloaded,
+    /// The caller used up its share of the server, retry once the
+    /// [`Status::retry_after`] has passed.
+    RateLimited,
+    /// The server is shutting down, reconnect elsewhere. Sent once for the
+    /// whole connection rather than in answer to a request.
+    GoingAway,
+    /// The caller isn't allowed to make the request.
+    PermissionDenied,
+    /// The server doesn't handle requests of this type.
+    Unimplemented,
+    /// The request took longer than the server allows.
+    DeadlineExceeded,
+    /// The server isn't in the state the request needs, like having data as
+    /// fresh as the caller asked for.
+    FailedPrecondition,
+    /// The server failed to handle the request.
+    Internal,
+}
+
+impl Code {
+    fn to_u8(self) -> u8 {
+        match self {
+            Code::Unknown => 0,
+            Code::Overloaded => 1,
+            Code::RateLimited => 2,
+            Code::GoingAway => 3,
+            Code::PermissionDenied => 4,
+            Code::Unimplemented => 5,
+            Code::DeadlineExceeded => 6,
+            Code::FailedPrecondition => 7,
+            Code::Internal => 8,
+        }
+    }
+
+    fn from_u8(code: u8) -> Self {
+        match code {
+            1 => Code::Overloaded,
+            2 => Code::RateLimited,
+            3 => Code::GoingAway,
+            4 => Code::PermissionDenied,
+            5 => Code::Unimplemented,
+            6 => Code::DeadlineExceeded,
+            7 => Code::FailedPrecondition,
+            8 => Code::Internal,
+            _ => Code::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Code::Unknown => "unknown",
+            Code::Overloaded => "overloaded",
+            Code::RateLimited => "rate limited",
+            Code::GoingAway => "going away",
+            Code::PermissionDenied => "permission denied",
+            Code::Unimplemented => "unimplemented",
+            Code::DeadlineExceeded => "deadline exceeded",
+            Code::FailedPrecondition => "failed precondition",
+            Code::Internal => "internal",
+        })
+    }
+}
+
+/// An error sent in an error frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub code: Code,
+    pub message: String,
+    /// How long the caller should wait before retrying, if the server knows.
+    pub retry_after: Option<Duration>,
+}
+
+impl Status {
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    /// The server has too much work queued to take on the request.
+    pub fn overloaded(message: impl Into<String>) -> Self {
+        Self::new(Code::Overloaded, message)
+    }
+
+    /// The caller sent more requests than it is allowed to.
+    pub fn rate_limited(message: impl Into<String>) -> Self {
+        Self::new(Code::RateLimited, message)
+    }
+
+    /// The server is shutting down.
+    pub fn going_away(message: impl Into<String>) -> Self {
+        Self::new(Code::GoingAway, message)
+    }
+
+    /// The caller isn't allowed to make the request.
+    pub fn permission_denied(message: impl Into<String>) -> Self {
+        Self::new(Code::PermissionDenied, message)
+    }
+
+    /// The server doesn't handle requests of this type.
+    pub fn unimplemented(message: impl Into<String>) -> Self {
+        Self::new(Code::Unimplemented, message)
+    }
+
+    /// The request took longer than the server allows.
+    pub fn deadline_exceeded(message: impl Into<String>) -> Self {
+        Self::new(Code::DeadlineExceeded, message)
+    }
+
+    /// The server isn't in the state the request needs.
+    pub fn failed_precondition(message: impl Into<String>) -> Self {
+        Self::new(Code::FailedPrecondition, message)
+    }
+
+    /// The server failed to handle the request.
+    pub fn internal(message: impl Into<String>) -> Self {
+        Self::new(Code::Internal, message)
+    }
+
+    pub fn with_retry_after(mut self, retry_after: Duration) -> Self {
+        self.retry_after = Some(retry_after);
+        self
+    }
+
+    fn retry_after_millis(&self) -> Option<u64> {
+        self.retry_after
+            .map(|retry_after| u64::try_from(retry_after.as_millis()).unwrap_or(u64::MAX))
+    }
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for Status {}
+
+impl WireFormat for Status {
+    fn byte_size(&self) -> u32 {
+        mem::size_of::<u8>() as u32
+            + self.message.byte_size()
+            + self.retry_after_millis().byte_size()
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        self.code.to_u8().encode(writer)?;
+        self.message.encode(writer)?;
+        self.retry_after_millis().encode(writer)
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let code: u8 = WireFormat::decode(reader)?;
+        let message = WireFormat::decode(reader)?;
+        let retry_after: Option<u64> = WireFormat::decode(reader)?;
+        Ok(Self {
+            code: Code::from_u8(code),
+            message,
+            retry_after: retry_after.map(Duration::from_millis),
+        })
+    }
+}
+
+impl<T: Framer> Frame<T> {
+    /// Returns an error frame answering the request tagged `tag`, or gives
+    /// `status` back if `T` can't carry it.
+    pub fn error(tag: u16, status: Status) -> Result<Self, Status> {
+        T::from_status(status).map(|msg| Frame::from((tag, msg)))
+    }
+
+    /// Returns the frame a server sends before closing a connection it is
+    /// shutting down. It doesn't answer any request, its tag is `u16::MAX`.
+    pub fn go_away(message: impl Into<String>) -> Result<Self, Status> {
+        Self::error(u16::MAX, Status::going_away(message))
+    }
+
+    /// Returns true if the frame is a [`go_away`](Self::go_away) frame.
+    pub fn is_go_away(&self) -> bool {
+        self.msg
+            .status()
+            .is_some_and(|status| status.code == Code::GoingAway)
+    }
+}
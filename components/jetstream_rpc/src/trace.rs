@@ -0,0 +1,252 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Tracing for RPCs.
+//!
+//! Every call gets a `tracing` span on both ends, named `rpc.client` and
+//! `rpc.server`, with the fields `rpc.service`, `rpc.method`, `tag`,
+//! `trace_id`, `span_id`, `parent_span_id`, `latency_ms` and `error`.
+//!
+//! The client sends its span's [`TraceContext`] in the request header, in
+//! the W3C `traceparent` format, so the server span records the client span
+//! as its parent. The same format is used by OpenTelemetry, so traces can be
+//! joined up with other systems. It has its own header key, apart from the
+//! [`Metadata`], and is sent on every connection that sends headers.
+
+use {
+    crate::{Frame, Framer, Layer, Metadata, Protocol},
+    rand::Rng,
+    std::{fmt, future::Future},
+    tokio::time::Instant,
+    tracing::{field, Instrument, Span},
+};
+
+/// The metadata key the trace context goes under when it's passed along as
+/// metadata, see [`TraceContext::inject`].
+pub const TRACEPARENT: &str = "traceparent";
+
+const SAMPLED: u8 = 0x01;
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+/// A W3C trace context, identifying a span within a distributed trace.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub flags: u8,
+}
+
+impl TraceContext {
+    /// Starts a new, sampled, trace.
+    pub fn new_root() -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            trace_id: rng.gen_range(1..=u128::MAX),
+            span_id: rng.gen_range(1..=u64::MAX),
+            flags: SAMPLED,
+        }
+    }
+
+    /// Returns a new span in the same trace.
+    pub fn child(&self) -> Self {
+        Self {
+            span_id: rand::thread_rng().gen_range(1..=u64::MAX),
+            ..*self
+        }
+    }
+
+    /// Returns true if the caller asked for the trace to be recorded.
+    pub fn is_sampled(&self) -> bool {
+        self.flags & SAMPLED != 0
+    }
+
+    /// Parses a `traceparent` value, `00-{trace_id}-{span_id}-{flags}`.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next().filter(|v| v.len() == 2)?;
+        let trace_id = parts.next().filter(|v| v.len() == 32)?;
+        let span_id = parts.next().filter(|v| v.len() == 16)?;
+        let flags = parts.next().filter(|v| v.len() == 2)?;
+        // Later versions may append fields, version 00 may not.
+        if version == "ff" || (version == "00" && parts.next().is_some()) {
+            return None;
+        }
+        u8::from_str_radix(version, 16).ok()?;
+        let context = Self {
+            trace_id: u128::from_str_radix(trace_id, 16).ok()?,
+            span_id: u64::from_str_radix(span_id, 16).ok()?,
+            flags: u8::from_str_radix(flags, 16).ok()?,
+        };
+        (context.trace_id != 0 && context.span_id != 0).then_some(context)
+    }
+
+    /// Reads the trace context from metadata, for bridging to systems that
+    /// pass it along as metadata.
+    pub fn from_metadata(metadata: &Metadata) -> Option<Self> {
+        metadata.get_str(TRACEPARENT).and_then(Self::parse)
+    }
+
+    /// Writes the trace context into metadata.
+    pub fn inject(&self, metadata: &mut Metadata) {
+        metadata.insert(TRACEPARENT, self.to_string());
+    }
+
+    /// Returns the context of the RPC span the current task runs in.
+    pub fn current() -> Option<Self> {
+        CURRENT.try_with(|context| *context).ok()
+    }
+
+    /// Runs `fut` with `self` as the current context.
+    pub async fn scope<F: Future>(self, fut: F) -> F::Output {
+        CURRENT.scope(self, fut).await
+    }
+
+    fn span(&self, parent: Option<&Self>, span: Span) -> Span {
+        span.record("trace_id", field::display(format_args!("{:032x}", self.trace_id)));
+        span.record("span_id", field::display(format_args!("{:016x}", self.span_id)));
+        if let Some(parent) = parent {
+            span.record(
+                "parent_span_id",
+                field::display(format_args!("{:016x}", parent.span_id)),
+            );
+        }
+        span
+    }
+}
+
+impl fmt::Display for TraceContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id, self.span_id, self.flags
+        )
+    }
+}
+
+async fn instrument<T, E: fmt::Display>(
+    span: Span,
+    context: TraceContext,
+    fut: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let start = Instant::now();
+    let res = context.scope(fut).instrument(span.clone()).await;
+    span.record("latency_ms", start.elapsed().as_secs_f64() * 1000.0);
+    if let Err(err) = &res {
+        span.record("error", field::display(err));
+    }
+    res
+}
+
+/// The `rpc.client` span of a call, see [`client`].
+#[derive(Debug)]
+pub struct ClientSpan {
+    span: Span,
+    context: TraceContext,
+}
+
+impl ClientSpan {
+    /// Runs `fut`, which sends the request and waits for the response, inside
+    /// the span.
+    pub async fn instrument<T, E: fmt::Display>(
+        self,
+        fut: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        instrument(self.span, self.context, fut).await
+    }
+}
+
+/// Starts the `rpc.client` span for sending `frame`, and adds the span's
+/// trace context to the frame's header.
+pub fn client<P: Protocol>(frame: &mut Frame<P::Request>) -> ClientSpan {
+    let parent = TraceContext::current();
+    let context = parent.map_or_else(TraceContext::new_root, |parent| parent.child());
+    frame.header.traceparent = Some(context.to_string());
+    let span = tracing::info_span!(
+        "rpc.client",
+        otel.kind = "client",
+        rpc.service = P::NAME,
+        rpc.method = frame.msg.message_name(),
+        tag = frame.tag,
+        trace_id = field::Empty,
+        span_id = field::Empty,
+        parent_span_id = field::Empty,
+        latency_ms = field::Empty,
+        error = field::Empty,
+    );
+    ClientSpan {
+        span: context.span(parent.as_ref(), span),
+        context,
+    }
+}
+
+/// Calls `protocol.rpc(frame)` inside an `rpc.server` span that continues
+/// the trace the caller sent along, if any.
+pub async fn server<P: Protocol>(
+    protocol: &mut P,
+    frame: Frame<P::Request>,
+) -> Result<Frame<P::Response>, P::Error> {
+    let parent = frame.trace_context();
+    let context = parent.map_or_else(TraceContext::new_root, |parent| parent.child());
+    let span = tracing::info_span!(
+        "rpc.server",
+        otel.kind = "server",
+        rpc.service = P::NAME,
+        rpc.method = frame.msg.message_name(),
+        tag = frame.tag,
+        trace_id = field::Empty,
+        span_id = field::Empty,
+        parent_span_id = field::Empty,
+        latency_ms = field::Empty,
+        error = field::Empty,
+        deadline_ms = field::Empty,
+    );
+    if let Some(timeout) = frame.header.timeout {
+        span.record("deadline_ms", timeout.as_millis() as u64);
+    }
+    let span = context.span(parent.as_ref(), span);
+    instrument(span, context, protocol.rpc(frame)).await
+}
+
+/// Wraps protocols in [`Traced`], for protocols that aren't served with
+/// `jetstream_server::service::run`, which traces requests already.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TraceLayer;
+
+impl<P> Layer<P> for TraceLayer {
+    type Protocol = Traced<P>;
+
+    fn layer(&self, inner: P) -> Self::Protocol {
+        Traced(inner)
+    }
+}
+
+/// A protocol that handles every request inside an `rpc.server` span.
+#[derive(Debug, Clone)]
+pub struct Traced<P>(pub P);
+
+impl<P: Protocol> Protocol for Traced<P> {
+    type Request = P::Request;
+    type Response = P::Response;
+    type Error = P::Error;
+    const VERSION: &'static str = P::VERSION;
+    const NAME: &'static str = P::NAME;
+
+    async fn rpc(
+        &mut self,
+        frame: Frame<Self::Request>,
+    ) -> Result<Frame<Self::Response>, Self::Error> {
+        server(&mut self.0, frame).await
+    }
+}
+
+impl<T: Framer> Frame<T> {
+    /// Returns the trace context the frame carries.
+    pub fn trace_context(&self) -> Option<TraceContext> {
+        self.header.traceparent.as_deref().and_then(TraceContext::parse)
+    }
+}
@@ -6,16 +6,22 @@
 //! ## Feature Flags
 //! - `proxy` - Enables the proxy server
 //! - `quic` - Enables the QUIC server
+//! - `prometheus` - Enables the Prometheus metrics endpoint
+//! - `tls` - Identifies peers by their client certificates
 #![cfg_attr(docsrs, feature(doc_cfg, doc_auto_cfg))]
 // Copyright (c) 2024, Sevki <s@sevki.io>
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
 #[cfg(feature = "proxy")]
 pub mod proxy;
 #[cfg(feature = "quic")]
 pub mod quic;
 
+pub mod peer;
 pub mod service;
+pub mod shutdown;
 
 use {
     std::fmt::Debug,
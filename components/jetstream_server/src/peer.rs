@@ -0,0 +1,163 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Working out who is calling.
+//!
+//! Streams that know their peer implement [`PeerIdentity`]. Serving them with
+//! [`serve`](crate::service::serve) attaches the peer to every request, where
+//! handlers extract it as a [`Peer`] argument and layers find it in the
+//! frame's extensions:
+//!
+//! ```ignore
+//! let (stream, _) = listener.accept().await?;
+//! serve(&mut router, stream).await?;
+//! ```
+//!
+//! Other transports are wrapped in [`Identified`] to the same effect. TLS
+//! transports identify clients by their certificate, see
+//! [`from_certificate`], and QUIC connections do so themselves, see
+//! `quic`.
+
+pub use jetstream_rpc::Peer;
+use {
+    futures::{Sink, Stream},
+    jetstream_rpc::{Frame, Framer},
+    std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    },
+};
+
+/// A stream that knows who is on the other end.
+pub trait PeerIdentity {
+    fn peer(&self) -> io::Result<Peer>;
+}
+
+impl PeerIdentity for tokio::net::TcpStream {
+    fn peer(&self) -> io::Result<Peer> {
+        self.peer_addr().map(Peer::Socket)
+    }
+}
+
+impl PeerIdentity for tokio::net::UnixStream {
+    /// Uses the credentials of the peer process, `SO_PEERCRED` on Linux.
+    fn peer(&self) -> io::Result<Peer> {
+        let cred = self.peer_cred()?;
+        Ok(Peer::Unix {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid: cred.pid(),
+        })
+    }
+}
+
+#[cfg(feature = "vsock")]
+impl PeerIdentity for tokio_vsock::VsockStream {
+    fn peer(&self) -> io::Result<Peer> {
+        let addr = self.peer_addr()?;
+        Ok(Peer::Vsock { cid: addr.cid() })
+    }
+}
+
+/// Identifies a TLS client by the DER encoded certificate it presented.
+#[cfg(feature = "tls")]
+pub fn from_certificate(addr: std::net::SocketAddr, der: &[u8]) -> io::Result<Peer> {
+    use {
+        sha2::{Digest, Sha256},
+        x509_parser::{certificate::X509Certificate, extensions::GeneralName, prelude::FromDer},
+    };
+
+    let (_, certificate) = X509Certificate::from_der(der)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?;
+    let sans = certificate
+        .subject_alternative_name()
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err.to_string()))?
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name)
+                    | GeneralName::RFC822Name(name)
+                    | GeneralName::URI(name) => Some(name.to_string()),
+                    GeneralName::IPAddress(&[a, b, c, d]) => {
+                        Some(std::net::Ipv4Addr::new(a, b, c, d).to_string())
+                    }
+                    GeneralName::IPAddress(ip) => <[u8; 16]>::try_from(*ip)
+                        .ok()
+                        .map(|ip| std::net::Ipv6Addr::from(ip).to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok(Peer::Tls {
+        addr,
+        fingerprint: Sha256::digest(der).into(),
+        subject: certificate.subject().to_string(),
+        sans,
+    })
+}
+
+/// A server transport that attaches the [`Peer`] to every request it reads.
+#[derive(Debug)]
+pub struct Identified<T> {
+    inner: T,
+    peer: Peer,
+}
+
+impl<T> Identified<T> {
+    pub fn new(transport: T, peer: Peer) -> Self {
+        Self {
+            inner: transport,
+            peer,
+        }
+    }
+
+    /// Returns who is on the other end.
+    pub fn peer(&self) -> &Peer {
+        &self.peer
+    }
+}
+
+impl<T, R, E> Stream for Identified<T>
+where
+    T: Stream<Item = Result<Frame<R>, E>> + Unpin,
+    R: Framer,
+{
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).poll_next(cx).map_ok(|mut frame| {
+            frame.extensions.insert(this.peer.clone());
+            frame
+        })
+    }
+}
+
+impl<T, R> Sink<Frame<R>> for Identified<T>
+where
+    T: Sink<Frame<R>> + Unpin,
+    R: Framer,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Frame<R>) -> Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Exposes the metrics in [`jetstream_rpc::metrics`] in the Prometheus text
+//! format.
+//!
+//! ```ignore
+//! let handle = jetstream_server::prometheus::install()?;
+//! let listener = TcpListener::bind("0.0.0.0:9090").await?;
+//! tokio::spawn(jetstream_server::prometheus::serve(listener, handle));
+//! ```
+
+pub use metrics_exporter_prometheus::{BuildError, PrometheusHandle};
+use {
+    metrics_exporter_prometheus::PrometheusBuilder,
+    tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    },
+};
+
+/// The largest request head [`serve`] reads before giving up on a scrape.
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// Installs a Prometheus recorder as the global metrics recorder, and
+/// describes the RPC metrics to it.
+pub fn install() -> Result<PrometheusHandle, BuildError> {
+    let handle = PrometheusBuilder::new().install_recorder()?;
+    jetstream_rpc::metrics::describe();
+    Ok(handle)
+}
+
+/// Answers `GET /metrics` on `listener` with everything `handle` recorded.
+pub async fn serve(listener: TcpListener, handle: PrometheusHandle) -> std::io::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            if let Err(err) = scrape(stream, &handle).await {
+                tracing::debug!(%err, "metrics scrape failed");
+            }
+        });
+    }
+}
+
+async fn scrape(mut stream: TcpStream, handle: &PrometheusHandle) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(std::io::ErrorKind::InvalidData.into());
+        }
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let mut request_line = request.split(|&b| b == b' ');
+    let response = match (request_line.next(), request_line.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            handle.run_upkeep();
+            let body = handle.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            )
+        }
+        _ => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
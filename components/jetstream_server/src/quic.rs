@@ -1 +1,87 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
 
+//! Identifying QUIC clients by their TLS certificates.
+//!
+//! A server started with the [`PeerCertificates`] event subscriber keeps the
+//! certificate every client presented in its handshake, which makes
+//! connections [`PeerIdentity`] streams:
+//!
+//! ```ignore
+//! let mut server = Server::builder()
+//!     .with_tls(tls)?
+//!     .with_event(PeerCertificates)?
+//!     .with_io("127.0.0.1:4433")?
+//!     .start()?;
+//! while let Some(mut connection) = server.accept().await {
+//!     let peer = connection.peer()?;
+//!     while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await {
+//!         let transport = Identified::new(Framed::new(stream, ServerCodec::default()), peer.clone());
+//!         tokio::spawn(async move { run(&mut EchoService { inner: EchoImpl {} }, transport).await });
+//!     }
+//! }
+//! ```
+//!
+//! Clients that didn't present a certificate are identified by their
+//! address.
+
+use {
+    crate::peer::{Peer, PeerIdentity},
+    s2n_quic::{
+        provider::event::{events, ConnectionInfo, ConnectionMeta, Subscriber},
+        Connection,
+    },
+    std::io,
+};
+
+/// An event subscriber that keeps the certificate each client presented.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PeerCertificates;
+
+/// The DER encoded certificate a connection's client presented, if any.
+#[derive(Debug, Default)]
+pub struct PeerCertificate(Option<Vec<u8>>);
+
+impl Subscriber for PeerCertificates {
+    type ConnectionContext = PeerCertificate;
+
+    fn create_connection_context(
+        &mut self,
+        _meta: &ConnectionMeta,
+        _info: &ConnectionInfo,
+    ) -> Self::ConnectionContext {
+        PeerCertificate::default()
+    }
+
+    fn on_tls_exporter_ready(
+        &mut self,
+        context: &mut Self::ConnectionContext,
+        _meta: &ConnectionMeta,
+        event: &events::TlsExporterReady,
+    ) {
+        // The chain starts with the client's own certificate.
+        context.0 = event
+            .session
+            .peer_cert_chain_der()
+            .ok()
+            .and_then(|chain| chain.into_iter().next());
+    }
+}
+
+impl PeerIdentity for Connection {
+    /// Needs the server to have been started with [`PeerCertificates`].
+    fn peer(&self) -> io::Result<Peer> {
+        let addr = self
+            .remote_addr()
+            .map_err(|err| io::Error::new(io::ErrorKind::NotConnected, err))?;
+        let certificate = self
+            .query_event_context(|context: &PeerCertificate| context.0.clone())
+            .map_err(|err| io::Error::other(err.to_string()))?;
+        match certificate {
+            #[cfg(feature = "tls")]
+            Some(der) => crate::peer::from_certificate(addr, &der),
+            _ => Ok(Peer::Socket(addr)),
+        }
+    }
+}
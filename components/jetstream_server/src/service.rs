@@ -1,23 +1,51 @@
 use {
-    jetstream_rpc::{Error, Frame, Protocol, ServiceTransport},
+    crate::peer::{Identified, PeerIdentity},
+    futures::{stream::FuturesUnordered, SinkExt, StreamExt},
+    jetstream_rpc::{
+        metrics::{self, CallMetrics, Side},
+        trace,
+        Deadline,
+        Error,
+        Frame,
+        Framer,
+        Header,
+        Protocol,
+        ServiceTransport,
+        Status,
+    },
     jetstream_wireformat::WireFormat,
-    std::pin::pin,
+    std::{sync::Arc, time::Duration},
+    tokio::{
+        io::{AsyncRead, AsyncWrite},
+        sync::Semaphore,
+        time::{timeout_at, Instant},
+    },
     tokio_util::{
         bytes::{self, Buf, BufMut},
-        codec::{Decoder, Encoder},
+        codec::{Decoder, Encoder, Framed},
     },
 };
 
 pub struct ServerCodec<P: Protocol> {
+    headers: bool,
     _phantom: std::marker::PhantomData<P>,
 }
 
 impl<P: Protocol> ServerCodec<P> {
     pub fn new() -> Self {
         Self {
+            headers: false,
             _phantom: std::marker::PhantomData,
         }
     }
+
+    /// Sends response headers from the start. Otherwise they are only sent
+    /// once the client has sent a header of its own, which shows it
+    /// understands them, so clients that predate frame headers keep working.
+    pub fn with_headers(mut self) -> Self {
+        self.headers = true;
+        self
+    }
 }
 
 impl<P: Protocol> Default for ServerCodec<P> {
@@ -34,9 +62,20 @@ where
     type Item = Frame<P::Request>;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        Frame::<P::Request>::decode(&mut src.reader())
-            .map(Some)
-            .map_err(Error::Io)
+        // Wait until the whole frame, including its size prefix, has been buffered.
+        let Some(size) = src.get(..4) else {
+            return Ok(None);
+        };
+        let size = u32::from_le_bytes([size[0], size[1], size[2], size[3]]) as usize;
+        if src.len() < size {
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+        let frame = src.split_to(size.max(4));
+        let frame = Frame::<P::Request>::decode(&mut frame.reader()).map_err(Error::Io)?;
+        metrics::record_received(Side::Server, P::NAME, frame.msg.message_name(), size);
+        self.headers |= !frame.header.is_empty();
+        Ok(Some(frame))
     }
 }
 
@@ -48,25 +87,203 @@ where
 
     fn encode(
         &mut self,
-        item: Frame<P::Response>,
+        mut item: Frame<P::Response>,
         dst: &mut bytes::BytesMut,
     ) -> Result<(), Self::Error> {
-        item.encode(&mut dst.writer())
-            .map_err(Error::Io)
-            .map(|_| ())
+        if !self.headers {
+            item.header = Header::default();
+        }
+        let start = dst.len();
+        item.encode(&mut dst.writer()).map_err(Error::Io)?;
+        metrics::record_sent(
+            Side::Server,
+            P::NAME,
+            item.msg.message_name(),
+            dst.len() - start,
+        );
+        Ok(())
     }
 }
 
+/// Handles a single request, returning `None` if its deadline passed before
+/// it was handled.
+async fn handle<P: Protocol>(
+    p: &mut P,
+    frame: Frame<P::Request>,
+) -> Option<Result<Frame<P::Response>, P::Error>> {
+    // Dropped unfinished, and so counted as failed, if the deadline passes.
+    let call = CallMetrics::start(Side::Server, P::NAME, frame.msg.message_name());
+    let res = match frame.header.timeout {
+        Some(timeout) => Deadline::after(timeout).timeout(trace::server(p, frame)).await.ok()?,
+        None => trace::server(p, frame).await,
+    };
+    call.respond(&res);
+    Some(res)
+}
+
+/// Handles the requests read from `stream` one at a time until it ends, then
+/// closes it.
 pub async fn run<T, P>(p: &mut P, mut stream: T) -> Result<(), P::Error>
 where
     T: ServiceTransport<P>,
     P: Protocol,
 {
-    use futures::{SinkExt, StreamExt};
-    let mut a = pin!(p);
     while let Some(Ok(frame)) = stream.next().await {
-        let res = a.rpc(frame).await?;
-        stream.send(res).await?
+        // The caller has given up on requests past their deadline, don't bother replying.
+        if let Some(res) = handle(p, frame).await {
+            stream.send(res?).await?
+        }
     }
+    close(stream).await
+}
+
+/// Serves `stream`, framed with a [`ServerCodec`], like [`run`], attaching
+/// who is on the other end of it, as the stream reports, to every request.
+/// For TCP, Unix domain socket and vsock connections.
+pub async fn serve<S, P>(p: &mut P, stream: S) -> Result<(), Error>
+where
+    S: AsyncRead + AsyncWrite + PeerIdentity + Unpin + Send + Sync,
+    P: Protocol<Error = Error>,
+{
+    let peer = stream.peer()?;
+    let transport = Framed::new(stream, ServerCodec::<P>::default());
+    run(p, Identified::new(transport, peer)).await
+}
+
+/// Closes `stream` once there are no more requests. The client may well have
+/// hung up already, so failing to close is no error.
+async fn close<T, P>(mut stream: T) -> Result<(), P::Error>
+where
+    T: ServiceTransport<P>,
+    P: Protocol,
+{
+    let _ = stream.close().await;
     Ok(())
 }
+
+/// A cap on the requests handled at once across every connection sharing it.
+#[derive(Debug, Clone)]
+pub struct GlobalLimit(Arc<Semaphore>);
+
+impl GlobalLimit {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self(Arc::new(Semaphore::new(max_in_flight)))
+    }
+
+    /// Returns how many more requests can be handled right now.
+    pub fn available(&self) -> usize {
+        self.0.available_permits()
+    }
+}
+
+/// Limits on the work a connection served with [`run_with_limits`] takes on.
+///
+/// Requests over a limit are answered with a [`Code::Overloaded`](jetstream_rpc::Code::Overloaded) error
+/// frame, so clients know to back off. They are dropped without a response
+/// if the protocol's responses can't carry an error.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    max_in_flight: Option<usize>,
+    max_wait: Option<Duration>,
+    global: Option<GlobalLimit>,
+}
+
+impl Limits {
+    /// Creates limits that let everything through.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the requests of a connection that were received but not yet
+    /// responded to, whether they are being handled or waiting for room
+    /// under the global limit.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// Caps how long a request may wait for room under the global limit.
+    pub fn with_max_wait(mut self, max_wait: Duration) -> Self {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    /// Shares `limit` with the other connections it was given to.
+    pub fn with_global_limit(mut self, limit: GlobalLimit) -> Self {
+        self.global = Some(limit);
+        self
+    }
+}
+
+/// Like [`run`], but handles the requests read from `stream` concurrently,
+/// each with its own clone of `p`, up to the given [`Limits`], shedding the
+/// rest. Responses are sent as they are ready.
+pub async fn run_with_limits<T, P>(p: &mut P, mut stream: T, limits: &Limits) -> Result<(), P::Error>
+where
+    T: ServiceTransport<P>,
+    P: Protocol + Clone,
+{
+    let mut in_flight = FuturesUnordered::new();
+    let mut eof = false;
+    loop {
+        tokio::select! {
+            biased;
+            Some(res) = in_flight.next(), if !in_flight.is_empty() => {
+                // The caller has given up on requests past their deadline, don't bother replying.
+                if let Some(res) = res {
+                    stream.send(res?).await?
+                }
+            }
+            next = stream.next(), if !eof => match next {
+                Some(Ok(frame)) => {
+                    if limits.max_in_flight.is_some_and(|max| in_flight.len() >= max) {
+                        if let Some(rframe) = overloaded::<P>(&frame) {
+                            stream.send(rframe).await?
+                        }
+                    } else {
+                        in_flight.push(admit(p.clone(), frame, limits));
+                    }
+                }
+                _ => eof = true,
+            },
+            else => return close(stream).await,
+        }
+    }
+}
+
+/// Waits for room under the global limit, then handles `frame`. Requests
+/// that don't get in within the limits' `max_wait` are answered with an
+/// overloaded error instead.
+async fn admit<P: Protocol>(
+    mut p: P,
+    frame: Frame<P::Request>,
+    limits: &Limits,
+) -> Option<Result<Frame<P::Response>, P::Error>> {
+    let _permit = match &limits.global {
+        Some(global) => {
+            let acquire = global.0.clone().acquire_owned();
+            let permit = match limits.max_wait {
+                Some(max_wait) => timeout_at(Instant::now() + max_wait, acquire)
+                    .await
+                    .ok()
+                    .and_then(Result::ok),
+                None => acquire.await.ok(),
+            };
+            match permit {
+                Some(permit) => Some(permit),
+                None => return overloaded::<P>(&frame).map(Ok),
+            }
+        }
+        None => None,
+    };
+    handle(&mut p, frame).await
+}
+
+/// Returns the overloaded error answering `frame`, if the protocol can carry
+/// one, and records the shed request as a failed call.
+fn overloaded<P: Protocol>(frame: &Frame<P::Request>) -> Option<Frame<P::Response>> {
+    let call = CallMetrics::start(Side::Server, P::NAME, frame.msg.message_name());
+    let rframe = Frame::error(frame.tag, Status::overloaded("too many requests in flight"));
+    call.respond(&rframe);
+    rframe.ok()
+}
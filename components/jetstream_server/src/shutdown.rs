@@ -0,0 +1,192 @@
+// Copyright (c) 2024, Sevki <s@sevki.io>
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Graceful shutdown.
+//!
+//! Once [`Shutdown::shutdown`] is called, accept loops stop, and every
+//! connection finishes the requests it has already started on, answers the
+//! ones still buffered with a going away error, sends its client a goaway
+//! frame and closes. Connections still busy when the grace period ends
+//! are dropped.
+//!
+//! ```ignore
+//! let shutdown = Shutdown::new();
+//! while let Some(accepted) = shutdown.accept(listener.accept()).await {
+//!     let (stream, _) = accepted?;
+//!     let transport = shutdown.transport(Framed::new(stream, ServerCodec::default()));
+//!     let mut service = EchoService { inner: EchoImpl {} };
+//!     shutdown.spawn(async move { run(&mut service, transport).await });
+//! }
+//!
+//! // Meanwhile, on SIGTERM:
+//! shutdown.shutdown(Duration::from_secs(30)).await;
+//! ```
+
+use {
+    futures::{ready, Sink, Stream},
+    jetstream_rpc::{Frame, Framer, Status},
+    std::{
+        future::Future,
+        pin::Pin,
+        sync::Mutex,
+        task::{Context, Poll},
+        time::Duration,
+    },
+    tokio::task::JoinHandle,
+    tokio_util::{
+        sync::{CancellationToken, WaitForCancellationFutureOwned},
+        task::TaskTracker,
+    },
+};
+
+/// Coordinates shutting down a server, cloned into everything that takes
+/// part.
+#[derive(Debug, Clone, Default)]
+pub struct Shutdown {
+    draining: CancellationToken,
+    closing: CancellationToken,
+    connections: TaskTracker,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true once shutdown has started.
+    pub fn is_draining(&self) -> bool {
+        self.draining.is_cancelled()
+    }
+
+    /// Waits for shutdown to start.
+    pub async fn draining(&self) {
+        self.draining.cancelled().await
+    }
+
+    /// Waits for `accept`, unless shutdown starts first, in which case no
+    /// more connections should be accepted.
+    pub async fn accept<T>(&self, accept: impl Future<Output = T>) -> Option<T> {
+        tokio::select! {
+            biased;
+            _ = self.draining.cancelled() => None,
+            accepted = accept => Some(accepted),
+        }
+    }
+
+    /// Wraps a server transport so it stops reading requests once shutdown
+    /// starts, and sends a goaway frame when it is closed.
+    pub fn transport<T>(&self, transport: T) -> Draining<T> {
+        Draining {
+            inner: transport,
+            draining: self.draining.clone(),
+            drained: Mutex::new(Box::pin(self.draining.clone().cancelled_owned())),
+            refused: Vec::new(),
+            goaway_sent: false,
+        }
+    }
+
+    /// Spawns a connection's task, which shutdown waits for and drops once
+    /// the grace period is over.
+    pub fn spawn<F>(&self, connection: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let closing = self.closing.clone();
+        self.connections.spawn(async move {
+            tokio::select! {
+                biased;
+                _ = closing.cancelled() => None,
+                out = connection => Some(out),
+            }
+        })
+    }
+
+    /// Starts shutting down and waits for the connections to finish, up to
+    /// `grace`. Returns true if they all finished in time.
+    pub async fn shutdown(&self, grace: Duration) -> bool {
+        self.draining.cancel();
+        self.connections.close();
+        if tokio::time::timeout(grace, self.connections.wait()).await.is_ok() {
+            return true;
+        }
+        self.closing.cancel();
+        self.connections.wait().await;
+        false
+    }
+}
+
+/// A server transport that ends once shutdown starts, see
+/// [`Shutdown::transport`].
+pub struct Draining<T> {
+    inner: T,
+    draining: CancellationToken,
+    // Only ever polled through `&mut self`, the mutex makes it `Sync`.
+    drained: Mutex<Pin<Box<WaitForCancellationFutureOwned>>>,
+    /// The tags of the requests that arrived but weren't handled.
+    refused: Vec<u16>,
+    goaway_sent: bool,
+}
+
+impl<T, Q, E> Stream for Draining<T>
+where
+    T: Stream<Item = Result<Frame<Q>, E>> + Unpin,
+    Q: Framer,
+{
+    type Item = T::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        let drained = this.drained.get_mut().unwrap_or_else(|err| err.into_inner());
+        if this.draining.is_cancelled() || drained.as_mut().poll(cx).is_ready() {
+            // Take the requests that already arrived, to refuse them on close
+            // rather than leave their callers waiting.
+            while let Poll::Ready(Some(Ok(frame))) = Pin::new(&mut this.inner).poll_next(cx) {
+                this.refused.push(frame.tag);
+            }
+            return Poll::Ready(None);
+        }
+        Pin::new(&mut this.inner).poll_next(cx)
+    }
+}
+
+impl<T, R> Sink<Frame<R>> for Draining<T>
+where
+    T: Sink<Frame<R>> + Unpin,
+    R: Framer,
+{
+    type Error = T::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Frame<R>) -> Result<(), Self::Error> {
+        Pin::new(&mut self.get_mut().inner).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let this = self.get_mut();
+        while let Some(&tag) = this.refused.first() {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx))?;
+            if let Ok(refusal) = Frame::error(tag, Status::going_away("server is shutting down")) {
+                Pin::new(&mut this.inner).start_send(refusal)?;
+            }
+            this.refused.remove(0);
+        }
+        if this.draining.is_cancelled() && !this.goaway_sent {
+            ready!(Pin::new(&mut this.inner).poll_ready(cx))?;
+            // Protocols whose responses can't carry errors just hang up.
+            if let Ok(goaway) = Frame::go_away("server is shutting down") {
+                Pin::new(&mut this.inner).start_send(goaway)?;
+            }
+            this.goaway_sent = true;
+        }
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
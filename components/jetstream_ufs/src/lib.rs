@@ -487,12 +487,12 @@ impl Server {
             .ok_or_else(ebadf)?;
 
         // Use an empty Rread struct to figure out the overhead of the header.
-        let header_size = Frame {
-            tag: 0,
-            msg: Rmessage::Read(Rread {
+        let header_size = Frame::from((
+            0,
+            Rmessage::Read(Rread {
                 data: Data(Vec::new()),
             }),
-        }
+        ))
         .byte_size();
 
         let capacity = min(self.cfg.msize - header_size, read.count);
@@ -797,12 +797,12 @@ impl Server {
 
         // Use an empty Rreaddir struct to figure out the maximum number of bytes that
         // can be returned.
-        let header_size = Frame {
-            tag: 0,
-            msg: Rmessage::Readdir(Rreaddir {
+        let header_size = Frame::from((
+            0,
+            Rmessage::Readdir(Rreaddir {
                 data: Data(Vec::new()),
             }),
-        }
+        ))
         .byte_size();
         let count = min(self.cfg.msize - header_size, readdir.count);
         let mut cursor = Cursor::new(Vec::with_capacity(count as usize));
@@ -999,7 +999,7 @@ impl Protocol for Server {
         &mut self,
         frame: Frame<Self::Request>,
     ) -> Result<Frame<Self::Response>, Self::Error> {
-        let Frame { msg, tag } = frame;
+        let Frame { msg, tag, .. } = frame;
         let rmsg = match msg {
             Tmessage::Version(ref version) => self.version(version).map(Rmessage::Version),
             Tmessage::Flush(ref flush) => self.flush(flush).and(Ok(Rmessage::Flush)),
@@ -1040,13 +1040,8 @@ impl Protocol for Server {
             }
         };
         match rmsg {
-            Ok(msg) => Ok(Frame { tag, msg }),
-            Err(e) => {
-                Ok(Frame {
-                    tag,
-                    msg: error_to_rmessage(e),
-                })
-            }
+            Ok(msg) => Ok(Frame::from((tag, msg))),
+            Err(e) => Ok(Frame::from((tag, error_to_rmessage(e)))),
         }
     }
 }
@@ -12,6 +12,7 @@ pub use jetstream_macros::JetStreamWireFormat;
 use {
     bytes::Buf,
     std::{
+        collections::BTreeMap,
         ffi::{CStr, CString, OsStr},
         fmt,
         io::{self, ErrorKind, Read, Write},
@@ -304,6 +305,53 @@ impl<T: WireFormat> WireFormat for Vec<T> {
     }
 }
 
+// Maps are encoded like vectors of their entries: a little endian encoded u16 |N|,
+// followed by |N| keys, each followed by its value, in key order.
+impl<K: WireFormat + Ord, V: WireFormat> WireFormat for BTreeMap<K, V> {
+    fn byte_size(&self) -> u32 {
+        mem::size_of::<u16>() as u32
+            + self
+                .iter()
+                .map(|(key, value)| key.byte_size() + value.byte_size())
+                .sum::<u32>()
+    }
+
+    fn encode<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.len() > u16::MAX as usize {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "too many entries in map",
+            ));
+        }
+
+        (self.len() as u16).encode(writer)?;
+        for (key, value) in self {
+            key.encode(writer)?;
+            value.encode(writer)?;
+        }
+
+        Ok(())
+    }
+
+    fn decode<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let len: u16 = WireFormat::decode(reader)?;
+        let mut result = BTreeMap::new();
+
+        for _ in 0..len {
+            let key = WireFormat::decode(reader)?;
+            let value = WireFormat::decode(reader)?;
+            if result.insert(key, value).is_some() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "duplicate key in map",
+                ));
+            }
+        }
+
+        Ok(result)
+    }
+}
+
 /// A type that encodes an arbitrary number of bytes of data.  Typically used for Rread
 /// Twrite messages.  This differs from a `Vec<u8>` in that it encodes the number of bytes
 /// using a `u32` instead of a `u16`.
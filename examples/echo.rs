@@ -2,7 +2,13 @@ use std::{net::SocketAddr, path::Path};
 
 use {
     echo_protocol::EchoChannel,
-    jetstream::prelude::*,
+    jetstream::prelude::{
+        server::{
+            peer::{Identified, PeerIdentity},
+            quic::PeerCertificates,
+        },
+        *,
+    },
     jetstream_macros::service,
     okstd::prelude::*,
     s2n_quic::{client::Connect, provider::tls, Client, Server},
@@ -17,8 +23,7 @@ struct EchoImpl {}
 
 impl Echo for EchoImpl {
     async fn ping(&mut self) -> Result<(), Error> {
-        eprintln!("Ping received");
-        eprintln!("Pong sent");
+        tracing::info!("ping received");
         Ok(())
     }
 }
@@ -38,26 +43,32 @@ async fn server() -> Result<(), Box<dyn std::error::Error>> {
 
     let mut server = Server::builder()
         .with_tls(tls)?
+        .with_event(PeerCertificates)?
         .with_io("127.0.0.1:4433")?
         .start()?;
 
     while let Some(mut connection) = server.accept().await {
         // spawn a new task for the connection
         tokio::spawn(async move {
-            eprintln!("Connection accepted from {:?}", connection.remote_addr());
+            tracing::info!(remote_addr = ?connection.remote_addr(), "connection accepted");
+            // Who presented the client certificate, for every request on the connection.
+            let Ok(peer) = connection.peer() else {
+                return;
+            };
 
             while let Ok(Some(stream)) = connection.accept_bidirectional_stream().await {
+                let peer = peer.clone();
                 // spawn a new task for the stream
                 tokio::spawn(async move {
-                    eprintln!(
-                        "Stream opened from {:?}",
-                        &stream.connection().remote_addr()
+                    tracing::info!(
+                        remote_addr = ?stream.connection().remote_addr(),
+                        "stream opened"
                     );
                     let echo = EchoImpl {};
                     let servercodec: jetstream::prelude::server::service::ServerCodec<
                         echo_protocol::EchoService<EchoImpl>,
                     > = Default::default();
-                    let framed = Framed::new(stream, servercodec);
+                    let framed = Identified::new(Framed::new(stream, servercodec), peer);
                     let mut serv = echo_protocol::EchoService { inner: echo };
                     server::service::run(&mut serv, framed).await.unwrap();
                 });
@@ -88,19 +99,20 @@ async fn client() -> Result<(), Box<dyn std::error::Error>> {
 
     // open a new stream and split the receiving and sending sides
     let stream = connection.open_bidirectional_stream().await?;
-    let client_codec: jetstream_client::ClientCodec<EchoChannel> = Default::default();
+    // Frame headers carry the trace context, so the server span joins the client's trace.
+    let client_codec = jetstream_client::ClientCodec::<EchoChannel>::default();
     let mut framed = Framed::new(stream, client_codec);
     let mut chan = EchoChannel {
         inner: Box::new(&mut framed),
     };
-    eprintln!("Ping sent");
     chan.ping().await?;
-    eprintln!("Pong received");
+    tracing::info!("pong received");
     Ok(())
 }
 
 #[okstd::main]
 async fn main() {
+    let _ = tracing_subscriber::fmt().try_init();
     tokio::select! {
       _ = server() => {},
       _ = client() => {},
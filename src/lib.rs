@@ -13,13 +13,40 @@ pub mod prelude {
 
     pub use jetstream_rpc::{
         ClientTransport,
+        Code,
+        Context,
         Error,
+        Extensions,
         Frame,
+        FromContext,
+        FromContextParts,
         Framer,
+        Handler,
+        Header,
         Message,
+        Metadata,
+        Peer,
         Protocol,
         ServiceTransport,
+        State,
+        Status,
         Tag,
+        ERROR_FRAME,
+    };
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub use jetstream_rpc::{
+        receive_metadata,
+        with_metadata,
+        with_timeout,
+        ClientTransportExt,
+        Deadline,
+        Layer,
+        Messages,
+        ProtocolBuilder,
+        ProtocolExt,
+        Route,
+        Router,
     };
 
     pub use lazy_static::*;
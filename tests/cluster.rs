@@ -0,0 +1,385 @@
+use {
+    futures::{SinkExt, Stream, StreamExt},
+    jetstream::prelude::*,
+    jetstream_client::ClientCodec,
+    jetstream_distributed::{
+        cluster::{
+            node::{Address, RemoteNode},
+            swim::{self, gossip_protocol, Dial, Swim},
+            Cluster, Health, Member, MemberEvent, Node, NodeId, TransientId,
+        },
+        coordinate::{
+            ping::{ping_protocol, Coordinates, Ping, PingServer, Pinger},
+            Config,
+        },
+    },
+    server::service::{run, ServerCodec},
+    sha2::{Digest, Sha256},
+    std::{
+        collections::BTreeMap,
+        future::Future,
+        io,
+        net::{IpAddr, Ipv4Addr},
+        path::PathBuf,
+        pin::Pin,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    },
+    tokio::net::UnixListener,
+    turmoil::{
+        net::{TcpListener, TcpStream},
+        Builder,
+    },
+};
+
+const PORT: u16 = 1738;
+
+async fn bind() -> std::result::Result<TcpListener, std::io::Error> {
+    TcpListener::bind((IpAddr::from(Ipv4Addr::UNSPECIFIED), PORT)).await
+}
+
+/// Answers pings with a coordinate of its own, forever.
+async fn serve_pings() -> turmoil::Result {
+    let coordinates = Coordinates::new(Config::default())?;
+    let listener = bind().await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let mut serv = ping_protocol::PingService {
+            inner: PingServer::new(coordinates.clone()),
+        };
+        let servercodec = ServerCodec::<ping_protocol::PingService<PingServer>>::default();
+        let framed = Framed::new(stream, servercodec);
+        tokio::spawn(async move { run(&mut serv, framed).await });
+    }
+}
+
+fn round_trips_are_sampled() -> turmoil::Result {
+    let latency = Duration::from_millis(5);
+    let mut sim = Builder::new()
+        .min_message_latency(latency)
+        .max_message_latency(latency)
+        .simulation_duration(Duration::from_secs(60))
+        .build();
+
+    sim.host("a", serve_pings);
+    sim.host("b", serve_pings);
+
+    sim.client("client", async move {
+        let coordinates = Coordinates::new(Config::default())?;
+        let mut pinger = Pinger::new(coordinates.clone(), |addr: String| {
+            async move {
+                let stream = TcpStream::connect((addr.as_str(), PORT)).await?;
+                let codec = ClientCodec::<ping_protocol::PingChannel>::default();
+                Ok(Framed::new(stream, codec))
+            }
+        })
+        .with_peer("a", "a");
+
+        let rtt = pinger.ping("a").await?;
+        assert!(rtt >= 2 * latency);
+        assert!(pinger.ping("b").await.is_err());
+
+        // Members are pinged as they join.
+        let joined = MemberEvent::Join(Member {
+            name: "b".to_string(),
+            addr: "b".to_string(),
+            incarnation: 0,
+            health: Health::Alive,
+            tags: BTreeMap::new(),
+        });
+        // Ends after the join, like the events of a cluster that was dropped.
+        let events = futures::stream::unfold(Some(joined), |joined| async move {
+            joined.map(|joined| (joined, None))
+        });
+        let sampling = pinger.run(Duration::from_millis(50), events);
+        assert!(tokio::time::timeout(Duration::from_secs(10), sampling)
+            .await
+            .is_err());
+        for node in ["a", "b"] {
+            let estimate = coordinates.estimate(node).expect("every peer is pinged");
+            assert!(
+                estimate.abs_diff(rtt) < Duration::from_millis(2),
+                "estimated {:?} to {} rather than {:?}",
+                estimate,
+                node,
+                rtt
+            );
+        }
+        let node = RemoteNode::new(NodeId::Transient(TransientId::from(Sha256::new())), [])
+            .with_coordinates(coordinates.clone(), "b");
+        let local = coordinates.coordinate();
+        assert_eq!(
+            local.distance_to(&node.coordinate()?)?,
+            local.distance_to(&coordinates.peer("b").unwrap())?
+        );
+        Ok(())
+    });
+
+    sim.run()
+}
+
+type GossipTransport = Framed<TcpStream, ClientCodec<gossip_protocol::GossipChannel<'static>>>;
+
+/// Dials members over simulated TCP.
+struct TcpDial;
+
+impl Dial for TcpDial {
+    type Future = Pin<Box<dyn Future<Output = io::Result<GossipTransport>> + Send>>;
+    type Transport = GossipTransport;
+
+    fn dial(&self, addr: &str) -> Self::Future {
+        let addr = addr.to_string();
+        Box::pin(async move {
+            let stream = TcpStream::connect((addr.as_str(), PORT)).await?;
+            Ok(Framed::new(stream, ClientCodec::default()))
+        })
+    }
+}
+
+fn swim_config() -> swim::Config {
+    swim::Config {
+        probe_interval: Duration::from_millis(100),
+        probe_timeout: Duration::from_millis(50),
+        suspicion_timeout: Duration::from_secs(1),
+        ..Default::default()
+    }
+}
+
+/// Serves gossip for `swim`, forever.
+async fn serve_gossip(swim: Swim<TcpDial>) -> io::Result<()> {
+    let listener = bind().await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let mut serv = gossip_protocol::GossipService {
+            inner: swim.server(),
+        };
+        let servercodec = ServerCodec::<gossip_protocol::GossipService<swim::GossipServer<TcpDial>>>::default();
+        let framed = Framed::new(stream, servercodec);
+        tokio::spawn(async move { run(&mut serv, framed).await });
+    }
+}
+
+/// What a member does, a while after it started.
+#[derive(Clone, Copy)]
+enum Then {
+    Stay,
+    Move(Duration, &'static str),
+    Leave(Duration),
+}
+
+/// Runs a member named `name` in zone "a", which joins through `seed` unless
+/// it is the seed.
+async fn member(name: &'static str, seed: &'static str, then: Then) -> turmoil::Result {
+    let swim = Swim::new(name, name, swim_config(), TcpDial);
+    tokio::spawn(serve_gossip(swim.clone()));
+    swim.set_tags(zone("a"));
+    if name != seed {
+        while swim.join(&[seed.to_string()]).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+    let probing = tokio::spawn({
+        let swim = swim.clone();
+        async move { swim.run().await }
+    });
+    match then {
+        Then::Stay => {}
+        Then::Move(after, to) => {
+            tokio::time::sleep(after).await;
+            swim.set_tags(zone(to));
+        }
+        Then::Leave(after) => {
+            tokio::time::sleep(after).await;
+            swim.leave().await?;
+        }
+    }
+    probing.await?;
+    Ok(())
+}
+
+async fn wait_for(events: &mut (impl Stream<Item = MemberEvent> + Unpin), event: MemberEvent) {
+    while let Some(received) = events.next().await {
+        if received == event {
+            return;
+        }
+    }
+    panic!("no more events");
+}
+
+fn zone(zone: &str) -> BTreeMap<String, String> {
+    [("zone".to_string(), zone.to_string())].into()
+}
+
+fn members_gossip() -> turmoil::Result {
+    let latency = Duration::from_millis(1);
+    let mut sim = Builder::new()
+        .min_message_latency(latency)
+        .max_message_latency(latency)
+        .simulation_duration(Duration::from_secs(60))
+        .build();
+
+    sim.host("n1", || member("n1", "n1", Then::Move(Duration::from_millis(1500), "b")));
+    sim.host("n2", || member("n2", "n1", Then::Leave(Duration::from_secs(3))));
+    sim.host("n3", || member("n3", "n1", Then::Stay));
+
+    sim.client("client", async move {
+        let client = Swim::new("client", "client", swim_config(), TcpDial);
+        tokio::spawn(serve_gossip(client.clone()));
+        let mut events = Box::pin(client.subscribe());
+        // Give the others time to join the seed.
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(client.join(&["n1".to_string()]).await?, 1);
+        tokio::spawn({
+            let client = client.clone();
+            async move { client.run().await }
+        });
+
+        let names: Vec<_> = client.members().into_iter().map(|member| member.name).collect();
+        assert_eq!(names, ["client", "n1", "n2", "n3"]);
+        assert!(client
+            .members()
+            .iter()
+            .skip(1)
+            .all(|member| member.health == Health::Alive && member.tags == zone("a")));
+
+        let moved = Member {
+            name: "n1".to_string(),
+            addr: "n1".to_string(),
+            incarnation: 2,
+            health: Health::Alive,
+            tags: zone("b"),
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait_for(&mut events, MemberEvent::Update(moved)))
+            .await?;
+
+        let left = Member {
+            name: "n2".to_string(),
+            addr: "n2".to_string(),
+            incarnation: 1,
+            health: Health::Left,
+            tags: zone("a"),
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait_for(&mut events, MemberEvent::Leave(left)))
+            .await?;
+
+        turmoil::partition("n3", "client");
+        turmoil::partition("n3", "n1");
+        let failed = Member {
+            name: "n3".to_string(),
+            addr: "n3".to_string(),
+            incarnation: 1,
+            health: Health::Dead,
+            tags: zone("a"),
+        };
+        tokio::time::timeout(Duration::from_secs(10), wait_for(&mut events, MemberEvent::Fail(failed)))
+            .await?;
+
+        let health: Vec<_> = client.members().into_iter().map(|member| member.health).collect();
+        assert_eq!(health, [Health::Alive, Health::Alive, Health::Left, Health::Dead]);
+        Ok(())
+    });
+
+    sim.run()
+}
+
+/// Serves pings on a fresh Unix socket, counting the connections.
+fn serve_pings_at(name: &str) -> std::io::Result<(PathBuf, Arc<AtomicUsize>)> {
+    let path = std::env::temp_dir().join(format!("{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let coordinates = Coordinates::new(Config::default()).map_err(std::io::Error::other)?;
+    tokio::spawn({
+        let accepted = accepted.clone();
+        async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted.fetch_add(1, Ordering::SeqCst);
+                let mut serv = ping_protocol::PingService {
+                    inner: PingServer::new(coordinates.clone()),
+                };
+                let servercodec = ServerCodec::<ping_protocol::PingService<PingServer>>::default();
+                let framed = Framed::new(stream, servercodec);
+                tokio::spawn(async move { run(&mut serv, framed).await });
+            }
+        }
+    });
+    Ok((path, accepted))
+}
+
+async fn nodes_are_dialed() -> Result<(), Box<dyn std::error::Error>> {
+    let (path, accepted) = serve_pings_at("nodes-are-dialed")?;
+    let id = NodeId::Transient(TransientId::from(Sha256::new()));
+    // The first address doesn't connect, the second does.
+    let node = RemoteNode::new(
+        id,
+        [
+            Address::Unix(path.with_extension("missing")),
+            format!("unix://{}", path.display()).parse()?,
+        ],
+    );
+
+    let mut transport = node.dial::<ping_protocol::PingChannel>().await?;
+    let mut chan = ping_protocol::PingChannel {
+        inner: Box::new(&mut transport),
+    };
+    chan.ping().await?;
+    drop(transport);
+
+    // A call cut short after its request went out leaves its response on the
+    // connection, so the connection isn't reused.
+    let mut cut = node.dial::<ping_protocol::PingChannel>().await?;
+    let request = ping_protocol::Tmessage::Ping(ping_protocol::Tping {});
+    cut.send(Frame::from((0, request))).await?;
+    drop(cut);
+    let mut transport = node.dial::<ping_protocol::PingChannel>().await?;
+    let mut chan = ping_protocol::PingChannel {
+        inner: Box::new(&mut transport),
+    };
+    chan.ping().await?;
+    drop(transport);
+    assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+    // The idle connection is reused, and a second one is dialed while it's
+    // busy.
+    let mut first = node.dial::<ping_protocol::PingChannel>().await?;
+    let mut chan = ping_protocol::PingChannel {
+        inner: Box::new(&mut first),
+    };
+    chan.ping().await?;
+    let mut second = node.dial::<ping_protocol::PingChannel>().await?;
+    let mut chan = ping_protocol::PingChannel {
+        inner: Box::new(&mut second),
+    };
+    chan.ping().await?;
+    assert_eq!(accepted.load(Ordering::SeqCst), 3);
+
+    let node = RemoteNode::new(node.id(), [Address::Unix(path.with_extension("missing"))]);
+    assert!(node.dial::<ping_protocol::PingChannel>().await.is_err());
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_round_trips_are_sampled() {
+        round_trips_are_sampled().unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_members_gossip() {
+        members_gossip().unwrap()
+    }
+
+    #[okstd::test]
+    async fn test_nodes_are_dialed() {
+        nodes_are_dialed().await.unwrap()
+    }
+}
@@ -1,8 +1,46 @@
 use {
     echo_protocol::EchoChannel,
+    futures::{SinkExt, StreamExt},
     jetstream::prelude::*,
-    server::service::run,
-    std::net::{IpAddr, Ipv4Addr},
+    jetstream_client::{reconnect::Reconnect, ClientCodec},
+    jetstream_distributed::access_control::{
+        authorize::AuthorizeLayer,
+        consistency::Consistency,
+        service::{acl_protocol, AclClient, AclServer},
+        store::MemoryStore,
+        tuple::{Object, User},
+        zanzibar::{UsersetTree, Zanzibar},
+        AccessControl,
+        Resource,
+        Subject,
+        Verb,
+    },
+    server::{
+        peer::{from_certificate, Identified, PeerIdentity},
+        service::{self, run, run_with_limits, GlobalLimit, Limits},
+        shutdown::Shutdown,
+    },
+    jetstream_rpc::{
+        layer::{layer_fn, TimeoutLayer},
+        rate_limit::RateLimitLayer,
+    },
+    metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter},
+    std::{
+        collections::HashMap,
+        net::{IpAddr, Ipv4Addr},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+            Mutex,
+        },
+        time::Duration,
+    },
+    tracing::{
+        field::{Field, Visit},
+        span,
+        Subscriber,
+    },
+    tracing_subscriber::{layer::SubscriberExt, registry::LookupSpan, Registry},
     turmoil::{
         net::{TcpListener, TcpStream},
         Builder,
@@ -11,15 +49,35 @@ use {
 
 #[service]
 pub trait Echo {
+    #[idempotent]
     async fn ping(&mut self) -> Result<(), Error>;
+    async fn shout(&mut self, #[resource] message: String) -> Result<String, Error>;
+    async fn sleep(&mut self, millis: u64) -> Result<bool, Error>;
+    async fn tenant(&mut self) -> Result<Option<String>, Error>;
 }
 
+#[derive(Clone)]
 struct EchoImpl {}
 
 impl Echo for EchoImpl {
     async fn ping(&mut self) -> Result<(), Error> {
         Ok(())
     }
+
+    async fn shout(&mut self, message: String) -> Result<String, Error> {
+        Ok(message.to_uppercase())
+    }
+
+    /// Sleeps for `millis` and reports whether the caller set a deadline.
+    async fn sleep(&mut self, millis: u64) -> Result<bool, Error> {
+        tokio::time::sleep(Duration::from_millis(millis)).await;
+        Ok(Deadline::current().is_some())
+    }
+
+    async fn tenant(&mut self) -> Result<Option<String>, Error> {
+        Metadata::from_iter([("served-by", "echo")]).add_to_response();
+        Ok(Metadata::current().and_then(|metadata| metadata.get_str("tenant").map(String::from)))
+    }
 }
 
 const PORT: u16 = 1738;
@@ -65,6 +123,824 @@ fn network_partitions_during_connect() -> turmoil::Result {
     sim.run()
 }
 
+fn reconnects_after_connection_loss() -> turmoil::Result {
+    let mut sim = Builder::new().build();
+
+    // A flaky server that hangs up after answering a single request.
+    sim.host("server", || {
+        async {
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let servercodec: jetstream::prelude::server::service::ServerCodec<
+                    echo_protocol::EchoService<EchoImpl>,
+                > = Default::default();
+                let mut framed = Framed::new(stream, servercodec);
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                if let Some(Ok(frame)) = framed.next().await {
+                    let res = serv.rpc(frame).await?;
+                    framed.send(res).await?;
+                }
+            }
+        }
+    });
+
+    sim.client("client", async {
+        let mut transport = Reconnect::<EchoChannel, _>::new(|| {
+            async {
+                let stream = TcpStream::connect(("server", PORT)).await?;
+                Ok(Framed::new(stream, ClientCodec::<EchoChannel>::default()))
+            }
+        });
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut transport),
+        };
+        chan.ping().await?;
+        // The server hung up, ping is idempotent so it is replayed on a new connection.
+        chan.ping().await?;
+        // shout isn't idempotent, so losing the connection fails the call...
+        assert!(chan.shout("hello".to_string()).await.is_err());
+        // ...but doesn't break the calls that come after it.
+        assert_eq!(chan.shout("hello".to_string()).await?, "HELLO");
+
+        // Every request in flight is replayed, not just the last one sent.
+        let ping = || echo_protocol::Tmessage::Ping(echo_protocol::Tping {});
+        transport.send(Frame::from((1, ping()))).await?;
+        transport.send(Frame::from((2, ping()))).await?;
+        for tag in [1, 2] {
+            assert_eq!(transport.next().await.unwrap()?.tag, tag);
+        }
+        Ok(())
+    });
+
+    sim.run()
+}
+
+fn deadlines_are_enforced() -> turmoil::Result {
+    let mut sim = Builder::new().build();
+
+    sim.host("server", || {
+        async {
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let servercodec: jetstream::prelude::server::service::ServerCodec<
+                    echo_protocol::EchoService<EchoImpl>,
+                > = Default::default();
+                let framed = Framed::new(stream, servercodec);
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                tokio::spawn(async move { run(&mut serv, framed).await });
+            }
+        }
+    });
+
+    sim.client("client", async {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default().with_headers());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        assert!(!chan.sleep(10).await?);
+        // The deadline travels with the request and is visible to the handler.
+        assert!(with_timeout(Duration::from_secs(1), chan.sleep(10)).await?);
+        let res = with_timeout(Duration::from_millis(100), chan.sleep(5000)).await;
+        assert!(matches!(res, Err(Error::Timeout)));
+        // The connection is still usable after a call times out.
+        chan.ping().await?;
+
+        // Without headers the deadline is only enforced by the client.
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        assert!(!with_timeout(Duration::from_secs(1), chan.sleep(10)).await?);
+        Ok(())
+    });
+
+    sim.run()
+}
+
+fn metadata_is_sent_when_enabled() -> turmoil::Result {
+    let mut sim = Builder::new().build();
+
+    sim.host("server", || {
+        async {
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let servercodec: jetstream::prelude::server::service::ServerCodec<
+                    echo_protocol::EchoService<EchoImpl>,
+                > = Default::default();
+                let framed = Framed::new(stream, servercodec);
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                tokio::spawn(async move { run(&mut serv, framed).await });
+            }
+        }
+    });
+
+    sim.client("client", async {
+        let tenant = Metadata::from_iter([("tenant", "acme")]);
+
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default().with_headers());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        assert_eq!(chan.tenant().await?, None);
+        let (res, received) = receive_metadata(with_metadata(tenant.clone(), chan.tenant())).await;
+        assert_eq!(res?.as_deref(), Some("acme"));
+        // The metadata the handler added to the response comes back with it.
+        assert_eq!(received.get_str("served-by"), Some("echo"));
+
+        // Connections to servers that predate frame headers leave them off the wire.
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        let (res, received) = receive_metadata(with_metadata(tenant, chan.tenant())).await;
+        assert_eq!(res?, None);
+        assert!(received.is_empty());
+        Ok(())
+    });
+
+    sim.run()
+}
+
+async fn shout(State(suffix): State<String>, msg: echo_protocol::Tshout) -> Result<String, Error> {
+    Ok(format!("{}{}", msg.message.to_uppercase(), suffix))
+}
+
+fn router_dispatches_to_handlers() -> turmoil::Result {
+    let mut sim = Builder::new().build();
+
+    sim.host("server", || {
+        async {
+            let router = Router::<echo_protocol::EchoMessages>::new()
+                .route::<echo_protocol::Tping, _>(|| async { Ok(()) })
+                .route::<echo_protocol::Tshout, _>(shout)
+                .route::<echo_protocol::Ttenant, _>(|tag: Tag, metadata: Metadata| {
+                    async move {
+                        Metadata::from_iter([("served-by", "router")]).add_to_response();
+                        let tenant = metadata.get_str("tenant").unwrap_or_default();
+                        Ok(Some(format!("{}@{}", tenant, u16::from(tag))))
+                    }
+                })
+                .with_state("!".to_string());
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let servercodec: jetstream::prelude::server::service::ServerCodec<
+                    Router<echo_protocol::EchoMessages>,
+                > = Default::default();
+                let framed = Framed::new(stream, servercodec);
+                let mut router = router.clone();
+                tokio::spawn(async move { run(&mut router, framed).await });
+            }
+        }
+    });
+
+    sim.client("client", async {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default().with_headers());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        chan.ping().await?;
+        assert_eq!(chan.shout("hello".to_string()).await?, "HELLO!");
+        let tenant = Metadata::from_iter([("tenant", "acme")]);
+        let (res, received) = receive_metadata(with_metadata(tenant, chan.tenant())).await;
+        assert!(res?.is_some_and(|tenant| tenant.starts_with("acme@")));
+        assert_eq!(received.get_str("served-by"), Some("router"));
+        // Sleep has no route, which fails the call but not the connection.
+        let Err(Error::Status(status)) = chan.sleep(1).await else {
+            panic!("expected sleep to be unimplemented");
+        };
+        assert_eq!(status.code, Code::Unimplemented);
+        chan.ping().await?;
+        Ok(())
+    });
+
+    sim.run()
+}
+
+async fn serve<P: Protocol<Error = Error>>(p: &mut P, stream: TcpStream) -> Result<(), Error> {
+    let servercodec = jetstream::prelude::server::service::ServerCodec::<P>::default();
+    run(p, Framed::new(stream, servercodec)).await
+}
+
+/// Counts the requests that reach the protocol it wraps.
+struct Counted<P> {
+    inner: P,
+    calls: Arc<AtomicUsize>,
+}
+
+impl<P: Protocol> Protocol for Counted<P> {
+    type Request = P::Request;
+    type Response = P::Response;
+    type Error = P::Error;
+    const VERSION: &'static str = P::VERSION;
+    const NAME: &'static str = P::NAME;
+
+    async fn rpc(
+        &mut self,
+        frame: Frame<Self::Request>,
+    ) -> Result<Frame<Self::Response>, Self::Error> {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        self.inner.rpc(frame).await
+    }
+}
+
+fn layers_wrap_generated_services() -> turmoil::Result {
+    let mut sim = Builder::new().build();
+    let calls = Arc::new(AtomicUsize::new(0));
+
+    let counter = calls.clone();
+    sim.host("server", move || {
+        let calls = counter.clone();
+        async move {
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let calls = calls.clone();
+                let mut serv = ProtocolBuilder::new()
+                    .layer(layer_fn(move |inner| {
+                        Counted {
+                            inner,
+                            calls: calls.clone(),
+                        }
+                    }))
+                    .layer(TimeoutLayer::new(Duration::from_millis(100)))
+                    .service(echo_protocol::EchoService { inner: EchoImpl {} });
+                tokio::spawn(async move { serve(&mut serv, stream).await });
+            }
+        }
+    });
+
+    sim.client("client", async move {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        chan.ping().await?;
+        assert_eq!(chan.shout("hello".to_string()).await?, "HELLO");
+        // The timeout layer gives up on the request, but not the connection.
+        let Err(Error::Status(status)) = chan.sleep(1000).await else {
+            panic!("expected sleep to time out");
+        };
+        assert_eq!(status.code, Code::DeadlineExceeded);
+        assert_eq!(status.message, "gave up after 100ms");
+        chan.ping().await?;
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+
+        // A caller in more of a hurry than the layer is told when it gave up.
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default().with_headers());
+        let sleep = echo_protocol::Tmessage::Sleep(echo_protocol::Tsleep { millis: 1000 });
+        let mut frame = Frame::from((1, sleep));
+        frame.header.timeout = Some(Duration::from_millis(50));
+        framed.send(frame).await?;
+        let rframe = framed.next().await.unwrap()?;
+        let status = rframe.msg.status().unwrap();
+        assert_eq!(status.code, Code::DeadlineExceeded);
+        assert_eq!(status.message, "gave up after 50ms");
+        Ok(())
+    });
+
+    sim.run()
+}
+
+/// The fields of a span, as recorded by [`SpanRecorder`].
+#[derive(Debug, Default, Clone)]
+struct Fields(HashMap<String, String>);
+
+impl Visit for Fields {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.0.insert(field.name().to_string(), format!("{:?}", value));
+    }
+}
+
+/// Collects the name and fields of every span once it closes.
+#[derive(Debug, Default, Clone)]
+struct SpanRecorder(Arc<Mutex<Vec<(String, Fields)>>>);
+
+impl SpanRecorder {
+    fn spans(&self, name: &str) -> Vec<Fields> {
+        let spans = self.0.lock().unwrap();
+        spans
+            .iter()
+            .filter(|(span, _)| span == name)
+            .map(|(_, fields)| fields.clone())
+            .collect()
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for SpanRecorder {
+    fn on_new_span(
+        &self,
+        attrs: &span::Attributes<'_>,
+        id: &span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        ctx.span(id).unwrap().extensions_mut().insert(fields);
+    }
+
+    fn on_record(
+        &self,
+        id: &span::Id,
+        values: &span::Record<'_>,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let span = ctx.span(id).unwrap();
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<Fields>() {
+            values.record(fields);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: tracing_subscriber::layer::Context<'_, S>) {
+        let span = ctx.span(&id).unwrap();
+        let fields = span.extensions().get::<Fields>().cloned().unwrap_or_default();
+        self.0.lock().unwrap().push((span.name().to_string(), fields));
+    }
+}
+
+fn rpcs_are_traced(recorder: SpanRecorder) -> turmoil::Result {
+    let mut sim = Builder::new().build();
+
+    sim.host("server", || {
+        async {
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                tokio::spawn(async move { serve(&mut serv, stream).await });
+            }
+        }
+    });
+
+    // No metadata set: the trace context goes along on any connection with
+    // headers.
+    sim.client("client", async {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default().with_headers());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        chan.ping().await?;
+        Ok(())
+    });
+
+    sim.run()?;
+
+    let client = recorder.spans("rpc.client");
+    let server = recorder.spans("rpc.server");
+    assert_eq!(client.len(), 1);
+    assert_eq!(server.len(), 1);
+    let (client, server) = (&client[0].0, &server[0].0);
+    for span in [client, server] {
+        assert_eq!(span["rpc.service"], "Echo");
+        assert_eq!(span["rpc.method"], "ping");
+        assert!(span.contains_key("latency_ms"));
+        assert!(!span.contains_key("error"));
+    }
+    // The client span is the parent of the server span.
+    assert_eq!(client["trace_id"], server["trace_id"]);
+    assert_eq!(client["span_id"], server["parent_span_id"]);
+    assert_ne!(client["span_id"], server["span_id"]);
+    Ok(())
+}
+
+/// Returns the recorded metrics, keyed by name and `/`-joined label values.
+fn snapshot(snapshotter: &Snapshotter) -> HashMap<(String, String), DebugValue> {
+    snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .map(|(key, _, _, value)| {
+            let (_, key) = key.into_parts();
+            let labels: Vec<_> = key.labels().map(|l| l.value().to_string()).collect();
+            ((key.name().to_string(), labels.join("/")), value)
+        })
+        .collect()
+}
+
+fn rpcs_are_measured() -> turmoil::Result {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    let mut sim = Builder::new().build();
+
+    sim.host("server", || {
+        async {
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                tokio::spawn(async move { serve(&mut serv, stream).await });
+            }
+        }
+    });
+
+    sim.client("client", async {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        chan.ping().await?;
+        assert_eq!(chan.shout("hi".to_string()).await?, "HI");
+        Ok(())
+    });
+
+    metrics::with_local_recorder(&recorder, || sim.run())?;
+
+    let metrics = snapshot(&snapshotter);
+    for side in ["client", "server"] {
+        for method in ["ping", "shout"] {
+            let get = |metric: &str| {
+                metrics.get(&(format!("jetstream_{side}_{metric}"), format!("Echo/{method}")))
+            };
+            assert_eq!(get("requests_total"), Some(&DebugValue::Counter(1)));
+            assert_eq!(get("errors_total"), None);
+            assert!(matches!(get("requests_in_flight"), Some(DebugValue::Gauge(g)) if g.0 == 0.0));
+            assert!(matches!(get("request_duration_seconds"), Some(DebugValue::Histogram(h)) if h.len() == 1));
+            assert!(matches!(get("sent_bytes_total"), Some(DebugValue::Counter(n)) if *n > 0));
+            assert!(matches!(get("received_bytes_total"), Some(DebugValue::Counter(n)) if *n > 0));
+        }
+    }
+    Ok(())
+}
+
+fn overloaded_requests_are_shed() -> turmoil::Result {
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    // The test depends on the timing of requests, keep the network out of it.
+    let mut sim = Builder::new()
+        .min_message_latency(Duration::from_millis(1))
+        .max_message_latency(Duration::from_millis(1))
+        .build();
+
+    sim.host("server", || {
+        async {
+            let listener = bind().await?;
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let servercodec = jetstream::prelude::server::service::ServerCodec::<
+                    echo_protocol::EchoService<EchoImpl>,
+                >::default();
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                let limits = Limits::new().with_max_in_flight(2);
+                tokio::spawn(async move {
+                    run_with_limits(&mut serv, Framed::new(stream, servercodec), &limits).await
+                });
+            }
+        }
+    });
+
+    sim.client("client", async {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        // Pipeline a third request while the first two are being handled.
+        let sleep = |millis| echo_protocol::Tmessage::Sleep(echo_protocol::Tsleep { millis });
+        framed.send(Frame::from((1, sleep(100)))).await?;
+        framed.send(Frame::from((2, sleep(50)))).await?;
+        let ping = echo_protocol::Tmessage::Ping(echo_protocol::Tping {});
+        framed.send(Frame::from((3, ping))).await?;
+
+        let rejected = framed.next().await.unwrap()?;
+        assert_eq!(rejected.tag, 3);
+        assert_eq!(rejected.msg.status().map(|status| status.code), Some(Code::Overloaded));
+        // Both sleeps run at once, the shorter one finishes first.
+        for tag in [2, 1] {
+            let handled = framed.next().await.unwrap()?;
+            assert_eq!(handled.tag, tag);
+            assert!(matches!(handled.msg, echo_protocol::Rmessage::Sleep(_)));
+        }
+        Ok(())
+    });
+
+    metrics::with_local_recorder(&recorder, || sim.run())?;
+
+    // The shed ping counts as a failed call, the sleeps as successful ones.
+    let metrics = snapshot(&snapshotter);
+    let errors = |method: &str| {
+        metrics.get(&("jetstream_server_errors_total".to_string(), format!("Echo/{method}")))
+    };
+    assert_eq!(errors("ping"), Some(&DebugValue::Counter(1)));
+    assert_eq!(errors("sleep"), None);
+    Ok(())
+}
+
+fn global_limit_sheds_requests_that_wait_too_long() -> turmoil::Result {
+    // The test depends on the timing of requests, keep the network out of it.
+    let mut sim = Builder::new()
+        .min_message_latency(Duration::from_millis(1))
+        .max_message_latency(Duration::from_millis(1))
+        .build();
+
+    sim.host("server", || {
+        async {
+            let listener = bind().await?;
+            let limits = Limits::new()
+                .with_global_limit(GlobalLimit::new(1))
+                .with_max_wait(Duration::from_millis(10));
+            loop {
+                let (stream, _) = listener.accept().await?;
+                let servercodec = jetstream::prelude::server::service::ServerCodec::<
+                    echo_protocol::EchoService<EchoImpl>,
+                >::default();
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                let limits = limits.clone();
+                tokio::spawn(async move {
+                    run_with_limits(&mut serv, Framed::new(stream, servercodec), &limits).await
+                });
+            }
+        }
+    });
+
+    sim.client("busy", async {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        chan.sleep(100).await?;
+        Ok(())
+    });
+
+    sim.client("shed", async {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        let mut chan = EchoChannel {
+            inner: Box::new(&mut framed),
+        };
+        let err = chan.ping().await.unwrap_err();
+        assert!(matches!(err, Error::Status(status) if status.code == Code::Overloaded));
+        // Once the busy client is done there is room again.
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        chan.ping().await?;
+        Ok(())
+    });
+
+    sim.run()
+}
+
+async fn noisy_clients_are_rate_limited() -> Result<(), Error> {
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+    let addr = listener.local_addr()?;
+    let limit = RateLimitLayer::new(10.0, 2).with_cost("shout", 2);
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let mut serv = echo_protocol::EchoService { inner: EchoImpl {} }.layer(&limit);
+            // Keyed by the address serve attaches to every request.
+            tokio::spawn(async move { service::serve(&mut serv, stream).await });
+        }
+    });
+    // Every loopback address is a different host.
+    let connect = |host: Ipv4Addr| async move {
+        let socket = tokio::net::TcpSocket::new_v4()?;
+        socket.bind((host, 0).into())?;
+        let stream = socket.connect(addr).await?;
+        Ok::<_, std::io::Error>(Framed::new(stream, ClientCodec::<EchoChannel>::default()))
+    };
+
+    let mut framed = connect(Ipv4Addr::new(127, 0, 0, 1)).await?;
+    let mut chan = EchoChannel {
+        inner: Box::new(&mut framed),
+    };
+    chan.ping().await?;
+    chan.ping().await?;
+    let Err(Error::Status(status)) = chan.ping().await else {
+        panic!("expected the third ping to be rate limited");
+    };
+    assert_eq!(status.code, Code::RateLimited);
+    let retry_after = status.retry_after.unwrap();
+    assert!(retry_after > Duration::ZERO && retry_after <= Duration::from_millis(100));
+
+    // Another connection from the same host shares its bucket.
+    let mut framed = connect(Ipv4Addr::new(127, 0, 0, 1)).await?;
+    let mut chan = EchoChannel {
+        inner: Box::new(&mut framed),
+    };
+    tokio::time::sleep(retry_after).await;
+    // A token has come back, but shouting takes two.
+    let Err(Error::Status(status)) = chan.shout("hi".to_string()).await else {
+        panic!("expected the shout to be rate limited");
+    };
+    assert_eq!(status.code, Code::RateLimited);
+    chan.ping().await?;
+
+    // Has a bucket of its own.
+    let mut framed = connect(Ipv4Addr::new(127, 0, 0, 2)).await?;
+    let mut chan = EchoChannel {
+        inner: Box::new(&mut framed),
+    };
+    assert_eq!(chan.shout("hi".to_string()).await?, "HI");
+    Ok(())
+}
+
+fn shutdown_drains_connections() -> turmoil::Result {
+    // The test depends on the timing of requests, keep the network out of it.
+    let mut sim = Builder::new()
+        .min_message_latency(Duration::from_millis(1))
+        .max_message_latency(Duration::from_millis(1))
+        .build();
+    let shutdown = Shutdown::new();
+
+    sim.host("server", move || {
+        let shutdown = shutdown.clone();
+        async move {
+            let listener = bind().await?;
+            let trigger = shutdown.clone();
+            let drained = tokio::spawn(async move {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                trigger.shutdown(Duration::from_millis(500)).await
+            });
+            while let Some(accepted) = shutdown.accept(listener.accept()).await {
+                let (stream, _) = accepted?;
+                let servercodec = jetstream::prelude::server::service::ServerCodec::<
+                    echo_protocol::EchoService<EchoImpl>,
+                >::default();
+                let transport = shutdown.transport(Framed::new(stream, servercodec));
+                let mut serv = echo_protocol::EchoService { inner: EchoImpl {} };
+                shutdown.spawn(async move { run(&mut serv, transport).await });
+            }
+            assert!(drained.await?, "connections weren't drained in time");
+            Ok(())
+        }
+    });
+
+    sim.client("client", async {
+        let stream = TcpStream::connect(("server", PORT)).await?;
+        let mut framed = Framed::new(stream, ClientCodec::<EchoChannel>::default());
+        // Still in flight when the server starts shutting down.
+        let sleep = echo_protocol::Tmessage::Sleep(echo_protocol::Tsleep { millis: 100 });
+        framed.send(Frame::from((1, sleep))).await?;
+        // Read by the server after it started shutting down.
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        let ping = echo_protocol::Tmessage::Ping(echo_protocol::Tping {});
+        framed.send(Frame::from((2, ping))).await?;
+
+        let handled = framed.next().await.unwrap()?;
+        assert_eq!(handled.tag, 1);
+        assert!(matches!(handled.msg, echo_protocol::Rmessage::Sleep(_)));
+        let refused = framed.next().await.unwrap()?;
+        assert_eq!(refused.tag, 2);
+        assert_eq!(refused.msg.status().map(|status| status.code), Some(Code::GoingAway));
+        let goaway = framed.next().await.unwrap()?;
+        assert!(goaway.is_go_away());
+        assert!(framed.next().await.is_none());
+        Ok(())
+    });
+
+    sim.run()
+}
+
+async fn unix_peers_are_identified() -> Result<(), Error> {
+    let (server, client) = tokio::net::UnixStream::pair()?;
+    let uid = client.peer_cred()?.uid();
+
+    let mut router = Router::<echo_protocol::EchoMessages>::new().route::<echo_protocol::Ttenant, _>(
+        |peer: Peer| async move {
+            match peer {
+                Peer::Unix { uid, .. } => Ok(Some(format!("uid:{uid}"))),
+                _ => Ok(None),
+            }
+        },
+    );
+    tokio::spawn(async move { service::serve(&mut router, server).await });
+
+    let mut framed = Framed::new(client, ClientCodec::<EchoChannel>::default());
+    let mut chan = EchoChannel {
+        inner: Box::new(&mut framed),
+    };
+    assert_eq!(chan.tenant().await?, Some(format!("uid:{uid}")));
+    Ok(())
+}
+
+fn client_certificates_identify_peers() -> Result<(), Box<dyn std::error::Error>> {
+    let pem = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/certs/client-cert.pem"))?;
+    let (_, pem) = x509_parser::pem::parse_x509_pem(&pem)?;
+    let addr = (Ipv4Addr::LOCALHOST, 4433).into();
+    let peer = from_certificate(addr, &pem.contents)?;
+    // Authorized by the name it was issued for, not the whole subject.
+    assert_eq!(peer.id(), "localhost");
+    let Peer::Tls {
+        fingerprint,
+        subject,
+        sans,
+        ..
+    } = peer
+    else {
+        panic!("expected a TLS peer");
+    };
+    assert!(subject.contains("CN=client.localhost"), "{subject}");
+    assert!(sans.iter().any(|san| san == "localhost"), "{sans:?}");
+    assert_eq!(fingerprint[..4], [0x72, 0x09, 0x55, 0x3A]);
+    Ok(())
+}
+
+/// Lets one local user make any request, except shout secrets.
+struct OwnerOnly {
+    uid: u32,
+}
+
+impl AccessControl for OwnerOnly {
+    async fn check(&self, subject: impl Subject, _: impl Verb, resource: impl Resource) -> bool {
+        subject.id() == format!("uid:{}", self.uid) && resource.id() != "secret"
+    }
+}
+
+async fn requests_are_authorized() -> Result<(), Error> {
+    let (server, client) = tokio::net::UnixStream::pair()?;
+    let uid = client.peer_cred()?.uid();
+
+    let peer = server.peer()?;
+    let servercodec = jetstream::prelude::server::service::ServerCodec::<
+        echo_protocol::EchoService<EchoImpl>,
+    >::default();
+    let transport = Identified::new(Framed::new(server, servercodec), peer);
+    let mut serv = echo_protocol::EchoService { inner: EchoImpl {} }
+        .layer(AuthorizeLayer::new(OwnerOnly { uid }));
+    tokio::spawn(async move { run(&mut serv, transport).await });
+
+    let mut framed = Framed::new(client, ClientCodec::<EchoChannel>::default());
+    let mut chan = EchoChannel {
+        inner: Box::new(&mut framed),
+    };
+    chan.ping().await?;
+    assert_eq!(chan.shout("hello".to_string()).await?, "HELLO");
+    let Err(Error::Status(status)) = chan.shout("secret".to_string()).await else {
+        panic!("expected the secret to be refused");
+    };
+    assert_eq!(status.code, Code::PermissionDenied);
+    Ok(())
+}
+
+async fn permission_servers_are_shared() -> Result<(), Error> {
+    let (server, client) = tokio::net::UnixStream::pair()?;
+    let servercodec = jetstream::prelude::server::service::ServerCodec::<
+        acl_protocol::AclService<AclServer<MemoryStore>>,
+    >::default();
+    let acl = Arc::new(Zanzibar::new(MemoryStore::new()));
+    let mut serv = acl_protocol::AclService {
+        inner: AclServer::new(acl),
+    };
+    tokio::spawn(async move { run(&mut serv, Framed::new(server, servercodec)).await });
+    let acl = Arc::new(AclClient::new(Framed::new(
+        client,
+        ClientCodec::<acl_protocol::AclChannel>::default(),
+    )));
+
+    let (server, client) = tokio::net::UnixStream::pair()?;
+    let uid = client.peer_cred()?.uid();
+    let user: User = format!("uid:{}", uid).parse().unwrap();
+    let tuple = format!("doc:design#shout@uid:{}", uid).parse().unwrap();
+    let zookie = acl.write(vec![tuple]).await?;
+    let fresh = Consistency::AtLeastAsFresh(zookie);
+    let doc: Object = "doc:design".parse().unwrap();
+    assert!(acl.check_user(doc.clone(), "shout", user.clone(), fresh).await?);
+    assert_eq!(acl.read(doc.clone(), "shout", fresh).await?, vec![user.clone()]);
+    let UsersetTree::Leaf { users, .. } = acl.expand(doc.clone(), "shout", fresh).await? else {
+        panic!("expected the tuples of doc:design#shout");
+    };
+    assert_eq!(users, vec![user.clone()]);
+
+    // The echo service asks the permission server who may shout what.
+    let peer = server.peer()?;
+    let servercodec = jetstream::prelude::server::service::ServerCodec::<
+        echo_protocol::EchoService<EchoImpl>,
+    >::default();
+    let transport = Identified::new(Framed::new(server, servercodec), peer);
+    let mut serv = echo_protocol::EchoService { inner: EchoImpl {} }
+        .layer(AuthorizeLayer::new(acl.clone()));
+    tokio::spawn(async move { run(&mut serv, transport).await });
+
+    let mut framed = Framed::new(client, ClientCodec::<EchoChannel>::default());
+    let mut chan = EchoChannel {
+        inner: Box::new(&mut framed),
+    };
+    assert_eq!(chan.shout("doc:design".to_string()).await?, "DOC:DESIGN");
+    let Err(Error::Status(status)) = chan.shout("doc:secret".to_string()).await else {
+        panic!("expected doc:secret to be refused");
+    };
+    assert_eq!(status.code, Code::PermissionDenied);
+
+    let tuple = format!("doc:design#shout@uid:{}", uid).parse().unwrap();
+    let fresh = Consistency::AtLeastAsFresh(acl.delete(vec![tuple]).await?);
+    assert!(!acl.check_user(doc, "shout", user, fresh).await?);
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -74,4 +950,90 @@ mod tests {
     fn test_network_partitions_during_connect() {
         network_partitions_during_connect().unwrap()
     }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_reconnects_after_connection_loss() {
+        reconnects_after_connection_loss().unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_deadlines_are_enforced() {
+        deadlines_are_enforced().unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_metadata_is_sent_when_enabled() {
+        metadata_is_sent_when_enabled().unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_router_dispatches_to_handlers() {
+        router_dispatches_to_handlers().unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_layers_wrap_generated_services() {
+        layers_wrap_generated_services().unwrap()
+    }
+
+    #[okstd::test]
+    fn test_rpcs_are_traced() {
+        let recorder = SpanRecorder::default();
+        let subscriber = Registry::default().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || rpcs_are_traced(recorder)).unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_rpcs_are_measured() {
+        rpcs_are_measured().unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_overloaded_requests_are_shed() {
+        overloaded_requests_are_shed().unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_global_limit_sheds_requests_that_wait_too_long() {
+        global_limit_sheds_requests_that_wait_too_long().unwrap()
+    }
+
+    #[okstd::test]
+    async fn test_noisy_clients_are_rate_limited() {
+        noisy_clients_are_rate_limited().await.unwrap()
+    }
+
+    #[okstd::test]
+    #[okstd::log(debug)]
+    fn test_shutdown_drains_connections() {
+        shutdown_drains_connections().unwrap()
+    }
+
+    #[okstd::test]
+    async fn test_unix_peers_are_identified() {
+        unix_peers_are_identified().await.unwrap()
+    }
+
+    #[okstd::test]
+    fn test_client_certificates_identify_peers() {
+        client_certificates_identify_peers().unwrap()
+    }
+
+    #[okstd::test]
+    async fn test_requests_are_authorized() {
+        requests_are_authorized().await.unwrap()
+    }
+
+    #[okstd::test]
+    async fn test_permission_servers_are_shared() {
+        permission_servers_are_shared().await.unwrap()
+    }
 }
@@ -5,6 +5,7 @@ use {
     jetstream_macros::JetStreamWireFormat,
     jetstream_wireformat::*,
     std::{
+        collections::BTreeMap,
         io::{self, Cursor},
         mem,
         pin::Pin,
@@ -257,6 +258,32 @@ fn vector_decode() {
     );
 }
 
+#[test]
+fn map_round_trip() {
+    let map: BTreeMap<String, u32> = [("b".to_string(), 2), ("a".to_string(), 1)].into();
+
+    // u16 length, then each key (u16 length + byte) and u32 value.
+    assert_eq!(map.byte_size(), 2 + 2 * (3 + 4));
+
+    let mut buf = Vec::new();
+    map.encode(&mut buf).unwrap();
+    assert_eq!(buf.len(), map.byte_size() as usize);
+    // Entries are encoded in key order.
+    assert_eq!(&buf[..5], &[0x02, 0x00, 0x01, 0x00, b'a']);
+
+    let decoded: BTreeMap<String, u32> = WireFormat::decode(&mut Cursor::new(&buf)).unwrap();
+    assert_eq!(map, decoded);
+}
+
+#[test]
+fn map_duplicate_keys() {
+    let mut buf = Vec::new();
+    vec![7u32, 1, 7, 2].encode(&mut buf).unwrap();
+    buf[0] = 2;
+    let result: io::Result<BTreeMap<u32, u32>> = WireFormat::decode(&mut Cursor::new(&buf));
+    assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+}
+
 #[test]
 fn data_encode() {
     let values = Data(vec![169, 155, 79, 67, 182, 199, 25, 73, 129, 200]);